@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The main crate is a binary, so `day3` is built from its source
+#[allow(dead_code, unused_imports)]
+#[path = "../../src"]
+mod cch24 {
+    pub mod day3;
+}
+
+fuzz_target!(|data: &[u8]| cch24::day3::fuzz::manifest(data));
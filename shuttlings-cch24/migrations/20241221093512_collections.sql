@@ -0,0 +1,4 @@
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS collection_id TEXT NOT NULL DEFAULT 'default';
+CREATE INDEX IF NOT EXISTS quotes_collection_id_created_at ON quotes (collection_id, created_at);
+
+ALTER TABLE pages ADD COLUMN IF NOT EXISTS collection_id TEXT NOT NULL DEFAULT 'default';
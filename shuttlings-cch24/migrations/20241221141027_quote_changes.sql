@@ -0,0 +1,47 @@
+CREATE TABLE IF NOT EXISTS quote_changes (
+    id BIGSERIAL PRIMARY KEY,
+    quote_id UUID NOT NULL,
+    collection_id TEXT NOT NULL,
+    op TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS quote_changes_collection_id ON quote_changes (collection_id, id);
+
+-- Record every change to a quote and wake up any listeners with the id of the change
+CREATE OR REPLACE FUNCTION notify_quote_change() RETURNS TRIGGER AS $$
+DECLARE
+    row_data quotes;
+    change_id BIGINT;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        row_data := OLD;
+    ELSE
+        row_data := NEW;
+    END IF;
+
+    INSERT INTO quote_changes (quote_id, collection_id, op, payload)
+    VALUES (
+        row_data.id,
+        row_data.collection_id,
+        CASE TG_OP
+            WHEN 'INSERT' THEN 'draft'
+            WHEN 'UPDATE' THEN 'update'
+            ELSE 'remove'
+        END,
+        to_jsonb(row_data)
+    )
+    RETURNING id INTO change_id;
+
+    PERFORM pg_notify('quote_changes', change_id::TEXT);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS quotes_notify_change ON quotes;
+
+CREATE TRIGGER quotes_notify_change
+    AFTER INSERT OR UPDATE OR DELETE ON quotes
+    FOR EACH ROW EXECUTE FUNCTION notify_quote_change();
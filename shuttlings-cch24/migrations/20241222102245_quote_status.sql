@@ -0,0 +1,5 @@
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'published'
+    CHECK (status IN ('draft', 'published', 'archived'));
+ALTER TABLE quotes ADD COLUMN IF NOT EXISTS publish_at TIMESTAMPTZ NULL;
+
+CREATE INDEX IF NOT EXISTS quotes_scheduled ON quotes (publish_at) WHERE status = 'draft';
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS gift_revocations (
+    jti UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS gift_revocations_expiry ON gift_revocations (expires_at);
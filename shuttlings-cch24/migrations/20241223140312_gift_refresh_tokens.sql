@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS gift_refresh_tokens (
+    hash BYTEA PRIMARY KEY,
+    family UUID NOT NULL,
+    data TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS gift_refresh_tokens_family ON gift_refresh_tokens (family);
+CREATE INDEX IF NOT EXISTS gift_refresh_tokens_expiry ON gift_refresh_tokens (expires_at);
@@ -0,0 +1,32 @@
+-- Change ids are handed out when a change is made rather than when it commits, so readers
+-- resuming after an id could skip a change that committed after later ones. Each change is
+-- given a position as its transaction commits instead. The advisory lock is held until the
+-- commit completes, so every position before a visible one is visible too.
+CREATE SEQUENCE IF NOT EXISTS quote_changes_position;
+
+ALTER TABLE quote_changes ADD COLUMN IF NOT EXISTS position BIGINT;
+
+-- Existing changes are already committed, so they keep their ids as positions
+UPDATE quote_changes SET position = id WHERE position IS NULL;
+SELECT setval('quote_changes_position', COALESCE(MAX(position), 0) + 1, false) FROM quote_changes;
+
+CREATE INDEX IF NOT EXISTS quote_changes_collection_position ON quote_changes (collection_id, position);
+CREATE INDEX IF NOT EXISTS quote_changes_created_at ON quote_changes (created_at);
+
+CREATE OR REPLACE FUNCTION position_quote_change() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('quote_changes_position'));
+
+    UPDATE quote_changes SET position = nextval('quote_changes_position') WHERE id = NEW.id;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS quote_changes_position ON quote_changes;
+
+-- Deferred, so it runs as the transaction commits
+CREATE CONSTRAINT TRIGGER quote_changes_position
+    AFTER INSERT ON quote_changes
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION position_quote_change();
@@ -0,0 +1,12 @@
+-- Signing keys generated by rotation, so they survive restarts and every replica signs
+-- with and publishes the same keys. Retired keys are kept until expires_at, after which
+-- tokens they signed are no longer accepted.
+CREATE TABLE IF NOT EXISTS gift_keys (
+    kid TEXT PRIMARY KEY,
+    alg TEXT NOT NULL,
+    private_key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS gift_keys_expiry ON gift_keys (expires_at);
@@ -0,0 +1,4 @@
+-- The gift issued alongside each refresh token, so the gifts of a family can be revoked
+-- together with it. Tokens issued before this have no gift recorded.
+ALTER TABLE gift_refresh_tokens ADD COLUMN IF NOT EXISTS jti UUID;
+ALTER TABLE gift_refresh_tokens ADD COLUMN IF NOT EXISTS jti_expires_at TIMESTAMPTZ;
@@ -0,0 +1,48 @@
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use std::sync::Arc;
+
+/// The bearer token required for admin-only operations. Admin access is disabled when unset.
+#[derive(Clone, Default)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+/// Extractor for requests authenticated with the configured [`AdminToken`]
+pub struct Admin;
+
+/// Compare the two strings without exiting early on the first mismatch
+fn constant_time_eq(x: &str, y: &str) -> bool {
+    x.len() == y.len()
+        && x.bytes()
+            .zip(y.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    AdminToken: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AdminToken(Some(expected)) = AdminToken::from_ref(state) else {
+            return Err((StatusCode::UNAUTHORIZED, "Admin access is disabled"));
+        };
+
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match token {
+            Some(token) if constant_time_eq(token, &expected) => Ok(Admin),
+            _ => Err((StatusCode::UNAUTHORIZED, "Invalid admin token")),
+        }
+    }
+}
@@ -1,11 +1,40 @@
-use axum::{body::Bytes, http::StatusCode};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
 use axum_extra::TypedHeader;
 use cargo_manifest::{Manifest, Package};
 use headers::ContentType;
-use serde::{Deserialize, Deserializer};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+mod aggregate;
+use aggregate::{aggregate, sort_lines, ManifestParams, Summary};
+mod dependencies;
+pub use dependencies::dependencies;
+mod diagnostics;
+use diagnostics::Diagnostic;
+mod format;
+use format::Format;
+mod input;
+use input::{decode_body, input_format, InputFormat};
+mod invoice;
+use invoice::Invoice;
+mod limits;
+use limits::ManifestLimits;
+mod lint;
+pub use lint::lint_manifest;
+mod policy;
+pub use policy::ManifestPolicy;
+mod workspace;
 
 #[derive(Deserialize, Debug)]
-struct Orders {
+pub struct Orders {
     #[serde(default)]
     orders: Option<Vec<Order>>,
 }
@@ -15,117 +44,517 @@ struct Order {
     #[serde(default)]
     item: Option<String>,
 
-    #[serde(default, deserialize_with = "deserialize_quantity")]
+    #[serde(default, deserialize_with = "deserialize_or_none")]
     quantity: Option<u32>,
+
+    /// What the quantity counts, such as `kg`
+    #[serde(default, deserialize_with = "deserialize_or_none")]
+    unit: Option<String>,
+
+    #[serde(
+        default,
+        rename = "unit-price",
+        deserialize_with = "deserialize_or_none"
+    )]
+    unit_price: Option<Decimal>,
+
+    /// An ISO 4217 code such as `EUR`
+    #[serde(default, deserialize_with = "deserialize_or_none")]
+    currency: Option<String>,
+
+    /// Fields outside the schema, passed through as they are
+    #[serde(flatten)]
+    extra: BTreeMap<String, toml::Value>,
 }
 
+/// Deserialize an order field, dropping it if it has the wrong type rather than failing
+/// the whole manifest. The problem is reported by [`diagnostics::order_diagnostics`].
 #[allow(clippy::unnecessary_wraps)]
-fn deserialize_quantity<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+fn deserialize_or_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
 where
     D: Deserializer<'de>,
+    T: Deserialize<'de>,
 {
-    let result = u32::deserialize(deserializer);
+    let result = T::deserialize(deserializer);
     match result {
         Ok(v) => Ok(Some(v)),
         Err(_) => Ok(None), // If deserialization fails, return None
     }
 }
 
-// Ensure the magic keyword is present in the keywords
-fn keyword_present(package: &Package<Orders>) -> bool {
-    let magic_word = "Christmas 2024".to_string();
+/// A single order line of the result
+#[derive(Debug, Default, Serialize)]
+struct OrderLine {
+    item: String,
+    quantity: u32,
 
-    package
-        .keywords
-        .as_ref()
-        .and_then(|keywords| keywords.clone().as_local())
-        .and_then(|keywords| keywords.contains(&magic_word).then_some(true))
-        .is_some()
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_price: Option<Decimal>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<String>,
+
+    /// The quantity times the unit price
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_total: Option<Decimal>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    extra: BTreeMap<String, toml::Value>,
+}
+
+impl OrderLine {
+    /// Whether the order uses any of the pricing fields
+    fn has_pricing(&self) -> bool {
+        self.unit.is_some() || self.unit_price.is_some() || self.currency.is_some()
+    }
+}
+
+/// The orders found in a manifest, along with the problems that caused any orders to be
+/// skipped
+#[derive(Debug, Serialize)]
+struct ManifestOrders {
+    orders: Vec<OrderLine>,
+
+    /// The total of the priced orders, if any are
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invoice: Option<Invoice>,
+
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Why a manifest was rejected
+#[derive(Debug)]
+struct ManifestError {
+    status: StatusCode,
+    message: String,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ManifestError {
+    fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn no_content() -> Self {
+        Self::new(StatusCode::NO_CONTENT, "")
+    }
+
+    /// Attribute every diagnostic to the given manifest of a workspace upload
+    fn in_file(mut self, file: &str) -> Self {
+        for diag in &mut self.diagnostics {
+            diag.file = Some(file.to_string());
+        }
+
+        self
+    }
+
+    fn invalid(diagnostics: Vec<Diagnostic>) -> Self {
+        Self {
+            diagnostics,
+            ..Self::new(StatusCode::BAD_REQUEST, "Invalid manifest")
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    error: &'a str,
+    diagnostics: &'a [Diagnostic],
 }
 
-/// Parse the given toml bytes as a [`Manifest`]
-fn parse_manifest_bytes(toml_bytes: &[u8]) -> Result<String, (StatusCode, String)> {
+/// Parse the given toml bytes as a [`Manifest`], returning them as text for diagnostics
+fn parse_manifest(toml_bytes: &[u8]) -> Result<(&str, Manifest<Orders>), ManifestError> {
+    let text = std::str::from_utf8(toml_bytes).map_err(|e| {
+        ManifestError::invalid(vec![Diagnostic::new("", format!("invalid UTF-8: {e}"))])
+    })?;
+
     let manifest: Manifest<Orders> = Manifest::from_slice_with_metadata(toml_bytes)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".to_string()))?;
+        .map_err(|_| ManifestError::invalid(diagnostics::manifest_error(text)))?;
+
+    Ok((text, manifest))
+}
+
+/// Parse the given toml bytes as a [`Manifest`], checking it against the policy
+fn parse_manifest_bytes(
+    toml_bytes: &[u8],
+    policy: &ManifestPolicy,
+) -> Result<ManifestOrders, ManifestError> {
+    let (text, mut manifest) = parse_manifest(toml_bytes)?;
+
+    let Some(package) = &mut manifest.package else {
+        // A virtual workspace root on its own has no packages to order from
+        if manifest.workspace.is_some() {
+            return Err(ManifestError::no_content());
+        }
 
-    let Some(package) = &manifest.package else {
-        return Err((StatusCode::BAD_REQUEST, "Invalid manifest".to_string()));
+        return Err(ManifestError::invalid(vec![Diagnostic::new(
+            "package",
+            "missing `package` table",
+        )]));
     };
 
-    // Ensure the 'Christmas 2024' keyword is added
-    if !keyword_present(package) {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Magic keyword not provided".to_string(),
-        ));
+    // The root package of a workspace can inherit from its own workspace. Any other
+    // package has nothing to inherit from.
+    let workspace = manifest
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.package.as_ref());
+
+    workspace::inherit(package, workspace).map_err(|diag| {
+        let span = diagnostics::Spans::new(text).of(&diag.path);
+        ManifestError::invalid(vec![diag.at(text, span)])
+    })?;
+
+    package_orders(text, package, policy)
+}
+
+/// Check the given package against the policy and collect its orders
+fn package_orders(
+    text: &str,
+    package: &Package<Orders>,
+    policy: &ManifestPolicy,
+) -> Result<ManifestOrders, ManifestError> {
+    let violations = policy.check(text, package);
+    if !violations.is_empty() {
+        // Missing keywords keep the message clients have always seen
+        let message = if violations
+            .iter()
+            .any(|violation| violation.rule == Some(policy::REQUIRED_KEYWORD))
+        {
+            "Magic keyword not provided"
+        } else {
+            "Manifest violates policy"
+        };
+
+        return Err(ManifestError {
+            diagnostics: violations,
+            ..ManifestError::new(StatusCode::BAD_REQUEST, message)
+        });
     }
 
     let Some(orders) = &package.metadata else {
-        return Err((StatusCode::NO_CONTENT, String::new()));
+        return Err(ManifestError::no_content());
     };
 
     let Some(orders) = &orders.orders else {
-        return Err((StatusCode::NO_CONTENT, String::new()));
+        return Err(ManifestError::no_content());
     };
 
     // Collect orders together
-    let result = orders
+    let orders = orders
         .iter()
-        .filter_map(|Order { item, quantity }| match (item, quantity) {
-            (Some(item), Some(quantity)) => Some(format!("{item}: {quantity}")),
+        .filter_map(|order| match (&order.item, order.quantity) {
+            (Some(item), Some(quantity)) => Some(OrderLine {
+                item: item.clone(),
+                quantity,
+                unit: order.unit.clone(),
+                unit_price: order.unit_price,
+                currency: order.currency.as_deref().map(str::to_uppercase),
+                line_total: None,
+                extra: order.extra.clone(),
+            }),
             _ => None,
         })
         .collect::<Vec<_>>();
 
     // Ensure we have some orders
-    if result.is_empty() {
-        return Err((StatusCode::NO_CONTENT, String::new()));
+    if orders.is_empty() {
+        return Err(ManifestError::no_content());
+    }
+
+    Ok(ManifestOrders {
+        orders,
+        invoice: None,
+        diagnostics: diagnostics::order_diagnostics(text),
+    })
+}
+
+/// Read the manifest sent in the given format as TOML, converting the other formats.
+/// Whether the manifest was converted is returned too.
+fn manifest_toml<'a>(
+    format: InputFormat,
+    body: &'a [u8],
+    limits: &ManifestLimits,
+) -> Result<(Cow<'a, [u8]>, bool), ManifestError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        ManifestError::invalid(vec![Diagnostic::new("", e.to_string())])
+    };
+
+    let text = || std::str::from_utf8(body).map_err(|e| invalid(&e));
+
+    // The limits are checked before parsing, which would recurse or expand aliases first
+    let value: serde_json::Value = match format {
+        InputFormat::Toml => {
+            limits.check_toml(body)?;
+            return Ok((Cow::Borrowed(body), false));
+        }
+        InputFormat::Yaml => {
+            limits.check_yaml(text()?)?;
+            serde_yaml::from_slice(body).map_err(|e| invalid(&e))?
+        }
+        InputFormat::Json => {
+            limits.check_brackets(text()?)?;
+            serde_json::from_slice(body).map_err(|e| invalid(&e))?
+        }
+        InputFormat::Json5 => {
+            limits.check_brackets(text()?)?;
+            json5::from_str(text()?).map_err(|e| invalid(&e))?
+        }
+        InputFormat::Ron => {
+            limits.check_brackets(text()?)?;
+            let value: ron::Value = ron::from_str(text()?).map_err(|e| invalid(&e))?;
+            serde_json::to_value(value).map_err(|e| invalid(&e))?
+        }
+        InputFormat::Multipart => {
+            return Err(ManifestError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Multipart uploads are only supported by /5/manifest",
+            ))
+        }
+    };
+
+    limits.check_depth(&value)?;
+
+    let toml = toml::to_string(&value)
+        .map_err(|_| ManifestError::new(StatusCode::BAD_REQUEST, "Failed to create toml"))?;
+
+    Ok((Cow::Owned(toml.into_bytes()), true))
+}
+
+/// Parse the manifest sent in the given format
+fn parse_body(
+    format: InputFormat,
+    body: &[u8],
+    policy: &ManifestPolicy,
+) -> Result<ManifestOrders, ManifestError> {
+    let (toml, converted) = manifest_toml(format, body, &policy.limits)?;
+    let result = parse_manifest_bytes(&toml, policy);
+
+    if !converted {
+        return result;
     }
 
-    // Return the list of orders
-    Ok(result.join("\n"))
+    // Spans would point into the converted document rather than what was sent
+    let strip = |diagnostics: &mut Vec<Diagnostic>| {
+        for diag in diagnostics.iter_mut() {
+            diag.span = None;
+        }
+    };
+
+    result
+        .map(|mut orders| {
+            strip(&mut orders.diagnostics);
+            orders
+        })
+        .map_err(|mut e| {
+            strip(&mut e.diagnostics);
+            e
+        })
+}
+
+/// The plain text output: one `item: quantity` line per order
+fn order_text<'a>(lines: impl Iterator<Item = (&'a str, u32)>) -> String {
+    lines
+        .map(|(item, quantity)| format!("{item}: {quantity}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render the parsed orders in the given format, aggregating and sorting them if asked to
+fn render(
+    mut orders: ManifestOrders,
+    params: &ManifestParams,
+    format: Format,
+) -> Result<Response, ManifestError> {
+    if !params.aggregate {
+        if let Some(key) = params.sort {
+            sort_lines(&mut orders.orders, key, params.order, |line| {
+                (&line.item, line.quantity)
+            });
+        }
+
+        let lines = orders
+            .orders
+            .iter()
+            .map(|line| (line.item.as_str(), line.quantity));
+
+        return Ok(match format {
+            Format::Text => order_text(lines).into_response(),
+            Format::Csv if orders.orders.iter().any(OrderLine::has_pricing) => format::csv(
+                &[
+                    "item",
+                    "quantity",
+                    "unit",
+                    "unit_price",
+                    "currency",
+                    "line_total",
+                ],
+                orders.orders.iter().map(|line| {
+                    let optional = |field: Option<String>| field.unwrap_or_default();
+                    vec![
+                        line.item.clone(),
+                        line.quantity.to_string(),
+                        optional(line.unit.clone()),
+                        optional(line.unit_price.map(|price| price.to_string())),
+                        optional(line.currency.clone()),
+                        optional(line.line_total.map(|total| total.to_string())),
+                    ]
+                }),
+            ),
+            Format::Csv => format::csv(
+                &["item", "quantity"],
+                lines.map(|(item, quantity)| vec![item.to_string(), quantity.to_string()]),
+            ),
+            Format::Structured(structured) => structured.serialize(&orders),
+        });
+    }
+
+    let (mut items, total_quantity) = aggregate(&orders.orders).map_err(|diag| ManifestError {
+        diagnostics: vec![diag],
+        ..ManifestError::new(StatusCode::BAD_REQUEST, "Order quantity overflow")
+    })?;
+
+    if let Some(key) = params.sort {
+        sort_lines(&mut items, key, params.order, |total| {
+            (&total.item, total.quantity)
+        });
+    }
+
+    Ok(match format {
+        Format::Text => order_text(
+            items
+                .iter()
+                .map(|total| (total.item.as_str(), total.quantity)),
+        )
+        .into_response(),
+        Format::Csv => format::csv(
+            &["item", "quantity", "orders"],
+            items.iter().map(|total| {
+                vec![
+                    total.item.clone(),
+                    total.quantity.to_string(),
+                    total.orders.to_string(),
+                ]
+            }),
+        ),
+        Format::Structured(structured) => structured.serialize(&Summary {
+            total_items: items.len(),
+            total_quantity,
+            items,
+            invoice: orders.invoice,
+            diagnostics: orders.diagnostics,
+        }),
+    })
+}
+
+/// Decode the request body and parse the manifest, or workspace, it holds
+async fn read_orders(
+    headers: &HeaderMap,
+    content_type: &str,
+    body: Body,
+    policy: &ManifestPolicy,
+) -> Result<ManifestOrders, ManifestError> {
+    let body = policy.limits.read_body(body).await?;
+    let body = decode_body(headers, body, &policy.limits)?;
+
+    let mut orders = match input_format(content_type)? {
+        InputFormat::Multipart => workspace::parse_workspace(content_type, body, policy).await?,
+        format => parse_body(format, &body, policy)?,
+    };
+
+    // Orders of every workspace member go on the same invoice
+    orders.invoice = invoice::invoice(&mut orders.orders, policy.conversion.as_ref())?;
+
+    Ok(orders)
 }
 
 pub async fn manifest(
+    State(policy): State<Arc<ManifestPolicy>>,
+    Query(params): Query<ManifestParams>,
+    headers: HeaderMap,
     TypedHeader(content_type): TypedHeader<ContentType>,
-    body: Bytes,
-) -> Result<String, (StatusCode, String)> {
-    match content_type.to_string().as_str() {
-        "application/toml" => parse_manifest_bytes(&body),
-        "application/yaml" => {
-            let yaml: serde_json::Value = serde_yaml::from_slice(&body)
-                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".to_string()))?;
-
-            let toml = toml::to_string(&yaml)
-                .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to create toml".to_string()))?;
-
-            parse_manifest_bytes(toml.as_bytes())
-        }
-        "application/json" => {
-            let json: serde_json::Value = serde_json::from_slice(&body)
-                .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid manifest".to_string()))?;
+    body: Body,
+) -> Response {
+    let Some(format) = Format::negotiate(&headers) else {
+        let supported = Format::supported();
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            format!("Not acceptable, supported formats are: {supported}"),
+        )
+            .into_response();
+    };
+
+    let content_type = content_type.to_string();
+    let result = read_orders(&headers, &content_type, body, &policy).await;
+
+    match result.and_then(|orders| render(orders, &params, format)) {
+        Ok(response) => response,
+        Err(e) if e.status == StatusCode::NO_CONTENT => e.status.into_response(),
+        Err(e) => match format {
+            Format::Structured(structured) => {
+                let report = ErrorReport {
+                    error: &e.message,
+                    diagnostics: &e.diagnostics,
+                };
 
-            let toml = toml::to_string(&json)
-                .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to create toml".to_string()))?;
+                (e.status, structured.serialize(&report)).into_response()
+            }
+            Format::Text | Format::Csv => (e.status, e.message).into_response(),
+        },
+    }
+}
+
+/// Entry points for the fuzz targets in `fuzz/`, which build this module from source
+#[cfg(fuzzing)]
+pub mod fuzz {
+    use super::{manifest_toml, parse_manifest_bytes, InputFormat, ManifestPolicy};
+
+    /// Parse the bytes as a TOML manifest
+    pub fn manifest(data: &[u8]) {
+        let _ = parse_manifest_bytes(data, &ManifestPolicy::default());
+    }
+
+    /// Convert the bytes to TOML from the format picked by the first one, then parse the
+    /// result as a manifest
+    pub fn conversion(data: &[u8]) {
+        let Some((format, body)) = data.split_first() else {
+            return;
+        };
 
-            parse_manifest_bytes(toml.as_bytes())
+        let format = match format % 4 {
+            0 => InputFormat::Yaml,
+            1 => InputFormat::Json,
+            2 => InputFormat::Json5,
+            _ => InputFormat::Ron,
+        };
+
+        let policy = ManifestPolicy::default();
+        if let Ok((toml, _)) = manifest_toml(format, body, &policy.limits) {
+            let _ = parse_manifest_bytes(&toml, &policy);
         }
-        x => Err((
-            StatusCode::UNSUPPORTED_MEDIA_TYPE,
-            format!("Unknown content type: {x}"),
-        )),
     }
 }
 
 #[cfg(test)]
 mod day3_tests {
-    use crate::app;
+    use crate::{app, router, SantaState};
     use axum::{
         body::Body,
         http::{Request, StatusCode},
     };
+    use flate2::{write::GzEncoder, Compression};
     use http::header;
     use http_body_util::BodyExt;
+    use std::fmt::Write;
+    use std::io::Write as _;
+    use std::sync::Arc;
     use tower::util::ServiceExt; // for `call`, `oneshot`, and `ready`
 
     #[tokio::test]
@@ -161,6 +590,830 @@ quantity = 230
         assert_eq!(body, "Toy car: 2\nLego brick: 230");
     }
 
+    #[tokio::test]
+    async fn manifest_json_diagnostics() {
+        let app = app();
+
+        let data = r#"[package]
+name = "not-a-gift-order"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230
+
+[[package.metadata.orders]]
+item = "Invalid"
+quantity = 230.5
+"#;
+
+        let response = app
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::ACCEPT, "application/json")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "orders": [
+                    {"item": "Toy car", "quantity": 2},
+                    {"item": "Lego brick", "quantity": 230},
+                ],
+                "diagnostics": [{
+                    "path": "package.metadata.orders[2].quantity",
+                    "span": {
+                        "start": {"line": 15, "column": 12},
+                        "end": {"line": 15, "column": 17},
+                    },
+                    "reason": "expected integer, found float",
+                }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn manifest_json_errors() {
+        let app = app();
+
+        let data = "[package]\nname = false\n";
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::ACCEPT, "text/html, application/json; q=0.9")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "Invalid manifest");
+        assert_eq!(body["diagnostics"][0]["path"], "package.name");
+        assert_eq!(body["diagnostics"][0]["span"]["start"]["line"], 2);
+
+        // Spans would refer to the converted TOML, so aren't given for YAML
+        let data = "package:\n  name: gift\n  keywords: [\"Christmas 2025\"]\n";
+
+        let response = app
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/yaml")
+                    .header(header::ACCEPT, "application/json")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "Magic keyword not provided",
+                "diagnostics": [{
+                    "path": "package.keywords",
+                    "reason": "missing required keyword \"Christmas 2024\"",
+                    "rule": "required-keyword",
+                }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn manifest_aggregate() {
+        let app = app();
+
+        let data = r#"
+[package]
+name = "not-a-gift-order"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230
+
+[[package.metadata.orders]]
+item = "toy car"
+quantity = 3
+"#;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/5/manifest?aggregate=true&sort=quantity&order=desc".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Lego brick: 230\nToy car: 5");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/5/manifest?aggregate=true&sort=item".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::ACCEPT, "application/json")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "items": [
+                    {"item": "Lego brick", "quantity": 230, "orders": 1},
+                    {"item": "Toy car", "quantity": 5, "orders": 2},
+                ],
+                "total_items": 2,
+                "total_quantity": 235,
+                "diagnostics": [],
+            })
+        );
+
+        let data = data.replace("quantity = 3", "quantity = 4294967295");
+
+        let response = app
+            .oneshot(
+                Request::post("/5/manifest?aggregate=true".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Order quantity overflow");
+    }
+
+    #[tokio::test]
+    async fn manifest_accept_formats() {
+        let app = app();
+
+        let data = r#"
+[package]
+name = "not-a-gift-order"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "Toy car, red"
+quantity = 2
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230
+"#;
+
+        let request = |accept: &str| {
+            Request::post("/5/manifest".to_string())
+                .header(header::CONTENT_TYPE, "application/toml")
+                .header(header::ACCEPT, accept)
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        let expected = [
+            ("text/csv", "item,quantity\r\n\"Toy car, red\",2\r\nLego brick,230\r\n"),
+            (
+                "application/yaml",
+                "orders:\n- item: Toy car, red\n  quantity: 2\n- item: Lego brick\n  quantity: 230\ndiagnostics: []\n",
+            ),
+            (
+                "application/toml",
+                "diagnostics = []\n\n[[orders]]\nitem = \"Toy car, red\"\nquantity = 2\n\n[[orders]]\nitem = \"Lego brick\"\nquantity = 230\n",
+            ),
+        ];
+
+        for (accept, expected) in expected {
+            let response = app.clone().oneshot(request(accept)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], accept);
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected);
+        }
+
+        let response = app.oneshot(request("text/html")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    async fn manifest_policy() {
+        let policy = toml::from_str(
+            r#"
+required-keywords = ["Christmas 2024"]
+denied-items = ["Coal"]
+required-fields = ["version"]
+"#,
+        )
+        .unwrap();
+
+        let app = router(SantaState {
+            manifest_policy: Arc::new(policy),
+            ..SantaState::new()
+        });
+
+        let data = r#"
+[package]
+name = "not-a-gift-order"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "coal"
+quantity = 1
+"#;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::ACCEPT, "application/json")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "Manifest violates policy");
+
+        let rules = body["diagnostics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|diag| diag["rule"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rules, vec!["required-field", "denied-item"]);
+
+        let data = data.replace("coal", "Toy car").replace(
+            "keywords = [\"Christmas 2024\"]",
+            "keywords = []\nversion = \"0.1.0\"",
+        );
+
+        let response = app
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Magic keyword not provided");
+    }
+
+    #[tokio::test]
+    async fn manifest_inherited_without_workspace() {
+        let policy = toml::from_str(
+            r#"
+required-fields = ["authors", "rust-version"]
+allowed-licenses = ["MIT"]
+"#,
+        )
+        .unwrap();
+
+        let app = router(SantaState {
+            manifest_policy: Arc::new(policy),
+            ..SantaState::new()
+        });
+
+        // Inheriting fields can't get around the policy without a workspace to inherit from
+        let data = r#"
+[package]
+name = "not-a-gift-order"
+keywords = ["Christmas 2024"]
+authors.workspace = true
+rust-version.workspace = true
+license.workspace = true
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 1
+"#;
+
+        let response = app
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::ACCEPT, "application/json")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["diagnostics"][0]["path"], "package.authors", "{body}");
+    }
+
+    /// A multipart body uploading the given manifests
+    fn multipart(parts: &[(&str, &str, &str)]) -> String {
+        let mut body = String::new();
+
+        for (field, file, manifest) in parts {
+            let _ = write!(
+                body,
+                "--BOUNDARY\r\nContent-Disposition: form-data; name=\"{field}\"; \
+                 filename=\"{file}\"\r\nContent-Type: application/toml\r\n\r\n{manifest}\r\n"
+            );
+        }
+
+        body.push_str("--BOUNDARY--\r\n");
+        body
+    }
+
+    #[tokio::test]
+    async fn manifest_workspace() {
+        let app = app();
+
+        let root = r#"
+[workspace]
+members = ["gifts", "toys"]
+
+[workspace.package]
+version = "1.0.0"
+keywords = ["Christmas 2024"]
+"#;
+
+        let gifts = r#"
+[package]
+name = "gifts"
+version.workspace = true
+keywords.workspace = true
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+"#;
+
+        let toys = r#"
+[package]
+name = "toys"
+keywords.workspace = true
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230
+"#;
+
+        let request = |body: String| {
+            Request::post("/5/manifest".to_string())
+                .header(
+                    header::CONTENT_TYPE,
+                    "multipart/form-data; boundary=BOUNDARY",
+                )
+                .header(header::ACCEPT, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let body = multipart(&[
+            ("root", "Cargo.toml", root),
+            ("member", "gifts/Cargo.toml", gifts),
+            ("member", "toys/Cargo.toml", toys),
+        ]);
+
+        let response = app.clone().oneshot(request(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["orders"],
+            serde_json::json!([
+                {"item": "Toy car", "quantity": 2},
+                {"item": "Lego brick", "quantity": 230},
+            ])
+        );
+
+        // Members can't inherit what the workspace doesn't define
+        let root = root.replace("keywords = [\"Christmas 2024\"]", "");
+        let body = multipart(&[
+            ("root", "Cargo.toml", &root),
+            ("member", "gifts/Cargo.toml", gifts),
+        ]);
+
+        let response = app.oneshot(request(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["diagnostics"][0],
+            serde_json::json!({
+                "file": "gifts/Cargo.toml",
+                "path": "package.keywords",
+                "span": {
+                    "start": {"line": 5, "column": 1},
+                    "end": {"line": 5, "column": 9},
+                },
+                "reason": "inherited, but `workspace.package.keywords` isn't set",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn dependencies() {
+        let app = app();
+
+        let data = r#"
+package:
+  name: gifts
+dependencies:
+  serde: { version: "1", optional: true }
+dev-dependencies:
+  tokio: "*"
+features:
+  json: ["dep:serde"]
+"#;
+
+        let response = app
+            .oneshot(
+                Request::post("/5/dependencies".to_string())
+                    .header(header::CONTENT_TYPE, "application/yaml")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["dependencies"][0]["name"], "serde");
+        assert_eq!(body["dependencies"][0]["optional"], true);
+        assert_eq!(body["dev_dependencies"][0]["wildcard"], true);
+        assert_eq!(
+            body["optional_dependencies"],
+            serde_json::json!({"serde": ["json"]})
+        );
+
+        // Spans aren't given for converted manifests
+        assert_eq!(
+            body["warnings"],
+            serde_json::json!([{
+                "path": "dev-dependencies.tokio",
+                "reason": "wildcard version requirement *",
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn manifest_input_formats() {
+        let toml = r#"
+[package]
+name = "gifts"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+"#;
+
+        let json5 = r"{
+  // Comments and trailing commas are fine
+  package: {
+    name: 'gifts',
+    keywords: ['Christmas 2024'],
+    metadata: { orders: [{ item: 'Toy car', quantity: 2 }] },
+  },
+}";
+
+        let ron = r#"(
+    package: (
+        name: "gifts",
+        keywords: ["Christmas 2024"],
+        metadata: (orders: [(item: "Toy car", quantity: 2)]),
+    ),
+)"#;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(toml.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let cases: [(&str, Option<&str>, Vec<u8>); 6] = [
+            ("application/toml; charset=utf-8", None, toml.into()),
+            ("text/x-toml", None, toml.into()),
+            (
+                "application/vnd.gifts+json",
+                None,
+                br#"{"package": {"name": "gifts", "keywords": ["Christmas 2024"],
+                    "metadata": {"orders": [{"item": "Toy car", "quantity": 2}]}}}"#
+                    .to_vec(),
+            ),
+            ("application/json5", None, json5.into()),
+            ("application/ron", None, ron.into()),
+            ("application/toml", Some("gzip"), gzipped),
+        ];
+
+        for (content_type, encoding, data) in cases {
+            let mut request =
+                Request::post("/5/manifest".to_string()).header(header::CONTENT_TYPE, content_type);
+            if let Some(encoding) = encoding {
+                request = request.header(header::CONTENT_ENCODING, encoding);
+            }
+
+            let response = app()
+                .oneshot(request.body(Body::from(data)).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK, "{content_type}");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "Toy car: 2", "{content_type}");
+        }
+
+        let response = app()
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::CONTENT_ENCODING, "br")
+                    .body(Body::from(toml))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Unknown content encoding: br");
+    }
+
+    #[tokio::test]
+    async fn manifest_invoice() {
+        let data = r#"
+[package]
+name = "gifts"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "Cocoa"
+quantity = 2
+unit = "kg"
+unit-price = 4.25
+currency = "eur"
+wrapping = "red"
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 3
+unit-price = "1.10"
+currency = "EUR"
+"#;
+
+        let request = |data: String, accept| {
+            Request::post("/5/manifest".to_string())
+                .header(header::CONTENT_TYPE, "application/toml")
+                .header(header::ACCEPT, accept)
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        let response = app()
+            .oneshot(request(data.to_string(), "application/json"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["orders"][0],
+            serde_json::json!({
+                "item": "Cocoa",
+                "quantity": 2,
+                "unit": "kg",
+                "unit_price": "4.25",
+                "currency": "EUR",
+                "line_total": "8.50",
+                "extra": {"wrapping": "red"},
+            })
+        );
+        assert_eq!(
+            body["invoice"],
+            serde_json::json!({"currency": "EUR", "total": "11.80", "lines": 2})
+        );
+        assert_eq!(
+            body["diagnostics"][0]["reason"],
+            "unknown field `wrapping`, passed through as is"
+        );
+
+        let response = app()
+            .oneshot(request(data.to_string(), "text/csv"))
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            "item,quantity,unit,unit_price,currency,line_total\r\n\
+             Cocoa,2,kg,4.25,EUR,8.50\r\n\
+             Toy car,3,,1.10,EUR,3.30\r\n"
+        );
+
+        // Mixed currencies are rejected without a conversion table
+        let mixed = data.replace("currency = \"EUR\"", "currency = \"USD\"");
+        let response = app()
+            .oneshot(request(mixed.clone(), "text/plain"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "Mixed currencies");
+
+        let policy = toml::from_str(
+            r#"
+[conversion]
+currency = "EUR"
+rates = { USD = 0.5 }
+"#,
+        )
+        .unwrap();
+
+        let state = SantaState {
+            manifest_policy: Arc::new(policy),
+            ..SantaState::new()
+        };
+
+        let response = router(state)
+            .oneshot(request(mixed, "application/json"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["invoice"],
+            serde_json::json!({"currency": "EUR", "total": "10.15", "lines": 2})
+        );
+    }
+
+    #[tokio::test]
+    async fn manifest_limits() {
+        let policy = toml::from_str(
+            r"
+[limits]
+body-size = 256
+depth = 4
+alias-expansion = 20
+",
+        )
+        .unwrap();
+
+        let app = router(SantaState {
+            manifest_policy: Arc::new(policy),
+            ..SantaState::new()
+        });
+
+        let request = |content_type, data: String| {
+            Request::post("/5/manifest".to_string())
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::ACCEPT, "application/json")
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        let large = format!("[package]\nname = \"{}\"\n", "a".repeat(256));
+        let deep = format!(
+            r#"{{"package": {{"name": {}1{}}}}}"#,
+            "[".repeat(8),
+            "]".repeat(8)
+        );
+        let bomb = "a: &a [1, 1, 1]\nb: &b [*a, *a, *a]\nc: [*b, *b, *b]\n".to_string();
+
+        let cases = [
+            (
+                "application/toml",
+                large,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Manifest too large",
+            ),
+            (
+                "application/json",
+                deep,
+                StatusCode::BAD_REQUEST,
+                "Manifest nested too deeply",
+            ),
+            (
+                "application/yaml",
+                bomb,
+                StatusCode::BAD_REQUEST,
+                "Too many YAML aliases",
+            ),
+        ];
+
+        for (content_type, data, status, error) in cases {
+            let response = app
+                .clone()
+                .oneshot(request(content_type, data))
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"], error);
+        }
+    }
+
+    #[tokio::test]
+    async fn lint() {
+        let data = r#"[package]
+name = "gifts"
+description = "Gift orders"
+license = "MIT"
+version = "1" # first release
+
+[dependencies]
+toml = "0.8"
+serde = "1"
+"#;
+
+        let response = app()
+            .oneshot(
+                Request::post("/5/lint".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["findings"][0],
+            serde_json::json!({
+                "path": "package.version",
+                "span": {"start": {"line": 5, "column": 11}, "end": {"line": 5, "column": 14}},
+                "reason": "1 is not a SemVer version: unexpected end of input while parsing major version number",
+                "rule": "invalid-version",
+                "fix": "changed version to 1.0.0",
+            })
+        );
+        assert_eq!(body["findings"][1]["rule"], "unsorted-dependencies");
+        assert_eq!(
+            body["manifest"],
+            data.replace("\"1\" #", "\"1.0.0\" #").replace(
+                "toml = \"0.8\"\nserde = \"1\"",
+                "serde = \"1\"\ntoml = \"0.8\""
+            )
+        );
+    }
+
     #[tokio::test]
     async fn manifest_with_float() {
         let app = app();
@@ -198,6 +1451,66 @@ quantity = 230
         assert_eq!(body, "Toy car: 2\nLego brick: 230");
     }
 
+    #[tokio::test]
+    async fn manifest_with_bad_pricing() {
+        let app = app();
+
+        let data = r#"
+[package]
+name = "not-a-gift-order"
+keywords = ["Christmas 2024"]
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+unit-price = "2.50"
+currency = 5
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230
+unit = ["bricks"]
+"#;
+
+        let response = app
+            .oneshot(
+                Request::post("/5/manifest".to_string())
+                    .header(header::CONTENT_TYPE, "application/toml")
+                    .header(header::ACCEPT, "application/json")
+                    .body(Body::from(data))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The bad fields are dropped, rather than the whole manifest
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["orders"],
+            serde_json::json!([
+                {"item": "Toy car", "quantity": 2, "unit_price": "2.50", "line_total": "5.00"},
+                {"item": "Lego brick", "quantity": 230},
+            ])
+        );
+
+        let paths = body["diagnostics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|diag| diag["path"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "package.metadata.orders[0].currency",
+                "package.metadata.orders[1].unit"
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn manifest_bad_data() {
         let app = app();
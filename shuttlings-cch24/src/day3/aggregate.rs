@@ -0,0 +1,194 @@
+use super::diagnostics::Diagnostic;
+use super::invoice::Invoice;
+use super::OrderLine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What to sort the order lines by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Item,
+    Quantity,
+}
+
+/// Direction to sort the order lines in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Query parameters of `/5/manifest`
+#[derive(Debug, Default, Deserialize)]
+pub struct ManifestParams {
+    /// Merge orders for the same item, ignoring case. Only quantities are merged: the
+    /// pricing of each order is dropped, even if they're priced in different currencies or
+    /// units.
+    #[serde(default)]
+    pub aggregate: bool,
+
+    /// Order lines are kept in manifest order if not given
+    pub sort: Option<SortKey>,
+
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// The total ordered of a single item
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ItemTotal {
+    /// The spelling of the item in its first order
+    pub item: String,
+    pub quantity: u32,
+
+    /// Number of orders merged into this total
+    pub orders: usize,
+}
+
+/// Aggregated orders, along with the grand totals
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub items: Vec<ItemTotal>,
+    pub total_items: usize,
+    pub total_quantity: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invoice: Option<Invoice>,
+
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Sort the given lines by item name (ignoring case) or quantity. Ties are broken by the
+/// item name so the output is stable.
+pub fn sort_lines<T>(
+    lines: &mut [T],
+    key: SortKey,
+    order: SortOrder,
+    fields: fn(&T) -> (&str, u32),
+) {
+    lines.sort_by(|a, b| {
+        let (a_item, a_quantity) = fields(a);
+        let (b_item, b_quantity) = fields(b);
+        let by_item = a_item.to_lowercase().cmp(&b_item.to_lowercase());
+
+        let ordering = match key {
+            SortKey::Item => by_item,
+            SortKey::Quantity => a_quantity.cmp(&b_quantity).then(by_item),
+        };
+
+        match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    });
+}
+
+/// Merge orders of the same item, case-insensitively, keeping the items in order of first
+/// appearance. Orders are merged whatever their unit, unit price or currency, none of which
+/// are kept. Fails if the total of any item, or the grand total, overflows.
+pub fn aggregate(orders: &[OrderLine]) -> Result<(Vec<ItemTotal>, u64), Diagnostic> {
+    let mut items: Vec<ItemTotal> = Vec::new();
+    let mut index = HashMap::new();
+    let mut total_quantity = 0_u64;
+
+    for OrderLine { item, quantity, .. } in orders {
+        let position = *index.entry(item.to_lowercase()).or_insert_with(|| {
+            items.push(ItemTotal {
+                item: item.clone(),
+                quantity: 0,
+                orders: 0,
+            });
+            items.len() - 1
+        });
+
+        let total = &mut items[position];
+        total.quantity = total.quantity.checked_add(*quantity).ok_or_else(|| {
+            Diagnostic::new(
+                "package.metadata.orders",
+                format!("total quantity of {} exceeds {}", total.item, u32::MAX),
+            )
+        })?;
+        total.orders += 1;
+
+        total_quantity = total_quantity
+            .checked_add(u64::from(*quantity))
+            .ok_or_else(|| {
+                Diagnostic::new(
+                    "package.metadata.orders",
+                    format!("total quantity exceeds {}", u64::MAX),
+                )
+            })?;
+    }
+
+    Ok((items, total_quantity))
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::{aggregate, sort_lines, ItemTotal, SortKey, SortOrder};
+    use crate::day3::OrderLine;
+
+    fn line(item: &str, quantity: u32) -> OrderLine {
+        OrderLine {
+            item: item.to_string(),
+            quantity,
+            ..OrderLine::default()
+        }
+    }
+
+    #[test]
+    fn merges_case_insensitively() {
+        let orders = [
+            line("Toy car", 2),
+            line("Lego brick", 5),
+            line("toy CAR", 3),
+        ];
+
+        let (mut items, total) = aggregate(&orders).unwrap();
+        assert_eq!(total, 10);
+        assert_eq!(
+            items,
+            vec![
+                ItemTotal {
+                    item: "Toy car".to_string(),
+                    quantity: 5,
+                    orders: 2
+                },
+                ItemTotal {
+                    item: "Lego brick".to_string(),
+                    quantity: 5,
+                    orders: 1
+                },
+            ]
+        );
+
+        // Ties on quantity are broken by the item name
+        sort_lines(&mut items, SortKey::Quantity, SortOrder::Asc, |total| {
+            (&total.item, total.quantity)
+        });
+        assert_eq!(items[0].item, "Lego brick");
+
+        sort_lines(&mut items, SortKey::Item, SortOrder::Desc, |total| {
+            (&total.item, total.quantity)
+        });
+        assert_eq!(items[0].item, "Toy car");
+    }
+
+    #[test]
+    fn detects_overflow() {
+        let orders = [line("Toy car", u32::MAX), line("toy car", 1)];
+
+        let diagnostic = aggregate(&orders).unwrap_err();
+        assert_eq!(
+            diagnostic.reason,
+            format!("total quantity of Toy car exceeds {}", u32::MAX)
+        );
+
+        // Different items can't overflow the grand total
+        let orders = [line("Toy car", u32::MAX), line("Lego brick", u32::MAX)];
+        assert_eq!(aggregate(&orders).unwrap().1, 2 * u64::from(u32::MAX));
+    }
+}
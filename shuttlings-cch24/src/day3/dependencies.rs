@@ -0,0 +1,505 @@
+use super::diagnostics::{self, Diagnostic, Spans};
+use super::input::{decode_body, input_format};
+use super::{manifest_toml, ErrorReport, ManifestError, ManifestLimits, ManifestPolicy};
+use axum::{
+    body::Body,
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::TypedHeader;
+use cargo_manifest::{Dependency, DepsSet, FeatureSet, Manifest};
+use headers::ContentType;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// Where a dependency comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Registry,
+    Git,
+    Path,
+
+    /// Inherited from `workspace.dependencies`
+    Workspace,
+}
+
+/// A single dependency as declared in the manifest
+#[derive(Debug, Serialize)]
+pub struct DependencyInfo {
+    /// The name the dependency is used under
+    pub name: String,
+
+    /// The crate depended on, if renamed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+
+    pub source: Source,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+
+    /// The branch, tag or rev of a git dependency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    pub optional: bool,
+    pub default_features: bool,
+    pub features: Vec<String>,
+
+    /// The version requirement accepts any version in some position, like `*` or `1.*`
+    pub wildcard: bool,
+}
+
+/// The dependencies of each kind
+#[derive(Debug, Default, Serialize)]
+pub struct DependencyKinds {
+    pub dependencies: Vec<DependencyInfo>,
+    pub dev_dependencies: Vec<DependencyInfo>,
+    pub build_dependencies: Vec<DependencyInfo>,
+}
+
+/// The dependencies only used on a specific target
+#[derive(Debug, Serialize)]
+pub struct TargetDependencies {
+    /// A target triple or `cfg()` expression
+    pub target: String,
+
+    #[serde(flatten)]
+    pub kinds: DependencyKinds,
+}
+
+/// A feature and what enabling it turns on
+#[derive(Debug, Serialize)]
+pub struct FeatureInfo {
+    pub name: String,
+
+    /// The feature's values as declared
+    pub enables: Vec<String>,
+
+    /// Optional dependencies enabled by the feature, directly or through other features
+    pub optional_dependencies: BTreeSet<String>,
+
+    /// Cargo created the feature for an optional dependency never named with `dep:`
+    pub implicit: bool,
+}
+
+/// Everything the manifest says about dependencies
+#[derive(Debug, Serialize)]
+pub struct DependencyReport {
+    #[serde(flatten)]
+    pub kinds: DependencyKinds,
+
+    pub targets: Vec<TargetDependencies>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub workspace_dependencies: Vec<DependencyInfo>,
+
+    pub features: Vec<FeatureInfo>,
+
+    /// Each optional dependency and the features that enable it
+    pub optional_dependencies: BTreeMap<String, BTreeSet<String>>,
+
+    /// Git, path and wildcard dependencies, which can't be published or pinned
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Whether a version requirement has a wildcard in any of its comparators
+fn is_wildcard(req: &str) -> bool {
+    req.split(',')
+        .any(|comparator| comparator.trim().split('.').any(|part| part.trim() == "*"))
+}
+
+impl DependencyInfo {
+    fn new(name: &str, dependency: &Dependency) -> Self {
+        let mut info = Self {
+            name: name.to_string(),
+            package: None,
+            source: Source::Registry,
+            version: None,
+            registry: None,
+            git: None,
+            reference: None,
+            path: None,
+            optional: dependency.optional(),
+            default_features: true,
+            features: dependency.req_features().to_vec(),
+            wildcard: false,
+        };
+
+        match dependency {
+            Dependency::Simple(version) => info.version = Some(version.clone()),
+            Dependency::Inherited(_) => info.source = Source::Workspace,
+            Dependency::Detailed(detail) => {
+                info.package.clone_from(&detail.package);
+                info.version.clone_from(&detail.version);
+                info.registry.clone_from(&detail.registry);
+                info.git.clone_from(&detail.git);
+                info.reference = detail
+                    .branch
+                    .clone()
+                    .or_else(|| detail.tag.clone())
+                    .or_else(|| detail.rev.clone());
+                info.path.clone_from(&detail.path);
+                info.default_features = detail.default_features.unwrap_or(true);
+
+                // Git and path dependencies can also have a version, for publishing
+                if detail.git.is_some() {
+                    info.source = Source::Git;
+                } else if detail.path.is_some() {
+                    info.source = Source::Path;
+                }
+            }
+        }
+
+        info.wildcard = info.version.as_deref().is_some_and(is_wildcard);
+        info
+    }
+}
+
+/// Collects the report, along with warnings located in the manifest
+struct Reporter<'a> {
+    /// The manifest, unless it was converted from another format, parsed once for the spans
+    text: Option<(&'a str, Spans<'a>)>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Reporter<'_> {
+    fn dependencies(&mut self, table: &str, deps: Option<&DepsSet>) -> Vec<DependencyInfo> {
+        let Some(deps) = deps else {
+            return Vec::new();
+        };
+
+        deps.iter()
+            .map(|(name, dependency)| {
+                let info = DependencyInfo::new(name, dependency);
+                let path = format!("{table}.{name}");
+
+                let reason = match info.source {
+                    Source::Git => Some("git dependency".to_string()),
+                    Source::Path => Some("path dependency".to_string()),
+                    Source::Registry | Source::Workspace => None,
+                };
+
+                if let Some(reason) = reason {
+                    self.warn(&path, reason);
+                }
+
+                if info.wildcard {
+                    let version = info.version.as_deref().unwrap_or_default();
+                    self.warn(&path, format!("wildcard version requirement {version}"));
+                }
+
+                info
+            })
+            .collect()
+    }
+
+    fn kinds(
+        &mut self,
+        prefix: &str,
+        dependencies: Option<&DepsSet>,
+        dev: Option<&DepsSet>,
+        build: Option<&DepsSet>,
+    ) -> DependencyKinds {
+        DependencyKinds {
+            dependencies: self.dependencies(&format!("{prefix}dependencies"), dependencies),
+            dev_dependencies: self.dependencies(&format!("{prefix}dev-dependencies"), dev),
+            build_dependencies: self.dependencies(&format!("{prefix}build-dependencies"), build),
+        }
+    }
+
+    fn warn(&mut self, path: &str, reason: String) {
+        let mut diag = Diagnostic::new(path, reason);
+
+        if let Some((text, spans)) = &self.text {
+            diag = diag.at(text, spans.of(path));
+        }
+
+        self.warnings.push(diag);
+    }
+}
+
+/// The optional dependencies the given feature enables, following other features
+fn enabled_optional(
+    feature: &str,
+    features: &FeatureSet,
+    optional: &BTreeSet<String>,
+    visited: &mut BTreeSet<String>,
+) -> BTreeSet<String> {
+    let mut enabled = BTreeSet::new();
+
+    if !visited.insert(feature.to_string()) {
+        return enabled;
+    }
+
+    for value in features.get(feature).into_iter().flatten() {
+        if let Some(dep) = value.strip_prefix("dep:") {
+            enabled.insert(dep.to_string());
+        } else if let Some((dep, _)) = value.split_once('/') {
+            // `dep?/feature` only enables the feature if something else enables `dep`
+            if !dep.ends_with('?') && optional.contains(dep) {
+                enabled.insert(dep.to_string());
+            }
+        } else if features.contains_key(value) {
+            enabled.extend(enabled_optional(value, features, optional, visited));
+        } else if optional.contains(value) {
+            enabled.insert(value.clone());
+        }
+    }
+
+    enabled
+}
+
+/// Report on the dependencies and features of the given manifest
+pub fn report(manifest: &Manifest, text: Option<&str>) -> DependencyReport {
+    let mut reporter = Reporter {
+        text: text.map(|text| (text, Spans::new(text))),
+        warnings: Vec::new(),
+    };
+
+    let kinds = reporter.kinds(
+        "",
+        manifest.dependencies.as_ref(),
+        manifest.dev_dependencies.as_ref(),
+        manifest.build_dependencies.as_ref(),
+    );
+
+    let targets = manifest
+        .target
+        .iter()
+        .flatten()
+        .map(|(target, deps)| TargetDependencies {
+            target: target.clone(),
+            kinds: reporter.kinds(
+                &format!("target.{target}."),
+                Some(&deps.dependencies),
+                Some(&deps.dev_dependencies),
+                Some(&deps.build_dependencies),
+            ),
+        })
+        .collect::<Vec<_>>();
+
+    let workspace_dependencies = reporter.dependencies(
+        "workspace.dependencies",
+        manifest
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.dependencies.as_ref()),
+    );
+
+    // Dev dependencies can't be optional
+    let optional = kinds
+        .dependencies
+        .iter()
+        .chain(&kinds.build_dependencies)
+        .chain(targets.iter().flat_map(|target| {
+            target
+                .kinds
+                .dependencies
+                .iter()
+                .chain(&target.kinds.build_dependencies)
+        }))
+        .filter(|dep| dep.optional)
+        .map(|dep| dep.name.clone())
+        .collect::<BTreeSet<_>>();
+
+    let mut declared = manifest.features.clone().unwrap_or_default();
+
+    // Optional dependencies never named with `dep:` get a feature of their own
+    let named = declared
+        .values()
+        .flatten()
+        .filter_map(|value| value.strip_prefix("dep:"))
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>();
+
+    let implicit = optional
+        .iter()
+        .filter(|dep| !named.contains(*dep) && !declared.contains_key(*dep))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    for dep in &implicit {
+        declared.insert(dep.clone(), vec![format!("dep:{dep}")]);
+    }
+
+    let features = declared
+        .iter()
+        .map(|(name, enables)| FeatureInfo {
+            name: name.clone(),
+            enables: enables.clone(),
+            optional_dependencies: enabled_optional(
+                name,
+                &declared,
+                &optional,
+                &mut BTreeSet::new(),
+            ),
+            implicit: implicit.contains(name),
+        })
+        .collect::<Vec<_>>();
+
+    let mut optional_dependencies = optional
+        .iter()
+        .map(|dep| (dep.clone(), BTreeSet::new()))
+        .collect::<BTreeMap<_, _>>();
+
+    for feature in &features {
+        for dep in &feature.optional_dependencies {
+            if let Some(enabled_by) = optional_dependencies.get_mut(dep) {
+                enabled_by.insert(feature.name.clone());
+            }
+        }
+    }
+
+    DependencyReport {
+        kinds,
+        targets,
+        workspace_dependencies,
+        features,
+        optional_dependencies,
+        warnings: reporter.warnings,
+    }
+}
+
+/// Decode the request body and report on the manifest it holds
+async fn read_report(
+    headers: &HeaderMap,
+    content_type: &str,
+    body: Body,
+    limits: &ManifestLimits,
+) -> Result<DependencyReport, ManifestError> {
+    let body = limits.read_body(body).await?;
+    let body = decode_body(headers, body, limits)?;
+    let (toml, converted) = manifest_toml(input_format(content_type)?, &body, limits)?;
+
+    let text = std::str::from_utf8(&toml).map_err(|e| {
+        ManifestError::invalid(vec![Diagnostic::new("", format!("invalid UTF-8: {e}"))])
+    })?;
+
+    let manifest = Manifest::from_slice(&toml).map_err(|_| {
+        let mut diagnostics = diagnostics::manifest_error(text);
+        if converted {
+            for diag in &mut diagnostics {
+                diag.span = None;
+            }
+        }
+
+        ManifestError::invalid(diagnostics)
+    })?;
+
+    Ok(report(&manifest, (!converted).then_some(text)))
+}
+
+pub async fn dependencies(
+    State(policy): State<Arc<ManifestPolicy>>,
+    headers: HeaderMap,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    body: Body,
+) -> Response {
+    let result = read_report(&headers, &content_type.to_string(), body, &policy.limits).await;
+
+    match result {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            let report = ErrorReport {
+                error: &e.message,
+                diagnostics: &e.diagnostics,
+            };
+
+            (e.status, Json(report)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod dependencies_tests {
+    use super::{is_wildcard, report, Source};
+    use cargo_manifest::Manifest;
+
+    #[test]
+    fn wildcards() {
+        assert!(is_wildcard("*"));
+        assert!(is_wildcard("1.*"));
+        assert!(is_wildcard(">=1.2, 1.*.*"));
+        assert!(!is_wildcard("1.2"));
+        assert!(!is_wildcard("^0.1.0"));
+    }
+
+    #[test]
+    fn features_enable_optional_dependencies() {
+        let text = r#"
+[package]
+name = "gifts"
+
+[dependencies]
+serde = { version = "1", optional = true }
+serde_json = { version = "1", optional = true }
+rand = { version = "*", optional = true }
+log = { git = "https://github.com/rust-lang/log", tag = "0.4.22" }
+
+[target.'cfg(windows)'.dependencies]
+winapi = { path = "../winapi", optional = true }
+
+[features]
+default = ["json"]
+json = ["dep:serde_json", "serde"]
+derive = ["serde?/derive"]
+windows = ["dep:winapi"]
+"#;
+
+        let manifest = Manifest::from_slice(text.as_bytes()).unwrap();
+        let report = report(&manifest, Some(text));
+
+        let enabled_by = |dep: &str| {
+            report.optional_dependencies[dep]
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(enabled_by("serde"), vec!["default", "json", "serde"]);
+        assert_eq!(enabled_by("serde_json"), vec!["default", "json"]);
+        assert_eq!(enabled_by("rand"), vec!["rand"]);
+        assert_eq!(enabled_by("winapi"), vec!["windows"]);
+
+        // serde is only named without `dep:`, so keeps its implicit feature
+        let implicit = report
+            .features
+            .iter()
+            .filter(|feature| feature.implicit)
+            .map(|feature| feature.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(implicit, vec!["rand", "serde"]);
+
+        let log = &report.kinds.dependencies[0];
+        assert_eq!(log.source, Source::Git);
+        assert_eq!(log.reference.as_deref(), Some("0.4.22"));
+
+        let warnings = report
+            .warnings
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![
+                "9:7: dependencies.log: git dependency",
+                "8:8: dependencies.rand: wildcard version requirement *",
+                "12:10: target.cfg(windows).dependencies.winapi: path dependency",
+            ]
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use super::Orders;
+use cargo_manifest::Manifest;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::ops::Range;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+/// A position in the manifest, both 1-based
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// The part of the manifest a diagnostic refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// A single problem found in a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// The manifest of a workspace upload the diagnostic is for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+
+    /// TOML path of the offending value, e.g. `package.metadata.orders[2].quantity`
+    pub path: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>,
+
+    pub reason: String,
+
+    /// The policy rule that was violated, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<&'static str>,
+}
+
+impl Diagnostic {
+    pub fn new(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            file: None,
+            path: path.into(),
+            span: None,
+            reason: reason.into(),
+            rule: None,
+        }
+    }
+
+    /// Attribute the diagnostic to the given manifest of a workspace upload
+    #[must_use]
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    /// Attribute the diagnostic to a policy rule
+    #[must_use]
+    pub fn rule(mut self, rule: &'static str) -> Self {
+        self.rule = Some(rule);
+        self
+    }
+
+    /// Locate the diagnostic at the given byte range of `text`
+    #[must_use]
+    pub fn at(mut self, text: &str, range: Option<Range<usize>>) -> Self {
+        self.span = range.map(|range| Span {
+            start: position(text, range.start),
+            end: position(text, range.end),
+        });
+
+        self
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            let separator = if self.span.is_some() { ":" } else { ": " };
+            write!(f, "{file}{separator}")?;
+        }
+
+        match &self.span {
+            Some(span) => write!(
+                f,
+                "{}:{}: {}: {}",
+                span.start.line, span.start.column, self.path, self.reason
+            ),
+            None => write!(f, "{}: {}", self.path, self.reason),
+        }
+    }
+}
+
+/// The line and column of the given byte offset into `text`
+fn position(text: &str, offset: usize) -> Position {
+    let before = &text.as_bytes()[..offset.min(text.len())];
+    let before = String::from_utf8_lossy(before);
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+
+    // Anything beyond u32::MAX lines is rejected long before it gets here
+    let saturate = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+
+    Position {
+        line: saturate(line),
+        column: saturate(column),
+    }
+}
+
+/// Explain why the manifest failed to deserialize, pointing at the offending value
+pub fn manifest_error(text: &str) -> Vec<Diagnostic> {
+    let deserializer = toml::Deserializer::new(text);
+
+    match serde_path_to_error::deserialize::<_, Manifest<Orders>>(deserializer) {
+        Ok(_) => Vec::new(),
+        Err(e) => {
+            let path = e.path().to_string();
+            let path = if path == "." { String::new() } else { path };
+
+            vec![Diagnostic::new(path, e.inner().message()).at(text, e.inner().span())]
+        }
+    }
+}
+
+/// The manifest parsed once, to look up where the values at many paths are
+pub struct Spans<'a> {
+    doc: Option<ImDocument<&'a str>>,
+}
+
+impl<'a> Spans<'a> {
+    /// Parse the manifest, no path has a span if it isn't valid TOML
+    pub fn new(text: &'a str) -> Self {
+        Self {
+            doc: ImDocument::parse(text).ok(),
+        }
+    }
+
+    /// The byte range of the value at the given path, such as `package.metadata.orders[2].item`.
+    /// Values without a span of their own, like dotted keys' tables, fall back to the span of
+    /// their key.
+    pub fn of(&self, path: &str) -> Option<Range<usize>> {
+        let mut item = self.doc.as_ref()?.as_item();
+        let mut key_span = None;
+
+        for segment in path.split('.') {
+            let mut parts = segment.split('[');
+
+            let (key, value) = item.as_table_like()?.get_key_value(parts.next()?)?;
+            item = value;
+            key_span = key.span();
+
+            for index in parts {
+                item = item.get(index.strip_suffix(']')?.parse::<usize>().ok()?)?;
+                key_span = None;
+            }
+        }
+
+        item.span().or(key_span)
+    }
+}
+
+/// An order's fields, unless it isn't a table, along with where the order is
+type OrderTable<'a> = (Option<&'a dyn TableLike>, Option<Range<usize>>);
+
+/// The fields of the order schema, any others are passed through
+const ORDER_FIELDS: [&str; 5] = ["item", "quantity", "unit", "unit-price", "currency"];
+
+/// The TOML type name of a value, as used in the reasons
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Integer(_) => "integer",
+        Value::Float(_) => "float",
+        Value::Boolean(_) => "boolean",
+        Value::Datetime(_) => "datetime",
+        Value::Array(_) => "array",
+        Value::InlineTable(_) => "table",
+    }
+}
+
+/// Why a pricing field of an order has to be ignored, if it does
+fn pricing_problem(key: &str, value: &Value) -> Option<String> {
+    let valid = match (key, value) {
+        ("unit" | "currency", Value::String(_))
+        | ("unit-price", Value::Integer(_) | Value::Float(_)) => true,
+        ("unit-price", Value::String(price)) => {
+            let price = price.value();
+            return (price.parse::<Decimal>().is_err() && Decimal::from_scientific(price).is_err())
+                .then(|| format!("invalid unit price {price:?}, ignoring it"));
+        }
+        _ => false,
+    };
+
+    let expected = if key == "unit-price" {
+        "number"
+    } else {
+        "string"
+    };
+
+    (!valid).then(|| {
+        format!(
+            "expected {expected}, found {}, ignoring it",
+            type_name(value)
+        )
+    })
+}
+
+/// Find the orders that will be skipped, and the fields that will be ignored, and why
+pub fn order_diagnostics(text: &str) -> Vec<Diagnostic> {
+    let Ok(doc) = ImDocument::parse(text) else {
+        return Vec::new();
+    };
+
+    let orders = doc
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("orders"));
+
+    // Orders can be an array of tables or an inline array
+    let orders: Vec<OrderTable> = match orders {
+        Some(Item::ArrayOfTables(tables)) => tables
+            .iter()
+            .map(|order| (Some(order as &dyn TableLike), order.span()))
+            .collect(),
+        Some(Item::Value(Value::Array(array))) => array
+            .iter()
+            .map(|order| {
+                let table = order.as_inline_table().map(|table| table as &dyn TableLike);
+                (table, order.span())
+            })
+            .collect(),
+        _ => return Vec::new(),
+    };
+
+    let mut diagnostics = Vec::new();
+
+    for (index, (table, span)) in orders.into_iter().enumerate() {
+        let path = format!("package.metadata.orders[{index}]");
+        let field = |name| {
+            table
+                .and_then(|table| table.get(name))
+                .and_then(Item::as_value)
+        };
+        let (item, quantity) = (field("item"), field("quantity"));
+
+        for key in ["unit", "unit-price", "currency"] {
+            let Some(value) = field(key) else {
+                continue;
+            };
+
+            if let Some(reason) = pricing_problem(key, value) {
+                diagnostics
+                    .push(Diagnostic::new(format!("{path}.{key}"), reason).at(text, value.span()));
+            }
+        }
+
+        let unknown = table
+            .into_iter()
+            .flat_map(|table| table.iter())
+            .filter(|(key, _)| !ORDER_FIELDS.contains(key));
+
+        for (key, value) in unknown {
+            diagnostics.push(
+                Diagnostic::new(
+                    format!("{path}.{key}"),
+                    format!("unknown field `{key}`, passed through as is"),
+                )
+                .at(text, value.span()),
+            );
+        }
+
+        match item {
+            Some(Value::String(_)) => {}
+            Some(item) => diagnostics.push(
+                Diagnostic::new(
+                    format!("{path}.item"),
+                    format!("expected string, found {}", type_name(item)),
+                )
+                .at(text, item.span()),
+            ),
+            None => diagnostics
+                .push(Diagnostic::new(&path, "missing field `item`").at(text, span.clone())),
+        }
+
+        match quantity {
+            Some(Value::Integer(quantity)) if u32::try_from(*quantity.value()).is_ok() => {}
+            Some(Value::Integer(quantity)) => diagnostics.push(
+                Diagnostic::new(
+                    format!("{path}.quantity"),
+                    format!("quantity {} is out of range", quantity.value()),
+                )
+                .at(text, quantity.span()),
+            ),
+            Some(quantity) => diagnostics.push(
+                Diagnostic::new(
+                    format!("{path}.quantity"),
+                    format!("expected integer, found {}", type_name(quantity)),
+                )
+                .at(text, quantity.span()),
+            ),
+            None => {
+                diagnostics.push(Diagnostic::new(&path, "missing field `quantity`").at(text, span));
+            }
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+    use super::{manifest_error, order_diagnostics, position, Position};
+
+    #[test]
+    fn positions() {
+        let text = "a = 1\nbé = 2\n";
+
+        assert_eq!(position(text, 0), Position { line: 1, column: 1 });
+        assert_eq!(position(text, 6), Position { line: 2, column: 1 });
+        // Columns count characters, not bytes
+        assert_eq!(position(text, 9), Position { line: 2, column: 3 });
+    }
+
+    #[test]
+    fn bad_orders() {
+        let text = r#"
+[package]
+name = "orders"
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230.5
+
+[[package.metadata.orders]]
+quantity = -1
+colour = "red"
+
+[[package.metadata.orders]]
+item = "Coal"
+quantity = 1
+unit = "kg"
+unit-price = "free"
+currency = 5
+"#;
+
+        let diagnostics = order_diagnostics(text)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            diagnostics,
+            vec![
+                "11:12: package.metadata.orders[1].quantity: expected integer, found float",
+                "15:10: package.metadata.orders[2].colour: unknown field `colour`, passed through as is",
+                "13:1: package.metadata.orders[2]: missing field `item`",
+                "14:12: package.metadata.orders[2].quantity: quantity -1 is out of range",
+                "21:14: package.metadata.orders[3].unit-price: invalid unit price \"free\", ignoring it",
+                "22:12: package.metadata.orders[3].currency: expected string, found integer, ignoring it",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_manifest() {
+        let text = "[package]\nname = false\n";
+
+        let diagnostics = manifest_error(text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].path, "package.name");
+        assert_eq!(diagnostics[0].span.unwrap().start.line, 2);
+    }
+}
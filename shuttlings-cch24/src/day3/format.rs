@@ -0,0 +1,259 @@
+use axum::{
+    http::{header::ACCEPT, header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use mime::Mime;
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// Formats that can carry the full result, including diagnostics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Structured {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// The formats orders can be returned in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One `item: quantity` line per order
+    Text,
+
+    /// One row per order, without diagnostics
+    Csv,
+
+    Structured(Structured),
+}
+
+/// Every format along with the media types it's returned for. Earlier formats are
+/// preferred when the client accepts several equally.
+const FORMATS: &[(Format, &[&str])] = &[
+    (Format::Text, &["text/plain"]),
+    (Format::Structured(Structured::Json), &["application/json"]),
+    (
+        Format::Structured(Structured::Yaml),
+        &["application/yaml", "application/x-yaml", "text/yaml"],
+    ),
+    (Format::Structured(Structured::Toml), &["application/toml"]),
+    (Format::Csv, &["text/csv"]),
+];
+
+/// A media range of an `Accept` header along with its quality, in thousandths
+struct MediaRange {
+    mime: Mime,
+    quality: u16,
+}
+
+impl MediaRange {
+    fn parse(range: &str) -> Option<Self> {
+        let mime = range.trim().parse::<Mime>().ok()?;
+
+        let quality = match mime.get_param("q") {
+            Some(q) => quality(q.as_str())?,
+            None => 1000,
+        };
+
+        Some(Self { mime, quality })
+    }
+
+    /// How specifically this range matches the given media type, if at all
+    fn specificity(&self, media_type: &Mime) -> Option<u8> {
+        if self.mime.type_() == mime::STAR {
+            return Some(0);
+        }
+
+        if self.mime.type_() != media_type.type_() {
+            return None;
+        }
+
+        if self.mime.subtype() == mime::STAR {
+            return Some(1);
+        }
+
+        (self.mime.subtype() == media_type.subtype()).then_some(2)
+    }
+}
+
+/// Parse a quality value such as `0.75` as thousandths
+fn quality(q: &str) -> Option<u16> {
+    let (whole, fraction) = q.split_once('.').unwrap_or((q, ""));
+
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = format!("{fraction:0<3}").parse::<u16>().ok()?;
+
+    match whole {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+impl Format {
+    /// Pick the format the client most prefers from its `Accept` headers. Text is returned
+    /// when nothing is asked for, and `None` when nothing asked for is supported.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Format> {
+        let ranges = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .flat_map(|accept| accept.split(','))
+            .filter(|range| !range.trim().is_empty())
+            .filter_map(MediaRange::parse)
+            .collect::<Vec<_>>();
+
+        if ranges.is_empty() {
+            return Some(Format::Text);
+        }
+
+        // The quality of each format is that of the most specific range matching it
+        let quality = |media_types: &[&str]| {
+            media_types
+                .iter()
+                .filter_map(|media_type| media_type.parse::<Mime>().ok())
+                .filter_map(|media_type| {
+                    ranges
+                        .iter()
+                        .filter_map(|range| Some((range.specificity(&media_type)?, range.quality)))
+                        .max_by_key(|(specificity, _)| *specificity)
+                        .map(|(_, quality)| quality)
+                })
+                .max()
+                .unwrap_or(0)
+        };
+
+        let mut best: Option<(Format, u16)> = None;
+
+        for (format, media_types) in FORMATS {
+            let quality = quality(media_types);
+
+            if quality > 0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((*format, quality));
+            }
+        }
+
+        best.map(|(format, _)| format)
+    }
+
+    /// The media types that can be asked for, for the 406 response
+    pub fn supported() -> String {
+        FORMATS
+            .iter()
+            .flat_map(|(_, media_types)| media_types.iter())
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Structured {
+    fn content_type(self) -> &'static str {
+        match self {
+            Structured::Json => "application/json",
+            Structured::Yaml => "application/yaml",
+            Structured::Toml => "application/toml",
+        }
+    }
+
+    /// Serialize `value` in this format
+    pub fn serialize<T: Serialize>(self, value: &T) -> Response {
+        let body = match self {
+            Structured::Json => serde_json::to_string(value).map_err(|e| e.to_string()),
+            Structured::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+            Structured::Toml => toml::to_string(value).map_err(|e| e.to_string()),
+        };
+
+        match body {
+            Ok(body) => ([(CONTENT_TYPE, self.content_type())], body).into_response(),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to serialize: {e}"),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Quote a CSV field if needed
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Write the given rows as CSV, with a header row
+pub fn csv(header: &[&str], rows: impl Iterator<Item = Vec<String>>) -> Response {
+    let mut body = header.join(",");
+    body.push_str("\r\n");
+
+    for row in rows {
+        let row = row.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+        body.push_str(&row.join(","));
+        body.push_str("\r\n");
+    }
+
+    ([(CONTENT_TYPE, "text/csv")], body).into_response()
+}
+
+#[cfg(test)]
+mod format_tests {
+    use super::{csv_field, quality, Format, Structured};
+    use axum::http::{header::ACCEPT, HeaderMap, HeaderValue};
+
+    fn negotiate(accept: &[&str]) -> Option<Format> {
+        let mut headers = HeaderMap::new();
+        for accept in accept {
+            headers.append(ACCEPT, HeaderValue::from_str(accept).unwrap());
+        }
+
+        Format::negotiate(&headers)
+    }
+
+    #[test]
+    fn qualities() {
+        assert_eq!(quality("1"), Some(1000));
+        assert_eq!(quality("1.000"), Some(1000));
+        assert_eq!(quality("0.5"), Some(500));
+        assert_eq!(quality("0.125"), Some(125));
+        assert_eq!(quality("0"), Some(0));
+        assert_eq!(quality("1.5"), None);
+        assert_eq!(quality("0.1234"), None);
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(&[]), Some(Format::Text));
+        assert_eq!(negotiate(&["*/*"]), Some(Format::Text));
+        assert_eq!(negotiate(&["text/csv"]), Some(Format::Csv));
+        assert_eq!(
+            negotiate(&["text/plain; q=0.5, application/yaml"]),
+            Some(Format::Structured(Structured::Yaml))
+        );
+        assert_eq!(
+            negotiate(&["text/html", "application/toml;q=0.1"]),
+            Some(Format::Structured(Structured::Toml))
+        );
+
+        // More specific ranges override less specific ones
+        assert_eq!(
+            negotiate(&["text/*, text/plain;q=0"]),
+            Some(Format::Structured(Structured::Yaml))
+        );
+        assert_eq!(negotiate(&["application/*;q=0, */*"]), Some(Format::Text));
+
+        assert_eq!(negotiate(&["text/html"]), None);
+        assert_eq!(negotiate(&["application/json;q=0"]), None);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("Toy car"), "Toy car");
+        assert_eq!(csv_field("Toy car, red"), "\"Toy car, red\"");
+        assert_eq!(csv_field("12\" ruler"), "\"12\"\" ruler\"");
+    }
+}
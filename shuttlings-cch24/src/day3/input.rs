@@ -0,0 +1,179 @@
+use super::{ManifestError, ManifestLimits};
+use axum::{
+    body::Bytes,
+    http::{header::CONTENT_ENCODING, HeaderMap, StatusCode},
+};
+use flate2::read::GzDecoder;
+use mime::Mime;
+use std::io::Read;
+
+/// The formats manifests can be sent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Toml,
+    Yaml,
+    Json,
+    Json5,
+    Ron,
+
+    /// A workspace root along with its members' manifests
+    Multipart,
+}
+
+impl InputFormat {
+    /// The format of the given `Content-Type`. Parameters such as `charset` are ignored, and
+    /// structured syntax suffixes like `application/vnd.gifts+json` are understood.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.parse::<Mime>().ok()?;
+
+        let format = match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("application" | "text", "toml" | "x-toml") => Self::Toml,
+            ("application" | "text", "yaml" | "x-yaml") => Self::Yaml,
+            ("application" | "text", "json") => Self::Json,
+            ("application", "json5") => Self::Json5,
+            ("application", "ron" | "x-ron") => Self::Ron,
+            ("multipart", "form-data") => Self::Multipart,
+            _ => match mime.suffix()?.as_str() {
+                "json" => Self::Json,
+                "yaml" => Self::Yaml,
+                "toml" => Self::Toml,
+                _ => return None,
+            },
+        };
+
+        Some(format)
+    }
+}
+
+/// The format of the given `Content-Type`, or 415 if it isn't supported
+pub fn input_format(content_type: &str) -> Result<InputFormat, ManifestError> {
+    InputFormat::from_content_type(content_type).ok_or_else(|| {
+        ManifestError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            &format!("Unknown content type: {content_type}"),
+        )
+    })
+}
+
+/// Undo the body's `Content-Encoding`. Only gzip is supported, and decompressed bodies are
+/// held to the same size limit.
+pub fn decode_body(
+    headers: &HeaderMap,
+    body: Bytes,
+    limits: &ManifestLimits,
+) -> Result<Bytes, ManifestError> {
+    let encodings = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .map(|encoding| encoding.to_str().unwrap_or_default())
+        .flat_map(|encoding| encoding.split(','))
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty())
+        .collect::<Vec<_>>();
+
+    // Encodings are listed in the order they were applied
+    let mut body = body;
+    for encoding in encodings.iter().rev() {
+        match encoding.as_str() {
+            "identity" => {}
+            "gzip" | "x-gzip" => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(body.as_ref())
+                    .take(limits.body_size as u64 + 1)
+                    .read_to_end(&mut decoded)
+                    .map_err(|e| {
+                        ManifestError::new(
+                            StatusCode::BAD_REQUEST,
+                            &format!("Invalid gzip body: {e}"),
+                        )
+                    })?;
+
+                if decoded.len() > limits.body_size {
+                    return Err(limits.too_large());
+                }
+
+                body = Bytes::from(decoded);
+            }
+            x => {
+                return Err(ManifestError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    &format!("Unknown content encoding: {x}"),
+                ))
+            }
+        }
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod input_tests {
+    use super::{decode_body, InputFormat};
+    use crate::day3::ManifestLimits;
+    use axum::{
+        body::Bytes,
+        http::{header::CONTENT_ENCODING, HeaderMap, HeaderValue, StatusCode},
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    #[test]
+    fn content_types() {
+        let format = InputFormat::from_content_type;
+
+        assert_eq!(
+            format("application/toml; charset=utf-8"),
+            Some(InputFormat::Toml)
+        );
+        assert_eq!(format("text/x-toml"), Some(InputFormat::Toml));
+        assert_eq!(format("Application/YAML"), Some(InputFormat::Yaml));
+        assert_eq!(
+            format("application/vnd.gifts+json"),
+            Some(InputFormat::Json)
+        );
+        assert_eq!(format("application/ld+yaml"), Some(InputFormat::Yaml));
+        assert_eq!(format("application/json5"), Some(InputFormat::Json5));
+        assert_eq!(format("application/ron"), Some(InputFormat::Ron));
+        assert_eq!(
+            format("multipart/form-data; boundary=x"),
+            Some(InputFormat::Multipart)
+        );
+        assert_eq!(format("text/html"), None);
+        assert_eq!(format("not a media type"), None);
+    }
+
+    #[test]
+    fn gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"[package]").unwrap();
+        let gzipped = Bytes::from(encoder.finish().unwrap());
+
+        let limits = ManifestLimits::default();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            decode_body(&headers, gzipped.clone(), &limits).unwrap(),
+            gzipped
+        );
+
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(
+            decode_body(&headers, gzipped.clone(), &limits).unwrap(),
+            "[package]"
+        );
+
+        // The limit applies to the decompressed body
+        let small = ManifestLimits {
+            body_size: 8,
+            ..ManifestLimits::default()
+        };
+        let e = decode_body(&headers, gzipped, &small).unwrap_err();
+        assert_eq!(e.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let e = decode_body(&headers, Bytes::from("not gzip"), &limits).unwrap_err();
+        assert_eq!(e.status, StatusCode::BAD_REQUEST);
+
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        let e = decode_body(&headers, Bytes::new(), &limits).unwrap_err();
+        assert_eq!(e.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -0,0 +1,211 @@
+use super::diagnostics::Diagnostic;
+use super::{ManifestError, OrderLine};
+use axum::http::StatusCode;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// How to total orders priced in several currencies
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CurrencyConversion {
+    /// The currency such invoices are totalled in
+    pub currency: String,
+
+    /// What one unit of each other currency is worth in `currency`
+    pub rates: HashMap<String, Decimal>,
+}
+
+impl CurrencyConversion {
+    /// The rate to convert from the given currency. Orders without a currency are taken to
+    /// be priced in the invoice currency.
+    fn rate(&self, currency: Option<&str>) -> Option<Decimal> {
+        match currency {
+            None => Some(Decimal::ONE),
+            Some(currency) if currency.eq_ignore_ascii_case(&self.currency) => Some(Decimal::ONE),
+            Some(currency) => self
+                .rates
+                .iter()
+                .find(|(other, _)| other.eq_ignore_ascii_case(currency))
+                .map(|(_, rate)| *rate),
+        }
+    }
+}
+
+/// The total of every priced order
+#[derive(Debug, Serialize)]
+pub struct Invoice {
+    /// Not set if no order named a currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+
+    /// Rounded to cents when converted from other currencies
+    pub total: Decimal,
+
+    /// The number of orders invoiced, as orders without a unit price aren't
+    pub lines: usize,
+}
+
+fn overflow() -> ManifestError {
+    ManifestError::new(StatusCode::BAD_REQUEST, "Order total overflow")
+}
+
+fn mixed_currencies(reason: String) -> ManifestError {
+    ManifestError {
+        diagnostics: vec![Diagnostic::new("package.metadata.orders", reason)],
+        ..ManifestError::new(StatusCode::BAD_REQUEST, "Mixed currencies")
+    }
+}
+
+/// Set the line total of each priced order and total them up, converting currencies if
+/// they're mixed. `None` if no order has a unit price.
+pub fn invoice(
+    orders: &mut [OrderLine],
+    conversion: Option<&CurrencyConversion>,
+) -> Result<Option<Invoice>, ManifestError> {
+    for line in orders.iter_mut() {
+        line.line_total = line
+            .unit_price
+            .map(|price| price.checked_mul(Decimal::from(line.quantity)))
+            .map(|total| total.ok_or_else(overflow))
+            .transpose()?;
+    }
+
+    let priced = orders
+        .iter()
+        .filter_map(|line| Some((line.currency.as_deref(), line.line_total?)))
+        .collect::<Vec<_>>();
+
+    if priced.is_empty() {
+        return Ok(None);
+    }
+
+    let currencies = priced
+        .iter()
+        .map(|(currency, _)| *currency)
+        .collect::<BTreeSet<_>>();
+
+    if let [currency] = currencies.iter().collect::<Vec<_>>()[..] {
+        let total = priced
+            .iter()
+            .try_fold(Decimal::ZERO, |total, (_, line)| total.checked_add(*line))
+            .ok_or_else(overflow)?;
+
+        return Ok(Some(Invoice {
+            currency: currency.map(str::to_string),
+            total,
+            lines: priced.len(),
+        }));
+    }
+
+    let Some(conversion) = conversion else {
+        let names = currencies
+            .iter()
+            .map(|currency| currency.unwrap_or("no currency"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return Err(mixed_currencies(format!(
+            "orders are priced in {names}, but no conversion table is configured"
+        )));
+    };
+
+    let mut total = Decimal::ZERO;
+
+    for (currency, line) in &priced {
+        let rate = conversion.rate(*currency).ok_or_else(|| {
+            mixed_currencies(format!(
+                "no conversion rate from {} to {}",
+                currency.unwrap_or_default(),
+                conversion.currency
+            ))
+        })?;
+
+        total = line
+            .checked_mul(rate)
+            .and_then(|line| total.checked_add(line))
+            .ok_or_else(overflow)?;
+    }
+
+    Ok(Some(Invoice {
+        currency: Some(conversion.currency.to_uppercase()),
+        total: total.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero),
+        lines: priced.len(),
+    }))
+}
+
+#[cfg(test)]
+mod invoice_tests {
+    use super::{invoice, CurrencyConversion};
+    use crate::day3::OrderLine;
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+
+    fn line(quantity: u32, unit_price: Option<&str>, currency: Option<&str>) -> OrderLine {
+        OrderLine {
+            item: "Toy car".to_string(),
+            quantity,
+            unit_price: unit_price.map(|price| price.parse().unwrap()),
+            currency: currency.map(str::to_string),
+            ..OrderLine::default()
+        }
+    }
+
+    #[test]
+    fn totals() {
+        let mut orders = vec![
+            line(2, Some("2.50"), Some("EUR")),
+            line(3, None, None),
+            line(1, Some("10"), Some("EUR")),
+        ];
+
+        let totalled = invoice(&mut orders, None).unwrap().unwrap();
+        assert_eq!(totalled.currency.as_deref(), Some("EUR"));
+        assert_eq!(totalled.total.to_string(), "15.00");
+        assert_eq!(totalled.lines, 2);
+
+        let totals = orders
+            .iter()
+            .map(|line| line.line_total.map(|total| total.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            totals,
+            vec![Some("5.00".to_string()), None, Some("10".to_string())]
+        );
+
+        let mut unpriced = vec![line(2, None, Some("EUR"))];
+        assert!(invoice(&mut unpriced, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn mixed_currencies() {
+        let mut orders = vec![
+            line(2, Some("2.50"), Some("EUR")),
+            line(1, Some("3"), Some("USD")),
+            line(1, Some("1"), None),
+        ];
+
+        let e = invoice(&mut orders, None).unwrap_err();
+        assert_eq!(e.message, "Mixed currencies");
+        assert_eq!(
+            e.diagnostics[0].reason,
+            "orders are priced in no currency, EUR, USD, but no conversion table is configured"
+        );
+
+        let mut conversion = CurrencyConversion {
+            currency: "eur".to_string(),
+            rates: HashMap::from([("usd".to_string(), "0.955".parse::<Decimal>().unwrap())]),
+        };
+
+        let converted = invoice(&mut orders, Some(&conversion)).unwrap().unwrap();
+        assert_eq!(converted.currency.as_deref(), Some("EUR"));
+        assert_eq!(converted.total.to_string(), "8.87");
+
+        conversion.rates.clear();
+        let e = invoice(&mut orders, Some(&conversion)).unwrap_err();
+        assert_eq!(
+            e.diagnostics[0].reason,
+            "no conversion rate from USD to eur"
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use super::diagnostics::{self, Diagnostic};
+use super::ManifestError;
+use axum::{
+    body::{Body, Bytes},
+    http::StatusCode,
+};
+use http_body_util::LengthLimitError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use yaml_rust2::parser::{Event, Parser};
+
+/// Limits on the manifests that are accepted, so large or malicious ones can't exhaust
+/// memory or the stack while they're parsed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ManifestLimits {
+    /// The largest body accepted in bytes, both as sent and once decompressed
+    pub body_size: usize,
+
+    /// How deeply tables and arrays may be nested
+    pub depth: usize,
+
+    /// How many values YAML aliases may expand to, in total
+    pub alias_expansion: usize,
+}
+
+impl Default for ManifestLimits {
+    fn default() -> Self {
+        Self {
+            body_size: 1024 * 1024,
+            depth: 32,
+            alias_expansion: 1000,
+        }
+    }
+}
+
+/// An anchored YAML node, as needed to expand aliases to it
+#[derive(Clone, Copy)]
+struct Anchored {
+    /// The number of values in it, including itself
+    values: usize,
+
+    /// How deeply it nests, 0 for scalars
+    depth: usize,
+}
+
+/// A YAML sequence or mapping being read
+struct Collection {
+    anchor: usize,
+    contents: Anchored,
+}
+
+impl ManifestLimits {
+    pub(super) fn too_large(&self) -> ManifestError {
+        ManifestError {
+            diagnostics: vec![Diagnostic::new(
+                "",
+                format!("larger than {} bytes", self.body_size),
+            )],
+            ..ManifestError::new(StatusCode::PAYLOAD_TOO_LARGE, "Manifest too large")
+        }
+    }
+
+    fn too_deep(&self) -> ManifestError {
+        ManifestError {
+            diagnostics: vec![Diagnostic::new(
+                "",
+                format!("nested deeper than {} levels", self.depth),
+            )],
+            ..ManifestError::new(StatusCode::BAD_REQUEST, "Manifest nested too deeply")
+        }
+    }
+
+    /// Read the body, giving up as soon as it's too large
+    pub(super) async fn read_body(&self, body: Body) -> Result<Bytes, ManifestError> {
+        axum::body::to_bytes(body, self.body_size)
+            .await
+            .map_err(|e| {
+                if e.into_inner().is::<LengthLimitError>() {
+                    self.too_large()
+                } else {
+                    ManifestError::new(StatusCode::BAD_REQUEST, "Failed to read body")
+                }
+            })
+    }
+
+    /// Check how deeply a parsed manifest nests
+    pub(super) fn check_depth(&self, value: &serde_json::Value) -> Result<(), ManifestError> {
+        // Iterative, as the value can be as deep as the parsers allow
+        let mut stack = vec![(value, 0)];
+
+        while let Some((value, depth)) = stack.pop() {
+            let children: Box<dyn Iterator<Item = &serde_json::Value>> = match value {
+                serde_json::Value::Array(values) => Box::new(values.iter()),
+                serde_json::Value::Object(values) => Box::new(values.values()),
+                _ => continue,
+            };
+
+            // The top-level table doesn't count
+            if depth > self.depth {
+                return Err(self.too_deep());
+            }
+
+            stack.extend(children.map(|child| (child, depth + 1)));
+        }
+
+        Ok(())
+    }
+
+    /// Check how deeply a TOML manifest nests. Manifests that don't parse are rejected,
+    /// with the same diagnostics the manifest parser gives.
+    pub(super) fn check_toml(&self, bytes: &[u8]) -> Result<(), ManifestError> {
+        let text = std::str::from_utf8(bytes).map_err(|e| {
+            ManifestError::invalid(vec![Diagnostic::new("", format!("invalid UTF-8: {e}"))])
+        })?;
+
+        match toml::from_str(text) {
+            Ok(value) => self.check_depth(&value),
+            Err(_) => Err(ManifestError::invalid(diagnostics::manifest_error(text))),
+        }
+    }
+
+    /// Check how deeply JSON, JSON5 or RON nests before it's parsed, as their parsers
+    /// recurse. Brackets in strings and comments don't count.
+    pub(super) fn check_brackets(&self, text: &str) -> Result<(), ManifestError> {
+        let mut depth = 0usize;
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '"' | '\'' => {
+                    while let Some(next) = chars.next() {
+                        match next {
+                            '\\' => {
+                                chars.next();
+                            }
+                            next if next == c => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '/' if chars.peek() == Some(&'/') => {
+                    chars.find(|&next| next == '\n');
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    let mut previous = ' ';
+                    chars
+                        .find(|&next| std::mem::replace(&mut previous, next) == '*' && next == '/');
+                }
+                '[' | '{' | '(' => {
+                    depth += 1;
+                    if depth > self.depth + 1 {
+                        return Err(self.too_deep());
+                    }
+                }
+                ']' | '}' | ')' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check how deeply YAML nests and how much its aliases expand before it's parsed, as
+    /// aliases are expanded in full when it's converted
+    pub(super) fn check_yaml(&self, text: &str) -> Result<(), ManifestError> {
+        let mut parser = Parser::new_from_str(text);
+        let mut anchors = HashMap::<usize, Anchored>::new();
+        let mut open = Vec::<Collection>::new();
+        let mut expanded = 0usize;
+
+        loop {
+            let (event, _) = parser.next_token().map_err(|e| {
+                ManifestError::invalid(vec![Diagnostic::new("", format!("invalid YAML: {e}"))])
+            })?;
+
+            let node = match event {
+                Event::StreamEnd => return Ok(()),
+                Event::Scalar(_, _, anchor, _) => {
+                    let node = Anchored {
+                        values: 1,
+                        depth: 0,
+                    };
+                    if anchor != 0 {
+                        anchors.insert(anchor, node);
+                    }
+
+                    node
+                }
+                Event::Alias(anchor) => {
+                    let Some(node) = anchors.get(&anchor).copied() else {
+                        return Err(ManifestError::invalid(vec![Diagnostic::new(
+                            "",
+                            "alias to an unknown anchor",
+                        )]));
+                    };
+
+                    expanded = expanded.saturating_add(node.values);
+                    if expanded > self.alias_expansion {
+                        return Err(ManifestError {
+                            diagnostics: vec![Diagnostic::new(
+                                "",
+                                format!(
+                                    "aliases expand to more than {} values",
+                                    self.alias_expansion
+                                ),
+                            )],
+                            ..ManifestError::new(StatusCode::BAD_REQUEST, "Too many YAML aliases")
+                        });
+                    }
+
+                    node
+                }
+                Event::SequenceStart(anchor, _) | Event::MappingStart(anchor, _) => {
+                    if open.len() > self.depth {
+                        return Err(self.too_deep());
+                    }
+
+                    open.push(Collection {
+                        anchor,
+                        contents: Anchored {
+                            values: 1,
+                            depth: 1,
+                        },
+                    });
+                    continue;
+                }
+                Event::SequenceEnd | Event::MappingEnd => {
+                    let Some(collection) = open.pop() else {
+                        return Ok(());
+                    };
+
+                    if collection.anchor != 0 {
+                        anchors.insert(collection.anchor, collection.contents);
+                    }
+
+                    collection.contents
+                }
+                _ => continue,
+            };
+
+            // The top-level mapping doesn't count, as for the other formats
+            if open.len() + node.depth > self.depth + 1 {
+                return Err(self.too_deep());
+            }
+
+            if let Some(parent) = open.last_mut() {
+                parent.contents.values = parent.contents.values.saturating_add(node.values);
+                parent.contents.depth = parent.contents.depth.max(node.depth + 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod limits_tests {
+    use super::ManifestLimits;
+    use axum::http::StatusCode;
+
+    fn limits() -> ManifestLimits {
+        ManifestLimits {
+            body_size: 64,
+            depth: 3,
+            alias_expansion: 10,
+        }
+    }
+
+    #[test]
+    fn depth() {
+        let depth = |json: &str| {
+            let value = serde_json::from_str(json).unwrap();
+            limits().check_depth(&value).map_err(|e| e.message)
+        };
+
+        assert_eq!(depth(r#"{"a": {"b": {"c": [1]}}}"#), Ok(()));
+        assert_eq!(
+            depth(r#"{"a": {"b": {"c": [[1]]}}}"#),
+            Err("Manifest nested too deeply".to_string())
+        );
+    }
+
+    #[test]
+    fn brackets() {
+        let limits = limits();
+
+        assert!(limits.check_brackets(r#"{"a": {"b": {"c": [1]}}}"#).is_ok());
+        assert!(limits
+            .check_brackets(r#"{"a": {"b": {"c": [[1]]}}}"#)
+            .is_err());
+
+        // Brackets in strings and comments are skipped
+        assert!(limits
+            .check_brackets(r#"{"a": "[[[[\"[[[[", b: '{{{{', /* (((( */ // [[[["#)
+            .is_ok());
+    }
+
+    #[test]
+    fn yaml() {
+        let limits = limits();
+
+        assert!(limits.check_yaml("a:\n  b:\n    c: [1]\n").is_ok());
+        assert!(limits.check_yaml("a:\n  b:\n    c: [[1]]\n").is_err());
+
+        // Aliases count as deep as what they refer to
+        let e = limits
+            .check_yaml("x: &x {c: [1]}\na:\n  b:\n    d: *x\n")
+            .unwrap_err();
+        assert_eq!(e.message, "Manifest nested too deeply");
+
+        let bomb = "a: &a [1, 1, 1]\nb: &b [*a, *a, *a]\nc: [*b, *b, *b]\n";
+        let e = limits.check_yaml(bomb).unwrap_err();
+        assert_eq!(e.status, StatusCode::BAD_REQUEST);
+        assert_eq!(e.message, "Too many YAML aliases");
+
+        // YAML the checks can't follow is rejected rather than let through
+        for yaml in ["a: [1, 2", "a: *unknown\n"] {
+            let e = limits.check_yaml(yaml).unwrap_err();
+            assert_eq!(e.status, StatusCode::BAD_REQUEST);
+            assert_eq!(e.message, "Invalid manifest");
+        }
+    }
+
+    #[test]
+    fn toml() {
+        let limits = limits();
+
+        assert!(limits.check_toml(b"[a.b]\nc = [1]\n").is_ok());
+        assert!(limits.check_toml(b"[a.b]\nc = [[1]]\n").is_err());
+
+        for toml in [&b"[a\n"[..], b"a = \xff"] {
+            let e = limits.check_toml(toml).unwrap_err();
+            assert_eq!(e.status, StatusCode::BAD_REQUEST);
+            assert_eq!(e.message, "Invalid manifest");
+        }
+    }
+}
@@ -0,0 +1,491 @@
+use super::diagnostics::{self, Diagnostic, Spans};
+use super::input::{decode_body, input_format};
+use super::{manifest_toml, ErrorReport, ManifestError, ManifestLimits, ManifestPolicy};
+use axum::{
+    body::Body,
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::TypedHeader;
+use headers::ContentType;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use toml_edit::{DocumentMut, Item, TableLike, Value};
+
+/// Names of the lints, as reported with each finding
+pub const DEPRECATED_PROJECT: &str = "deprecated-project";
+pub const MISSING_DESCRIPTION: &str = "missing-description";
+pub const MISSING_LICENSE: &str = "missing-license";
+pub const INVALID_VERSION: &str = "invalid-version";
+pub const INVALID_REQUIREMENT: &str = "invalid-requirement";
+pub const UNSORTED_DEPENDENCIES: &str = "unsorted-dependencies";
+pub const DUPLICATE_DEPENDENCY: &str = "duplicate-dependency";
+
+/// The tables dependencies are declared in, at the top level and per target
+const DEPENDENCY_KINDS: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// An issue found in the manifest
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    #[serde(flatten)]
+    pub diagnostic: Diagnostic,
+
+    /// What the corrected manifest changes to address it, if anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LintReport {
+    pub findings: Vec<Finding>,
+
+    /// The manifest with every fixable finding fixed, keeping its comments and formatting
+    pub manifest: String,
+}
+
+/// Collects findings, pointing them at the original manifest
+struct Linter<'a> {
+    /// The original manifest, unless it was converted to TOML, parsed once for the spans
+    text: Option<(&'a str, Spans<'a>)>,
+    findings: Vec<Finding>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, rule: &'static str, path: &str, reason: String, fix: Option<String>) {
+        let mut diagnostic = Diagnostic::new(path, reason).rule(rule);
+        if let Some((text, spans)) = &self.text {
+            diagnostic = diagnostic.at(text, spans.of(path));
+        }
+
+        self.findings.push(Finding { diagnostic, fix });
+    }
+
+    /// Rename a `[project]` table to `[package]`, returning the name the package was
+    /// originally declared under
+    fn project(&mut self, doc: &mut DocumentMut) -> &'static str {
+        if !doc.contains_key("project") {
+            return "package";
+        }
+
+        if doc.contains_key("package") {
+            self.report(
+                DEPRECATED_PROJECT,
+                "project",
+                "`[project]` is deprecated and ignored alongside `[package]`".to_string(),
+                None,
+            );
+
+            return "package";
+        }
+
+        // The table keeps its position, so it's renamed in place
+        if let Some(project) = doc.remove("project") {
+            doc.insert("package", project);
+        }
+
+        self.report(
+            DEPRECATED_PROJECT,
+            "project",
+            "`[project]` is deprecated in favor of `[package]`".to_string(),
+            Some("renamed `[project]` to `[package]`".to_string()),
+        );
+
+        "project"
+    }
+
+    /// Check the package's fields, where `name` is what it was declared as
+    fn package(&mut self, package: &mut dyn TableLike, name: &str) {
+        if !package.contains_key("description") {
+            self.report(
+                MISSING_DESCRIPTION,
+                name,
+                "missing `description`".to_string(),
+                None,
+            );
+        }
+
+        if !package.contains_key("license") && !package.contains_key("license-file") {
+            self.report(
+                MISSING_LICENSE,
+                name,
+                "missing `license` or `license-file`".to_string(),
+                None,
+            );
+        }
+
+        self.version(package, name);
+    }
+
+    /// Check the `version` of a package or `workspace.package`, fixing versions that are
+    /// only missing components
+    fn version(&mut self, table: &mut dyn TableLike, name: &str) {
+        let Some(value) = table.get_mut("version").and_then(Item::as_value_mut) else {
+            return;
+        };
+
+        let Some(version) = value.as_str().map(str::to_string) else {
+            return;
+        };
+
+        let Err(e) = Version::parse(&version) else {
+            return;
+        };
+
+        let path = format!("{name}.version");
+        let reason = format!("{version} is not a SemVer version: {e}");
+
+        match complete_version(&version) {
+            Some(fixed) => {
+                let fix = format!("changed version to {fixed}");
+                replace_str(value, &fixed);
+                self.report(INVALID_VERSION, &path, reason, Some(fix));
+            }
+            None => self.report(INVALID_VERSION, &path, reason, None),
+        }
+    }
+
+    /// Check a table of dependencies, sorting it if needed
+    fn dependencies(&mut self, table: &mut dyn TableLike, path: &str) {
+        for (name, item) in table.iter() {
+            let requirement = item.as_str().or_else(|| {
+                item.as_table_like()
+                    .and_then(|dependency| dependency.get("version"))
+                    .and_then(Item::as_str)
+            });
+
+            if let Some(requirement) = requirement {
+                if let Err(e) = VersionReq::parse(requirement) {
+                    self.report(
+                        INVALID_REQUIREMENT,
+                        &format!("{path}.{name}"),
+                        format!("{requirement} is not a SemVer requirement: {e}"),
+                        None,
+                    );
+                }
+            }
+        }
+
+        // Dependencies declared as their own `[dependencies.name]` tables are ordered by
+        // where they appear, not by their place in the table
+        let names = table
+            .iter()
+            .filter(|(_, item)| !item.is_table())
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+
+        if !names.is_sorted() {
+            table.sort_values();
+            self.report(
+                UNSORTED_DEPENDENCIES,
+                path,
+                "dependencies aren't sorted by name".to_string(),
+                Some("sorted dependencies by name".to_string()),
+            );
+        }
+    }
+
+    /// Report dev-dependencies identical to normal dependencies, which they're already
+    /// available as
+    fn duplicates(&mut self, table: &mut dyn TableLike, path: &str) {
+        let Some(dev_dependencies) = table.get("dev-dependencies").and_then(Item::as_table_like)
+        else {
+            return;
+        };
+
+        let Some(dependencies) = table.get("dependencies").and_then(Item::as_table_like) else {
+            return;
+        };
+
+        let duplicates = dev_dependencies
+            .iter()
+            .filter(|(name, dev)| {
+                dependencies
+                    .get(name)
+                    .is_some_and(|dependency| same_dependency(dependency, dev))
+            })
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<_>>();
+
+        if duplicates.is_empty() {
+            return;
+        }
+
+        let Some(dev_dependencies) = table
+            .get_mut("dev-dependencies")
+            .and_then(Item::as_table_like_mut)
+        else {
+            return;
+        };
+
+        for name in duplicates {
+            dev_dependencies.remove(&name);
+
+            let prefix = if path.is_empty() {
+                String::new()
+            } else {
+                format!("{path}.")
+            };
+
+            self.report(
+                DUPLICATE_DEPENDENCY,
+                &format!("{prefix}dev-dependencies.{name}"),
+                format!("{name} is already a dependency, so it's available to tests too"),
+                Some(format!("removed {name} from dev-dependencies")),
+            );
+        }
+    }
+
+    /// Check every dependency table of the manifest
+    fn all_dependencies(&mut self, doc: &mut DocumentMut) {
+        self.duplicates(doc.as_table_mut(), "");
+
+        for kind in DEPENDENCY_KINDS {
+            if let Some(table) = doc.get_mut(kind).and_then(Item::as_table_like_mut) {
+                self.dependencies(table, kind);
+            }
+        }
+
+        if let Some(targets) = doc.get_mut("target").and_then(Item::as_table_like_mut) {
+            for (target, item) in targets.iter_mut() {
+                let Some(target_table) = item.as_table_like_mut() else {
+                    continue;
+                };
+
+                let path = format!("target.{target}");
+                self.duplicates(target_table, &path);
+
+                for kind in DEPENDENCY_KINDS {
+                    if let Some(table) =
+                        target_table.get_mut(kind).and_then(Item::as_table_like_mut)
+                    {
+                        self.dependencies(table, &format!("{path}.{kind}"));
+                    }
+                }
+            }
+        }
+
+        let workspace_dependencies = doc
+            .get_mut("workspace")
+            .and_then(|workspace| workspace.get_mut("dependencies"))
+            .and_then(Item::as_table_like_mut);
+
+        if let Some(table) = workspace_dependencies {
+            self.dependencies(table, "workspace.dependencies");
+        }
+    }
+}
+
+/// Replace a string value, keeping the whitespace and comments around it
+fn replace_str(value: &mut Value, replacement: &str) {
+    let decor = value.decor().clone();
+    *value = Value::from(replacement);
+    *value.decor_mut() = decor;
+}
+
+/// Complete a version with missing components, like `v1.2`, into a semantic version
+fn complete_version(version: &str) -> Option<String> {
+    let version = version.trim().trim_start_matches(['v', 'V', '=']);
+
+    let parts = version.split('.').collect::<Vec<_>>();
+    if parts.is_empty()
+        || parts.len() > 3
+        || parts
+            .iter()
+            .any(|part| part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Version::parse(version)
+            .ok()
+            .map(|version| version.to_string());
+    }
+
+    let mut numbers = parts
+        .iter()
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    numbers.resize(3, 0);
+
+    Some(Version::new(numbers[0], numbers[1], numbers[2]).to_string())
+}
+
+/// A dependency as data, ignoring how it's written
+fn dependency_value(item: &Item) -> Option<toml::Value> {
+    let value = item.clone().into_value().ok()?.to_string();
+    let value = toml::Value::deserialize(toml::de::ValueDeserializer::new(value.trim())).ok()?;
+
+    Some(match value {
+        toml::Value::String(version) => toml::Value::Table(toml::Table::from_iter([(
+            "version".to_string(),
+            version.into(),
+        )])),
+        value => value,
+    })
+}
+
+/// Whether two dependency declarations are the same, however they're written
+fn same_dependency(a: &Item, b: &Item) -> bool {
+    match (dependency_value(a), dependency_value(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Lint the given manifest, fixing what can be fixed in place. `text` is the original
+/// manifest, unless it was converted.
+pub fn lint(doc: &mut DocumentMut, text: Option<&str>) -> Vec<Finding> {
+    let mut linter = Linter {
+        text: text.map(|text| (text, Spans::new(text))),
+        findings: Vec::new(),
+    };
+
+    let name = linter.project(doc);
+
+    if let Some(package) = doc.get_mut("package").and_then(Item::as_table_like_mut) {
+        linter.package(package, name);
+    }
+
+    let workspace_package = doc
+        .get_mut("workspace")
+        .and_then(|workspace| workspace.get_mut("package"))
+        .and_then(Item::as_table_like_mut);
+
+    if let Some(workspace_package) = workspace_package {
+        linter.version(workspace_package, "workspace.package");
+    }
+
+    linter.all_dependencies(doc);
+
+    linter.findings
+}
+
+/// Decode the request body and lint the manifest it holds
+async fn read_lint(
+    headers: &HeaderMap,
+    content_type: &str,
+    body: Body,
+    limits: &ManifestLimits,
+) -> Result<LintReport, ManifestError> {
+    let body = limits.read_body(body).await?;
+    let body = decode_body(headers, body, limits)?;
+    let (toml, converted) = manifest_toml(input_format(content_type)?, &body, limits)?;
+
+    let text = std::str::from_utf8(&toml).map_err(|e| {
+        ManifestError::invalid(vec![Diagnostic::new("", format!("invalid UTF-8: {e}"))])
+    })?;
+
+    let mut doc = text.parse::<DocumentMut>().map_err(|_| {
+        let mut diagnostics = diagnostics::manifest_error(text);
+        if converted {
+            for diag in &mut diagnostics {
+                diag.span = None;
+            }
+        }
+
+        ManifestError::invalid(diagnostics)
+    })?;
+
+    let findings = lint(&mut doc, (!converted).then_some(text));
+
+    Ok(LintReport {
+        findings,
+        manifest: doc.to_string(),
+    })
+}
+
+pub async fn lint_manifest(
+    State(policy): State<Arc<ManifestPolicy>>,
+    headers: HeaderMap,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    body: Body,
+) -> Response {
+    let result = read_lint(&headers, &content_type.to_string(), body, &policy.limits).await;
+
+    match result {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            let report = ErrorReport {
+                error: &e.message,
+                diagnostics: &e.diagnostics,
+            };
+
+            (e.status, Json(report)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod lint_tests {
+    use super::{complete_version, lint};
+    use toml_edit::DocumentMut;
+
+    #[test]
+    fn completes_versions() {
+        assert_eq!(complete_version("1"), Some("1.0.0".to_string()));
+        assert_eq!(complete_version("v1.2"), Some("1.2.0".to_string()));
+        assert_eq!(complete_version("=1.2.3"), Some("1.2.3".to_string()));
+        assert_eq!(complete_version("01.2.3"), Some("1.2.3".to_string()));
+        assert_eq!(complete_version("latest"), None);
+        assert_eq!(complete_version("1.2.3.4"), None);
+    }
+
+    #[test]
+    fn lints_and_fixes() {
+        let text = r#"# Gift orders
+[project]
+name = "gifts"
+version = "1.2" # bumped for Christmas
+
+[dependencies]
+# Serialization
+serde = { version = "1", features = ["derive"] }
+anyhow = "1"
+rand = "latest"
+
+[dev-dependencies]
+anyhow = { version = "1" }
+serde = { version = "1" }
+"#;
+
+        let mut doc = text.parse::<DocumentMut>().unwrap();
+        let findings = lint(&mut doc, Some(text))
+            .iter()
+            .map(|finding| {
+                let diagnostic = &finding.diagnostic;
+                format!("{}: {diagnostic}", diagnostic.rule.unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            findings,
+            vec![
+                "deprecated-project: 2:1: project: `[project]` is deprecated in favor of `[package]`",
+                "missing-description: 2:1: project: missing `description`",
+                "missing-license: 2:1: project: missing `license` or `license-file`",
+                "invalid-version: 4:11: project.version: 1.2 is not a SemVer version: unexpected end of input while parsing minor version number",
+                "duplicate-dependency: 13:10: dev-dependencies.anyhow: anyhow is already a dependency, so it's available to tests too",
+                "invalid-requirement: 10:8: dependencies.rand: latest is not a SemVer requirement: unexpected character 'l' while parsing major version number",
+                "unsorted-dependencies: 6:1: dependencies: dependencies aren't sorted by name",
+            ]
+        );
+
+        assert_eq!(
+            doc.to_string(),
+            r#"# Gift orders
+[package]
+name = "gifts"
+version = "1.2.0" # bumped for Christmas
+
+[dependencies]
+anyhow = "1"
+rand = "latest"
+# Serialization
+serde = { version = "1", features = ["derive"] }
+
+[dev-dependencies]
+serde = { version = "1" }
+"#
+        );
+    }
+}
@@ -0,0 +1,456 @@
+use super::diagnostics::{Diagnostic, Spans};
+use super::invoice::CurrencyConversion;
+use super::limits::ManifestLimits;
+use super::{Order, Orders};
+use cargo_manifest::{MaybeInherited, Package};
+use serde::Deserialize;
+use shuttle_runtime::SecretStore;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The keyword every gift order manifest must carry by default
+const MAGIC_KEYWORD: &str = "Christmas 2024";
+
+/// Names of the rules, as reported with each violation
+pub const REQUIRED_KEYWORD: &str = "required-keyword";
+pub const ALLOWED_ITEM: &str = "allowed-item";
+pub const DENIED_ITEM: &str = "denied-item";
+pub const MAX_QUANTITY: &str = "max-quantity";
+pub const REQUIRED_FIELD: &str = "required-field";
+pub const ALLOWED_LICENSE: &str = "allowed-license";
+
+/// A `package` field that can be required
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageField {
+    Authors,
+    Version,
+    RustVersion,
+}
+
+impl PackageField {
+    fn key(self) -> &'static str {
+        match self {
+            PackageField::Authors => "authors",
+            PackageField::Version => "version",
+            PackageField::RustVersion => "rust-version",
+        }
+    }
+
+    /// Whether the package sets this field. Inherited fields only count once they've been
+    /// resolved from the workspace.
+    fn present(self, package: &Package<Orders>) -> bool {
+        match self {
+            PackageField::Authors => matches!(package.authors, Some(MaybeInherited::Local(_))),
+            PackageField::Version => matches!(package.version, Some(MaybeInherited::Local(_))),
+            PackageField::RustVersion => {
+                matches!(package.rust_version, Some(MaybeInherited::Local(_)))
+            }
+        }
+    }
+}
+
+/// The rules gift order manifests are checked against, configured at startup. Item names
+/// are compared ignoring case.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ManifestPolicy {
+    /// Keywords every manifest must list
+    pub required_keywords: Vec<String>,
+
+    /// The only items that may be ordered, unless empty
+    pub allowed_items: Vec<String>,
+
+    /// Items that may never be ordered
+    pub denied_items: Vec<String>,
+
+    /// The most of an item that may be ordered, summed over all its orders
+    pub max_quantity: HashMap<String, u64>,
+
+    /// Fields every manifest's `package` must set
+    pub required_fields: Vec<PackageField>,
+
+    /// The only licenses manifests may use, unless empty. SPDX expressions are allowed if
+    /// any of their alternatives only uses allowed licenses.
+    pub allowed_licenses: Vec<String>,
+
+    /// How to total orders priced in several currencies, which are rejected otherwise
+    pub conversion: Option<CurrencyConversion>,
+
+    /// Limits on the manifests themselves, as a `[limits]` table
+    pub limits: ManifestLimits,
+}
+
+impl Default for ManifestPolicy {
+    fn default() -> Self {
+        Self {
+            required_keywords: vec![MAGIC_KEYWORD.to_string()],
+            allowed_items: Vec::new(),
+            denied_items: Vec::new(),
+            max_quantity: HashMap::new(),
+            required_fields: Vec::new(),
+            allowed_licenses: Vec::new(),
+            conversion: None,
+            limits: ManifestLimits::default(),
+        }
+    }
+}
+
+/// Whether `names` contains `name`, ignoring case
+fn contains(names: &[String], name: &str) -> bool {
+    let name = name.to_lowercase();
+    names.iter().any(|other| other.to_lowercase() == name)
+}
+
+impl ManifestPolicy {
+    /// Load the policy from the TOML file at `MANIFEST_POLICY_PATH`, if given
+    pub fn from_secrets(secrets: &SecretStore) -> Result<Self, String> {
+        match secrets.get("MANIFEST_POLICY_PATH") {
+            Some(path) => Self::load(Path::new(&path)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let policy = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+        toml::from_str(&policy).map_err(|e| format!("Invalid policy {}: {e}", path.display()))
+    }
+
+    /// Check the given package against every rule, returning each violation
+    pub fn check(&self, text: &str, package: &Package<Orders>) -> Vec<Diagnostic> {
+        let mut violations = Vec::new();
+        let spans = Spans::new(text);
+
+        let keywords = match &package.keywords {
+            Some(MaybeInherited::Local(keywords)) => keywords.as_slice(),
+            _ => &[],
+        };
+
+        let keywords_span = spans.of("package.keywords");
+        for keyword in &self.required_keywords {
+            if !keywords.contains(keyword) {
+                violations.push(
+                    Diagnostic::new(
+                        "package.keywords",
+                        format!("missing required keyword \"{keyword}\""),
+                    )
+                    .rule(REQUIRED_KEYWORD)
+                    .at(text, keywords_span.clone()),
+                );
+            }
+        }
+
+        for field in &self.required_fields {
+            if !field.present(package) {
+                violations.push(
+                    Diagnostic::new(
+                        format!("package.{}", field.key()),
+                        format!("missing required field `{}`", field.key()),
+                    )
+                    .rule(REQUIRED_FIELD),
+                );
+            }
+        }
+
+        if !self.allowed_licenses.is_empty() {
+            violations.extend(self.check_license(text, &spans, package));
+        }
+
+        let orders = package
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.orders.as_deref())
+            .unwrap_or_default();
+
+        violations.extend(self.check_orders(text, &spans, orders));
+
+        violations
+    }
+
+    fn check_license(
+        &self,
+        text: &str,
+        spans: &Spans,
+        package: &Package<Orders>,
+    ) -> Option<Diagnostic> {
+        let span = spans.of("package.license");
+
+        let reason = match &package.license {
+            Some(MaybeInherited::Local(license)) => {
+                match license_allowed(license, |id| contains(&self.allowed_licenses, id)) {
+                    Some(true) => return None,
+                    Some(false) => format!("license {license} is not allowed"),
+                    None => format!("invalid license expression {license}"),
+                }
+            }
+            // Inherited licenses are resolved from the workspace before the policy is checked
+            Some(MaybeInherited::Inherited { .. }) => {
+                "`license` is inherited from an unknown workspace".to_string()
+            }
+            None => "missing `license`".to_string(),
+        };
+
+        Some(
+            Diagnostic::new("package.license", reason)
+                .rule(ALLOWED_LICENSE)
+                .at(text, span),
+        )
+    }
+
+    fn check_orders(&self, text: &str, spans: &Spans, orders: &[Order]) -> Vec<Diagnostic> {
+        let mut violations = Vec::new();
+
+        // Totals of each item, by lowercase name, along with its name as first spelled
+        let mut totals: Vec<(String, &str, u64)> = Vec::new();
+
+        for (index, order) in orders.iter().enumerate() {
+            let Some(item) = &order.item else {
+                continue;
+            };
+
+            let path = format!("package.metadata.orders[{index}].item");
+            let span = || spans.of(&path);
+
+            if !self.allowed_items.is_empty() && !contains(&self.allowed_items, item) {
+                violations.push(
+                    Diagnostic::new(&path, format!("{item} is not an allowed item"))
+                        .rule(ALLOWED_ITEM)
+                        .at(text, span()),
+                );
+            }
+
+            if contains(&self.denied_items, item) {
+                violations.push(
+                    Diagnostic::new(&path, format!("{item} may not be ordered"))
+                        .rule(DENIED_ITEM)
+                        .at(text, span()),
+                );
+            }
+
+            let quantity = u64::from(order.quantity.unwrap_or(0));
+            let key = item.to_lowercase();
+            match totals.iter_mut().find(|(other, _, _)| *other == key) {
+                Some((_, _, total)) => *total = total.saturating_add(quantity),
+                None => totals.push((key, item, quantity)),
+            }
+        }
+
+        for (key, item, total) in totals {
+            let max = self
+                .max_quantity
+                .iter()
+                .find(|(name, _)| name.to_lowercase() == key)
+                .map(|(_, max)| *max);
+
+            if let Some(max) = max.filter(|max| total > *max) {
+                violations.push(
+                    Diagnostic::new(
+                        "package.metadata.orders",
+                        format!("{total} {item} ordered, more than the maximum of {max}"),
+                    )
+                    .rule(MAX_QUANTITY),
+                );
+            }
+        }
+
+        violations
+    }
+}
+
+/// Evaluate an SPDX license expression, given whether each license is allowed. `None` if
+/// the expression is malformed.
+fn license_allowed(expression: &str, allowed: impl Fn(&str) -> bool) -> Option<bool> {
+    let spaced = expression.replace('(', " ( ").replace(')', " ) ");
+
+    let mut parser = LicenseParser {
+        tokens: spaced.split_whitespace().collect(),
+        pos: 0,
+        allowed: &allowed,
+    };
+
+    let result = parser.or()?;
+
+    (parser.pos == parser.tokens.len()).then_some(result)
+}
+
+/// Recursive descent over an SPDX expression, where `AND` binds tighter than `OR`
+struct LicenseParser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+    allowed: &'a dyn Fn(&str) -> bool,
+}
+
+impl<'a> LicenseParser<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        let token = *self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(token)
+    }
+
+    /// Consume the next token if it's `expected`
+    fn eat(&mut self, expected: &str) -> bool {
+        let matches = self.tokens.get(self.pos) == Some(&expected);
+        if matches {
+            self.pos += 1;
+        }
+
+        matches
+    }
+
+    fn or(&mut self) -> Option<bool> {
+        let mut result = self.and()?;
+
+        while self.eat("OR") {
+            // Both sides are parsed so malformed expressions are always caught
+            let rhs = self.and()?;
+            result = result || rhs;
+        }
+
+        Some(result)
+    }
+
+    fn and(&mut self) -> Option<bool> {
+        let mut result = self.license()?;
+
+        while self.eat("AND") {
+            let rhs = self.license()?;
+            result = result && rhs;
+        }
+
+        Some(result)
+    }
+
+    fn license(&mut self) -> Option<bool> {
+        match self.next()? {
+            "(" => {
+                let result = self.or()?;
+                self.eat(")").then_some(result)
+            }
+            ")" | "AND" | "OR" | "WITH" => None,
+            id => {
+                let allowed = (self.allowed)(id.trim_end_matches('+'));
+
+                // Exceptions don't change which license applies
+                if self.eat("WITH") {
+                    self.next()?;
+                }
+
+                Some(allowed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod policy_tests {
+    use super::{license_allowed, ManifestPolicy};
+    use crate::day3::Orders;
+    use cargo_manifest::Manifest;
+
+    #[test]
+    fn license_expressions() {
+        let allowed = |expression| license_allowed(expression, |id| id == "MIT");
+
+        assert_eq!(allowed("MIT"), Some(true));
+        assert_eq!(allowed("GPL-3.0"), Some(false));
+        assert_eq!(allowed("MIT OR Apache-2.0"), Some(true));
+        assert_eq!(allowed("MIT AND Apache-2.0"), Some(false));
+        assert_eq!(allowed("Apache-2.0 AND (MIT OR GPL-3.0)"), Some(false));
+        assert_eq!(allowed("(MIT AND MIT) OR GPL-3.0"), Some(true));
+        assert_eq!(allowed("MIT WITH LLVM-exception"), Some(true));
+        assert_eq!(allowed("MIT OR"), None);
+        assert_eq!(allowed("(MIT"), None);
+        assert_eq!(allowed("MIT)"), None);
+    }
+
+    #[test]
+    fn violations() {
+        let policy: ManifestPolicy = toml::from_str(
+            r#"
+required-keywords = ["Christmas 2024"]
+allowed-items = ["Toy car", "Lego brick", "Coal"]
+denied-items = ["coal"]
+max-quantity = { "lego brick" = 100 }
+required-fields = ["authors", "rust-version"]
+allowed-licenses = ["MIT", "Apache-2.0"]
+"#,
+        )
+        .unwrap();
+
+        let text = r#"
+[package]
+name = "orders"
+authors = ["Santa"]
+keywords = ["Christmas 2024"]
+license = "GPL-3.0"
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 60
+
+[[package.metadata.orders]]
+item = "Coal"
+quantity = 1
+
+[[package.metadata.orders]]
+item = "lego Brick"
+quantity = 60
+
+[[package.metadata.orders]]
+item = "Yacht"
+quantity = 1
+"#;
+
+        let manifest = Manifest::<Orders>::from_slice_with_metadata(text.as_bytes()).unwrap();
+        let violations = policy
+            .check(text, manifest.package.as_ref().unwrap())
+            .iter()
+            .map(|violation| format!("{}: {violation}", violation.rule.unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            violations,
+            vec![
+                "required-field: package.rust-version: missing required field `rust-version`",
+                "allowed-license: 6:11: package.license: license GPL-3.0 is not allowed",
+                "denied-item: 13:8: package.metadata.orders[1].item: Coal may not be ordered",
+                "allowed-item: 21:8: package.metadata.orders[3].item: Yacht is not an allowed item",
+                "max-quantity: package.metadata.orders: 120 Lego brick ordered, more than the maximum of 100",
+            ]
+        );
+    }
+
+    #[test]
+    fn unresolved_inherited_fields() {
+        let policy: ManifestPolicy = toml::from_str(
+            r#"
+required-fields = ["authors", "rust-version"]
+allowed-licenses = ["MIT"]
+"#,
+        )
+        .unwrap();
+
+        let text = r#"
+[package]
+name = "orders"
+keywords = ["Christmas 2024"]
+authors.workspace = true
+rust-version.workspace = true
+license.workspace = true
+"#;
+
+        let manifest = Manifest::<Orders>::from_slice_with_metadata(text.as_bytes()).unwrap();
+        let rules = policy
+            .check(text, manifest.package.as_ref().unwrap())
+            .iter()
+            .map(|violation| violation.rule.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            rules,
+            vec!["required-field", "required-field", "allowed-license"]
+        );
+    }
+}
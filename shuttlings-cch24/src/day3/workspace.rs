@@ -0,0 +1,259 @@
+use super::diagnostics::{Diagnostic, Spans};
+use super::{
+    package_orders, parse_manifest, ManifestError, ManifestOrders, ManifestPolicy, Orders,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
+};
+use cargo_manifest::{Manifest, MaybeInherited, Package, WorkspacePackage};
+
+/// A manifest uploaded as part of a workspace
+struct Upload {
+    /// The file name it was uploaded with
+    name: String,
+    bytes: Bytes,
+}
+
+/// Resolve the fields `package` inherits from the workspace's `workspace.package`
+pub fn inherit(
+    package: &mut Package<Orders>,
+    workspace: Option<&WorkspacePackage>,
+) -> Result<(), Diagnostic> {
+    macro_rules! inherit {
+        ($($field:ident => $key:literal),* $(,)?) => {$(
+            if let Some(MaybeInherited::Inherited { .. }) = package.$field {
+                let value = workspace
+                    .and_then(|workspace| workspace.$field.clone())
+                    .ok_or_else(|| {
+                        Diagnostic::new(
+                            concat!("package.", $key),
+                            concat!("inherited, but `workspace.package.", $key, "` isn't set"),
+                        )
+                    })?;
+
+                package.$field = Some(MaybeInherited::Local(value));
+            }
+        )*};
+    }
+
+    inherit!(
+        version => "version",
+        authors => "authors",
+        edition => "edition",
+        description => "description",
+        homepage => "homepage",
+        documentation => "documentation",
+        readme => "readme",
+        keywords => "keywords",
+        categories => "categories",
+        license => "license",
+        license_file => "license-file",
+        repository => "repository",
+        rust_version => "rust-version",
+        exclude => "exclude",
+        include => "include",
+    );
+
+    Ok(())
+}
+
+/// Read the `root` and `member` manifests of a multipart upload
+async fn read_uploads(
+    content_type: &str,
+    body: Bytes,
+) -> Result<(Upload, Vec<Upload>), ManifestError> {
+    let invalid_form = |_| ManifestError::new(StatusCode::BAD_REQUEST, "Invalid form");
+
+    let request = Request::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .map_err(|_| ManifestError::new(StatusCode::BAD_REQUEST, "Invalid form"))?;
+
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|_| ManifestError::new(StatusCode::BAD_REQUEST, "Invalid form"))?;
+
+    let mut root = None;
+    let mut members = Vec::new();
+
+    while let Some(field) = multipart.next_field().await.map_err(invalid_form)? {
+        let field_name = field.name().map(str::to_string);
+        let file_name = field.file_name().map(str::to_string);
+        let bytes = field.bytes().await.map_err(invalid_form)?;
+
+        match field_name.as_deref() {
+            Some("root") if root.is_some() => {
+                return Err(ManifestError::new(
+                    StatusCode::BAD_REQUEST,
+                    "Only one workspace root can be uploaded",
+                ));
+            }
+            Some("root") => {
+                root = Some(Upload {
+                    name: file_name.unwrap_or_else(|| "Cargo.toml".to_string()),
+                    bytes,
+                });
+            }
+            Some("member") => members.push(Upload {
+                name: file_name.unwrap_or_else(|| format!("member{}", members.len())),
+                bytes,
+            }),
+            name => {
+                return Err(ManifestError::new(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Expected root or member, found {name:?}"),
+                ));
+            }
+        }
+    }
+
+    let root = root.ok_or_else(|| {
+        ManifestError::new(StatusCode::BAD_REQUEST, "Missing workspace root manifest")
+    })?;
+
+    Ok((root, members))
+}
+
+/// Parse a multipart upload of a workspace root manifest (`root`) and the manifests of its
+/// members (`member`), collecting the orders of every package. Members inherit from the
+/// root's `workspace.package`, and a root with a `[package]` is a member itself.
+pub async fn parse_workspace(
+    content_type: &str,
+    body: Bytes,
+    policy: &ManifestPolicy,
+) -> Result<ManifestOrders, ManifestError> {
+    let (root, members) = read_uploads(content_type, body).await?;
+
+    for upload in std::iter::once(&root).chain(&members) {
+        policy
+            .limits
+            .check_toml(&upload.bytes)
+            .map_err(|e| e.in_file(&upload.name))?;
+    }
+
+    let (root_text, root_manifest) =
+        parse_manifest(&root.bytes).map_err(|e| e.in_file(&root.name))?;
+
+    let Some(workspace) = root_manifest.workspace else {
+        let diag = Diagnostic::new("workspace", "missing `workspace` table").in_file(&root.name);
+        return Err(ManifestError::invalid(vec![diag]));
+    };
+
+    let mut packages = Vec::new();
+    let mut errors = Vec::new();
+
+    if let Some(package) = root_manifest.package {
+        packages.push((&root.name, root_text, package));
+    }
+
+    for member in &members {
+        match parse_manifest(&member.bytes) {
+            Ok((
+                text,
+                Manifest {
+                    package: Some(package),
+                    ..
+                },
+            )) => packages.push((&member.name, text, package)),
+            Ok(_) => {
+                let diag = Diagnostic::new("package", "missing `package` table");
+                errors.push(ManifestError::invalid(vec![diag.in_file(&member.name)]));
+            }
+            Err(e) => errors.push(e.in_file(&member.name)),
+        }
+    }
+
+    let mut orders = ManifestOrders {
+        orders: Vec::new(),
+        invoice: None,
+        diagnostics: Vec::new(),
+    };
+
+    for (name, text, mut package) in packages {
+        if let Err(diag) = inherit(&mut package, workspace.package.as_ref()) {
+            let span = Spans::new(text).of(&diag.path);
+            let diag = diag.at(text, span).in_file(name);
+            errors.push(ManifestError::invalid(vec![diag]));
+            continue;
+        }
+
+        match package_orders(text, &package, policy) {
+            Ok(member) => {
+                orders.orders.extend(member.orders);
+                orders.diagnostics.extend(
+                    member
+                        .diagnostics
+                        .into_iter()
+                        .map(|diag| diag.in_file(name)),
+                );
+            }
+            // Members without orders are fine, as long as some member has them
+            Err(e) if e.status == StatusCode::NO_CONTENT => {}
+            Err(e) => errors.push(e.in_file(name)),
+        }
+    }
+
+    if let Some(first) = errors.first() {
+        let message = if errors.iter().all(|e| e.message == first.message) {
+            first.message.clone()
+        } else {
+            "Invalid workspace".to_string()
+        };
+
+        return Err(ManifestError {
+            status: StatusCode::BAD_REQUEST,
+            message,
+            diagnostics: errors.into_iter().flat_map(|e| e.diagnostics).collect(),
+        });
+    }
+
+    if orders.orders.is_empty() {
+        return Err(ManifestError::no_content());
+    }
+
+    Ok(orders)
+}
+
+#[cfg(test)]
+mod workspace_tests {
+    use super::inherit;
+    use crate::day3::Orders;
+    use cargo_manifest::{Manifest, MaybeInherited};
+
+    #[test]
+    fn inherits_from_workspace() {
+        let text = r#"
+[workspace]
+members = ["gifts"]
+
+[workspace.package]
+version = "1.2.3"
+keywords = ["Christmas 2024"]
+
+[package]
+name = "root"
+version.workspace = true
+keywords.workspace = true
+license.workspace = true
+"#;
+
+        let manifest = Manifest::<Orders>::from_slice_with_metadata(text.as_bytes()).unwrap();
+        let workspace = manifest.workspace.unwrap().package;
+        let mut package = manifest.package.unwrap();
+
+        let diag = inherit(&mut package, workspace.as_ref()).unwrap_err();
+        assert_eq!(
+            diag.to_string(),
+            "package.license: inherited, but `workspace.package.license` isn't set"
+        );
+
+        // Fields before the missing one were still inherited
+        assert_eq!(package.version, Some(MaybeInherited::Local("1.2.3".into())));
+        assert_eq!(
+            package.keywords,
+            Some(MaybeInherited::Local(vec!["Christmas 2024".into()]))
+        );
+    }
+}
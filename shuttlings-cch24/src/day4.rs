@@ -12,7 +12,7 @@ pub const PINTS_IN_LITRES: f32 = 1.759_754;
 #[cfg(test)]
 pub const RATE_LIMIT_INTERVAL: Duration = Duration::from_millis(10);
 #[cfg(not(test))]
-pub const RATE_LIMIT_INTERVAL: Duration = Duration::from_millis(1000);
+pub const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct Conversion {
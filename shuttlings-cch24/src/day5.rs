@@ -112,7 +112,7 @@ impl Board {
 
     /// Check if the board is finished
     fn check_finished(&mut self) {
-        self.finished = !self.grid.iter().any(|x| *x == Piece::Empty);
+        self.finished = !self.grid.contains(&Piece::Empty);
     }
 
     #[builder]
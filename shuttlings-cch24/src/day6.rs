@@ -1,82 +1,290 @@
+use crate::admin::Admin;
 use axum::{
     body::Bytes,
     extract::State,
     http::{header::SET_COOKIE, HeaderMap, StatusCode},
+    Json,
 };
 use axum_extra::TypedHeader;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use headers::ContentType;
-use jsonwebtoken::{decode_header, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{
+    decode_header, errors::ErrorKind, get_current_timestamp, jwk::JwkSet, Algorithm, Validation,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+mod config;
+pub use config::GiftConfig;
+mod inspect;
+mod jwe;
+mod key_store;
+pub use key_store::{spawn_gift_key_reloader, GiftKeyStore, DEFAULT_GIFT_KEYS_RELOAD_INTERVAL};
+mod keys;
+pub use keys::GiftKeys;
+use keys::TokenError;
+mod refresh;
+pub use refresh::RefreshStore;
+use refresh::{IssuedGift, RefreshError};
+mod revocation;
+pub use revocation::RevocationStore;
+mod santa;
+pub use santa::{spawn_santa_reloader, SantaKeys, DEFAULT_RELOAD_INTERVAL};
 
-const COOKIE_NAME: &str = "gift";
+const SECS_PER_MINUTE: u64 = 60;
+const SECS_PER_HOUR: u64 = 60 * SECS_PER_MINUTE;
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    exp: i64,
+    iss: String,
+    aud: String,
+    jti: Uuid,
+    iat: u64,
+    nbf: u64,
+    exp: u64,
     data: serde_json::Value,
 }
 
-impl From<serde_json::Value> for Claims {
-    fn from(val: serde_json::Value) -> Self {
+impl Claims {
+    /// Claims for a new gift wrapping `data`, valid from now for the configured TTL
+    fn new(data: serde_json::Value, config: &GiftConfig) -> Self {
+        let now = get_current_timestamp();
+
         Self {
-            exp: 0xdead_beef,
-            data: val,
+            iss: config.issuer.clone(),
+            aud: config.audience.clone(),
+            jti: Uuid::new_v4(),
+            iat: now,
+            nbf: now,
+            exp: now + config.ttl.as_secs(),
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RotateParams {
+    kid: Option<String>,
+    alg: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RotateResult {
+    kid: String,
+    alg: Algorithm,
+    retired: String,
+}
+
+/// Add a `Set-Cookie` header for the given cookie
+fn append_cookie(headers: &mut HeaderMap, cookie: &str) -> Result<(), (StatusCode, String)> {
+    let value = cookie.parse().map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Invalid cookie: {e:?}"),
+        )
+    })?;
+
+    headers.append(SET_COOKIE, value);
+
+    Ok(())
+}
+
+/// Wrap `data` in a new gift token, along with a refresh token in the given family,
+/// returning the cookies holding them. If the refresh token can't be stored the gift is
+/// still issued, just without one.
+async fn issue_gift(
+    keys: &RwLock<GiftKeys>,
+    config: &GiftConfig,
+    refresh_tokens: &RefreshStore,
+    data: serde_json::Value,
+    family: Uuid,
+) -> Result<HeaderMap, (StatusCode, String)> {
+    let claims = Claims::new(data, config);
+    let token = keys
+        .read()
+        .unwrap()
+        .encode(&claims)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to encode JWT".to_string()))?;
+
+    let cookie = config
+        .cookie
+        .set_cookie(&token, claims.exp.saturating_sub(claims.iat));
+
+    let mut headers = HeaderMap::new();
+    append_cookie(&mut headers, &cookie)?;
+
+    // Tokens are still accepted within the leeway after they expire
+    let gift = IssuedGift {
+        jti: claims.jti,
+        expires_at: claims.exp + config.leeway.as_secs(),
+    };
+    let refresh_expires_at = get_current_timestamp() + config.refresh_ttl.as_secs();
+
+    match refresh_tokens
+        .issue(family, gift, &claims.data, refresh_expires_at)
+        .await
+    {
+        Ok(refresh_token) => {
+            let refresh_cookie = config
+                .cookie
+                .set_refresh_cookie(&refresh_token, config.refresh_ttl.as_secs());
+            append_cookie(&mut headers, &refresh_cookie)?;
         }
+        Err(e) => tracing::warn!("Failed to issue refresh token, issuing the gift without: {e}"),
     }
+
+    Ok(headers)
+}
+
+/// Revoke the gifts issued in a revoked refresh token family
+async fn revoke_gifts(
+    revocations: &RevocationStore,
+    gifts: &[IssuedGift],
+) -> Result<(), (StatusCode, String)> {
+    for gift in gifts {
+        revocations
+            .revoke(gift.jti, gift.expires_at)
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to revoke gift: {e:?}"),
+                )
+            })?;
+    }
+
+    Ok(())
 }
 
 pub async fn wrap(
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(config): State<Arc<GiftConfig>>,
+    State(refresh_tokens): State<RefreshStore>,
     TypedHeader(content_type): TypedHeader<ContentType>,
     body: Bytes,
-) -> Result<HeaderMap, (StatusCode, &'static str)> {
+) -> Result<HeaderMap, (StatusCode, String)> {
     if !matches!(content_type.to_string().as_str(), "application/json") {
-        return Err((StatusCode::BAD_REQUEST, ""));
+        return Err((StatusCode::BAD_REQUEST, String::new()));
     }
 
     let data: serde_json::Value = serde_json::from_slice(&body)
-        .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to encode json"))?;
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to encode json".to_string()))?;
+
+    issue_gift(&keys, &config, &refresh_tokens, data, Uuid::new_v4()).await
+}
+
+/// Exchange the refresh token in the body, or the refresh cookie if the body is empty,
+/// for a new gift and refresh token
+pub async fn refresh(
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(config): State<Arc<GiftConfig>>,
+    State(refresh_tokens): State<RefreshStore>,
+    State(revocations): State<RevocationStore>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    body: Bytes,
+) -> Result<HeaderMap, (StatusCode, String)> {
+    let body = std::str::from_utf8(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid body: {e:?}")))?
+        .trim();
+
+    let name = config.cookie.refresh_name();
+    let token = if body.is_empty() {
+        cookies
+            .as_ref()
+            .and_then(|TypedHeader(cookies)| cookies.get(&name))
+            .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("No {name} cookie found")))?
+    } else {
+        body
+    };
 
-    let token = jsonwebtoken::encode::<Claims>(
-        &Header::default(),
-        &data.into(),
-        &EncodingKey::from_secret("sharedsecret".as_ref()),
+    let redeemed = match refresh_tokens.redeem(token).await {
+        Ok(redeemed) => redeemed,
+        Err(e) => {
+            let code = match &e {
+                RefreshError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                RefreshError::Reused(gifts) => {
+                    // The gifts issued in the family may have been stolen along with it
+                    revoke_gifts(&revocations, gifts).await?;
+                    StatusCode::UNAUTHORIZED
+                }
+                _ => StatusCode::UNAUTHORIZED,
+            };
+
+            return Err((code, e.to_string()));
+        }
+    };
+
+    issue_gift(
+        &keys,
+        &config,
+        &refresh_tokens,
+        redeemed.data,
+        redeemed.family,
     )
-    .map_err(|_| (StatusCode::BAD_REQUEST, "Failed to encode JWT"))?;
+    .await
+}
 
-    let cookie = format!("{COOKIE_NAME}={token}");
-    let mut headers = HeaderMap::new();
-    headers.insert(SET_COOKIE, cookie.parse().unwrap());
+/// Get the gift token from the request cookies
+fn gift_cookie<'a>(
+    cookies: &'a headers::Cookie,
+    config: &GiftConfig,
+) -> Result<&'a str, (StatusCode, String)> {
+    let name = &config.cookie.name;
 
-    Ok(headers)
+    cookies
+        .get(name)
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("No {name} cookie found")))
+}
+
+/// Verify the signature and claims of the given gift token
+fn verify_gift(
+    keys: &RwLock<GiftKeys>,
+    config: &GiftConfig,
+    token: &str,
+) -> Result<Claims, (StatusCode, String)> {
+    let token = keys
+        .read()
+        .unwrap()
+        .decode::<Claims>(token, config.validation())
+        .map_err(|e| {
+            let msg = match &e {
+                TokenError::Jwt(e) if *e.kind() == ErrorKind::ExpiredSignature => {
+                    "Gift has expired".to_string()
+                }
+                TokenError::Jwt(e) if *e.kind() == ErrorKind::ImmatureSignature => {
+                    "Gift is not valid yet".to_string()
+                }
+                _ => format!("Failed to decode JWT: {e}"),
+            };
+
+            (StatusCode::BAD_REQUEST, msg)
+        })?;
+
+    Ok(token.claims)
 }
 
 pub async fn unwrap(
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(config): State<Arc<GiftConfig>>,
+    State(revocations): State<RevocationStore>,
     TypedHeader(cookies): TypedHeader<headers::Cookie>,
 ) -> Result<String, (StatusCode, String)> {
-    let Some(cookie) = cookies.get(COOKIE_NAME) else {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            format!("No {COOKIE_NAME} cookie found"),
-        ));
-    };
+    let claims = verify_gift(&keys, &config, gift_cookie(&cookies, &config)?)?;
 
-    let mut validation = Validation::default();
-    validation.validate_exp = false;
-
-    let token = jsonwebtoken::decode::<Claims>(
-        cookie,
-        &DecodingKey::from_secret("sharedsecret".as_ref()),
-        &validation,
-    )
-    .map_err(|e| {
+    let revoked = revocations.is_revoked(claims.jti).await.map_err(|e| {
         (
-            StatusCode::BAD_REQUEST,
-            format!("Failed to decode JWT: {e}"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to check revocation: {e:?}"),
         )
     })?;
 
-    serde_json::to_string(&token.claims.data).map_err(|e| {
+    if revoked {
+        return Err((StatusCode::BAD_REQUEST, "Gift has been revoked".to_string()));
+    }
+
+    serde_json::to_string(&claims.data).map_err(|e| {
         (
             StatusCode::BAD_REQUEST,
             format!("Failed to decode to json: {e}"),
@@ -84,56 +292,342 @@ pub async fn unwrap(
     })
 }
 
+/// Revoke the gift given in the body, or the `gift` cookie if the body is empty
+pub async fn revoke(
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(config): State<Arc<GiftConfig>>,
+    State(revocations): State<RevocationStore>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+    body: Bytes,
+) -> Result<String, (StatusCode, String)> {
+    let body = std::str::from_utf8(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid body: {e:?}")))?
+        .trim();
+
+    let token = match &cookies {
+        _ if !body.is_empty() => body,
+        Some(TypedHeader(cookies)) => gift_cookie(cookies, &config)?,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("No {} cookie found", config.cookie.name),
+            ))
+        }
+    };
+
+    let claims = verify_gift(&keys, &config, token)?;
+
+    // Tokens are still accepted within the leeway after they expire
+    let expires_at = claims.exp + config.leeway.as_secs();
+
+    revocations
+        .revoke(claims.jti, expires_at)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to revoke gift: {e:?}"),
+            )
+        })?;
+
+    Ok(claims.jti.to_string())
+}
+
+/// Clear the gift cookies, revoking the gift and refresh token they held
+pub async fn logout(
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(config): State<Arc<GiftConfig>>,
+    State(revocations): State<RevocationStore>,
+    State(refresh_tokens): State<RefreshStore>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
+) -> Result<HeaderMap, (StatusCode, String)> {
+    let refresh_token = cookies
+        .as_ref()
+        .and_then(|TypedHeader(cookies)| cookies.get(&config.cookie.refresh_name()));
+
+    if let Some(refresh_token) = refresh_token {
+        let gifts = refresh_tokens.revoke(refresh_token).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to revoke refresh token: {e:?}"),
+            )
+        })?;
+
+        revoke_gifts(&revocations, &gifts).await?;
+    }
+
+    let claims = cookies
+        .as_ref()
+        .and_then(|TypedHeader(cookies)| gift_cookie(cookies, &config).ok())
+        .and_then(|token| verify_gift(&keys, &config, token).ok());
+
+    if let Some(claims) = claims {
+        revocations
+            .revoke(claims.jti, claims.exp + config.leeway.as_secs())
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to revoke gift: {e:?}"),
+                )
+            })?;
+    }
+
+    let mut headers = HeaderMap::new();
+    append_cookie(&mut headers, &config.cookie.clear_cookie())?;
+    append_cookie(&mut headers, &config.cookie.clear_refresh_cookie())?;
+
+    Ok(headers)
+}
+
+/// The `alg` of a token whose header couldn't be parsed because the alg isn't one
+/// `jsonwebtoken` knows, e.g. `none`. Headers invalid for any other reason have none.
+fn unknown_alg(token: &str) -> Option<String> {
+    let header = token.split('.').next()?;
+    let header = URL_SAFE_NO_PAD.decode(header).ok()?;
+    let header: serde_json::Value = serde_json::from_slice(&header).ok()?;
+
+    header
+        .get("alg")?
+        .as_str()
+        .filter(|alg| Algorithm::from_str(alg).is_err())
+        .map(ToString::to_string)
+}
+
+/// Whether the error is about the token's signature or algorithm, rather than its encoding
+fn is_signature_error(e: &jsonwebtoken::errors::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::InvalidSignature
+            | ErrorKind::InvalidAlgorithm
+            | ErrorKind::InvalidAlgorithmName
+            | ErrorKind::InvalidEcdsaKey
+            | ErrorKind::InvalidRsaKey(_)
+            | ErrorKind::InvalidKeyFormat
+            | ErrorKind::Crypto(_)
+    )
+}
+
 pub async fn decode(
-    State(key): State<Arc<DecodingKey>>,
+    State(santa): State<Arc<RwLock<SantaKeys>>>,
     body: Bytes,
 ) -> Result<String, (StatusCode, String)> {
     let token = String::from_utf8(body.to_vec())
         .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid body: {e:?}")))?;
 
-    let Ok(header) = decode_header(&token) else {
-        return Err((StatusCode::BAD_REQUEST, "Invalid header".to_string()));
-    };
+    let header = decode_header(&token).map_err(|_| match unknown_alg(&token) {
+        Some(alg) => (
+            StatusCode::UNAUTHORIZED,
+            format!("Algorithm {alg} is not allowed"),
+        ),
+        None => (StatusCode::BAD_REQUEST, "Invalid header".to_string()),
+    })?;
+
+    let santa = santa.read().unwrap();
+
+    // Never trust the token's alg on its own: only keys of a matching type are tried
+    let allowed = santa
+        .keys
+        .iter()
+        .filter(|key| key.allows(header.alg))
+        .collect::<Vec<_>>();
+
+    if allowed.is_empty() {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            format!("Algorithm {:?} is not allowed", header.alg),
+        ));
+    }
+
+    // Only try the key named by the token, if Santa has a key by that name
+    let named = allowed
+        .iter()
+        .copied()
+        .filter(|key| header.kid.as_ref() == Some(&key.name))
+        .collect::<Vec<_>>();
+    let candidates = if named.is_empty() { allowed } else { named };
 
     let mut validation = Validation::new(header.alg);
     validation.validate_exp = false;
     validation.required_spec_claims.remove("exp");
 
-    let token =
-        jsonwebtoken::decode::<serde_json::Value>(&token, &key, &validation).map_err(|e| {
-            let code = if matches!(e.kind(), jsonwebtoken::errors::ErrorKind::InvalidSignature) {
-                StatusCode::UNAUTHORIZED
-            } else {
-                StatusCode::BAD_REQUEST
-            };
+    let mut error = None;
 
-            (code, format!("Failed to decode JWT: {e}"))
-        })?;
+    for candidate in candidates {
+        match jsonwebtoken::decode::<serde_json::Value>(&token, &candidate.key, &validation) {
+            Ok(token) => {
+                return serde_json::to_string(&token.claims).map_err(|e| {
+                    (
+                        StatusCode::IM_A_TEAPOT,
+                        format!("Failed to decode to json: {e}"),
+                    )
+                })
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+
+    let e = error.expect("At least one key was tried");
+
+    let code = if is_signature_error(&e) {
+        StatusCode::UNAUTHORIZED
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+
+    Err((code, format!("Failed to decode JWT: {e}")))
+}
+
+/// Describe the token in the body for debugging, without requiring it to be valid
+pub async fn inspect(
+    _: Admin,
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(santa): State<Arc<RwLock<SantaKeys>>>,
+    State(config): State<Arc<GiftConfig>>,
+    body: Bytes,
+) -> Result<Json<inspect::Inspection>, (StatusCode, String)> {
+    let token = std::str::from_utf8(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid body: {e:?}")))?
+        .trim();
+
+    let inspection = inspect::inspect(
+        token,
+        &keys.read().unwrap(),
+        &santa.read().unwrap(),
+        config.leeway,
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    Ok(Json(inspection))
+}
+
+pub async fn jwks(State(keys): State<Arc<RwLock<GiftKeys>>>) -> Json<JwkSet> {
+    Json(keys.read().unwrap().jwks())
+}
+
+pub async fn rotate_key(
+    _: Admin,
+    State(keys): State<Arc<RwLock<GiftKeys>>>,
+    State(store): State<GiftKeyStore>,
+    body: Bytes,
+) -> Result<String, (StatusCode, String)> {
+    let params: RotateParams = if body.is_empty() {
+        RotateParams::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to deserialize payload: {e:?}"),
+            )
+        })?
+    };
+
+    let (key, retention) = {
+        let keys = keys.read().unwrap();
+
+        // Default to a new key of the same type as the current one
+        let alg = match params.alg {
+            Some(alg) => Algorithm::from_str(&alg)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid alg {alg}: {e}")))?,
+            None => keys.alg(),
+        };
+        let kid = params.kid.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let key = keys
+            .generate(&kid, alg)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+        (key, keys.retention())
+    };
 
-    serde_json::to_string(&token.claims).map_err(|e| {
+    // Stored first, so tokens are never signed with a key that'd be lost on restart
+    let saved = store.save(&key, retention).await.map_err(|e| {
         (
-            StatusCode::IM_A_TEAPOT,
-            format!("Failed to decode to json: {e}"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store key: {e:?}"),
         )
-    })
+    })?;
+
+    if !saved {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Key {} already exists", key.kid),
+        ));
+    }
+
+    let mut keys = keys.write().unwrap();
+    let retired = keys.kid().to_string();
+
+    keys.install(&key)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let (kid, alg) = (key.kid, key.alg);
+
+    let result = RotateResult { kid, alg, retired };
+
+    Ok(serde_json::to_string_pretty(&result).unwrap())
 }
 
 #[cfg(test)]
 mod day6_tests {
-    use super::{Claims, COOKIE_NAME};
-    use crate::app;
+    use super::{config::SameSite, jwe::GiftEncryption, Claims, GiftConfig};
+    use super::{RefreshStore, SantaKeys};
+    use crate::{admin::AdminToken, app, router, SantaState};
     use axum::{
         body::Body,
         http::{Request, StatusCode},
+        Router,
     };
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
     use http::header;
     use http_body_util::BodyExt;
-    use jsonwebtoken::{EncodingKey, Header};
+    use jsonwebtoken::{get_current_timestamp, jwk::JwkSet, Algorithm, EncodingKey, Header};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
     use tower::util::ServiceExt;
 
+    /// Split a `Set-Cookie` value into its name, value and attributes
+    fn parse_set_cookie(set_cookie: &str) -> (String, String, HashMap<String, String>) {
+        let mut parts = set_cookie.split(';').map(str::trim);
+
+        let (name, value) = parts.next().unwrap().split_once('=').unwrap();
+
+        let attributes = parts
+            .map(|attr| {
+                let (key, val) = attr.split_once('=').unwrap_or((attr, ""));
+                (key.to_ascii_lowercase(), val.to_string())
+            })
+            .collect();
+
+        (name.to_string(), value.to_string(), attributes)
+    }
+
+    /// Send the given cookie to `/16/unwrap`, returning the status and body
+    async fn unwrap_cookie(app: &Router, cookie: &str) -> (StatusCode, String) {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/16/unwrap")
+                    .header(header::COOKIE, cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
     #[tokio::test]
     async fn wrap() {
-        let app = app();
+        let state = SantaState::new();
+        let keys = state.gift_keys.clone();
+        let config = state.gift_config.clone();
+        let app = router(state);
 
         let data = r#"{"cookie is delicious?":true}"#;
 
@@ -148,51 +642,594 @@ mod day6_tests {
             .await
             .unwrap();
 
-        let data_json: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let set_cookie = response
+            .headers()
+            .get("set-cookie")
+            .expect("No gift")
+            .to_str()
+            .expect("No str");
+
+        let (name, token, attributes) = parse_set_cookie(set_cookie);
+        assert_eq!(name, config.cookie.name);
+        assert_eq!(attributes["max-age"], config.ttl.as_secs().to_string());
+        assert_eq!(attributes["path"], "/");
+        assert_eq!(attributes["samesite"], "Lax");
+        assert!(attributes.contains_key("httponly"));
+        assert!(!attributes.contains_key("secure"));
+        assert!(!attributes.contains_key("domain"));
 
-        let token = jsonwebtoken::encode::<Claims>(
-            &Header::default(),
-            &data_json.clone().into(),
-            &EncodingKey::from_secret("sharedsecret".as_ref()),
-        )
-        .unwrap();
+        let cookie = format!("{name}={token}");
+
+        let claims = keys
+            .read()
+            .unwrap()
+            .verify::<Claims>(&token, config.validation())
+            .unwrap()
+            .claims;
+
+        assert_eq!(claims.iss, config.issuer);
+        assert_eq!(claims.aud, config.audience);
+        assert_eq!(claims.nbf, claims.iat);
+        assert_eq!(claims.exp - claims.iat, config.ttl.as_secs());
+
+        let (status, body) = unwrap_cookie(&app, &cookie).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, data);
+    }
+
+    #[tokio::test]
+    async fn unwrap_checks_claims() {
+        let state = SantaState::new();
+        let keys = state.gift_keys.clone();
+        let config = state.gift_config.clone();
+        let app = router(state);
+
+        let now = get_current_timestamp();
+        let leeway = config.leeway.as_secs();
+        let sign = |claims: &Claims| {
+            let token = keys.read().unwrap().sign(claims).unwrap();
+            format!("{}={token}", config.cookie.name)
+        };
+
+        let valid = Claims::new(json!("present"), &config);
+
+        // Each token gets its own id
+        assert_ne!(valid.jti, Claims::new(json!("present"), &config).jti);
+
+        // Within the leeway is still fine
+        let skewed = Claims {
+            exp: now - leeway / 2,
+            ..valid.clone()
+        };
+        assert_eq!(
+            unwrap_cookie(&app, &sign(&skewed)).await,
+            (StatusCode::OK, r#""present""#.to_string())
+        );
+
+        let expired = Claims {
+            exp: now - leeway - 1,
+            ..valid.clone()
+        };
+        assert_eq!(
+            unwrap_cookie(&app, &sign(&expired)).await,
+            (StatusCode::BAD_REQUEST, "Gift has expired".to_string())
+        );
+
+        let immature = Claims {
+            nbf: now + leeway + 60,
+            ..valid.clone()
+        };
+        assert_eq!(
+            unwrap_cookie(&app, &sign(&immature)).await,
+            (StatusCode::BAD_REQUEST, "Gift is not valid yet".to_string())
+        );
 
-        let cookie = format!("{COOKIE_NAME}={token}");
+        let wrong_issuer = Claims {
+            iss: "grinch".to_string(),
+            ..valid.clone()
+        };
+        let (status, body) = unwrap_cookie(&app, &sign(&wrong_issuer)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("InvalidIssuer"), "{body}");
+
+        let wrong_audience = Claims {
+            aud: "reindeer".to_string(),
+            ..valid
+        };
+        let (status, body) = unwrap_cookie(&app, &sign(&wrong_audience)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("InvalidAudience"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn revoke() {
+        let state = SantaState::new();
+        let keys = state.gift_keys.clone();
+        let config = state.gift_config.clone();
+        let app = router(state);
+
+        let token = keys
+            .read()
+            .unwrap()
+            .sign(&Claims::new(json!("present"), &config))
+            .unwrap();
+        let cookie = format!("{}={token}", config.cookie.name);
+
+        assert_eq!(unwrap_cookie(&app, &cookie).await.0, StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/revoke")
+                    .header(header::COOKIE, &cookie)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
-        let headers = response.headers();
-        println!("{headers:?}");
+
         assert_eq!(
-            headers
-                .get("set-cookie")
-                .expect("No gift")
-                .to_str()
-                .expect("No str"),
-            cookie
+            unwrap_cookie(&app, &cookie).await,
+            (StatusCode::BAD_REQUEST, "Gift has been revoked".to_string())
         );
 
+        // Other gifts are unaffected
+        let other = keys
+            .read()
+            .unwrap()
+            .sign(&Claims::new(json!("present"), &config))
+            .unwrap();
+        let other = format!("{}={other}", config.cookie.name);
+        assert_eq!(unwrap_cookie(&app, &other).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn encrypted_gifts() {
+        let state = SantaState::new();
+        let keys = state.gift_keys.clone();
+        let config = state.gift_config.clone();
+        let app = router(state);
+
+        // Gifts wrapped before encryption was enabled are still accepted
+        let plain = keys
+            .read()
+            .unwrap()
+            .encode(&Claims::new(json!("plain"), &config))
+            .unwrap();
+
+        keys.write()
+            .unwrap()
+            .set_encryption(GiftEncryption::random_direct("first"));
+
         let response = app
             .clone()
             .oneshot(
-                Request::get("/16/unwrap")
-                    .header(header::COOKIE, cookie)
+                Request::post("/16/wrap")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#""a surprise""#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+        let (name, token, _) = parse_set_cookie(set_cookie);
+
+        // A compact JWE whose header gives nothing away
+        assert_eq!(token.split('.').count(), 5);
+        let header = token.split('.').next().unwrap();
+        let header =
+            base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, header)
+                .unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&header).unwrap();
+        assert_eq!(header["alg"], "dir");
+        assert_eq!(header["enc"], "A256GCM");
+        assert_eq!(header["kid"], "first");
+
+        assert_eq!(
+            unwrap_cookie(&app, &format!("{name}={token}")).await,
+            (StatusCode::OK, r#""a surprise""#.to_string())
+        );
+
+        assert_eq!(
+            unwrap_cookie(&app, &format!("{name}={plain}")).await,
+            (StatusCode::OK, r#""plain""#.to_string())
+        );
+
+        // Gifts encrypted with a rotated out key are still accepted
+        keys.write()
+            .unwrap()
+            .set_encryption(GiftEncryption::random_direct("second"));
+
+        assert_eq!(
+            unwrap_cookie(&app, &format!("{name}={token}")).await,
+            (StatusCode::OK, r#""a surprise""#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn host_prefixed_cookie_and_logout() {
+        let mut config = GiftConfig::default();
+        config.cookie.name = "__Host-gift".to_string();
+        config.cookie.secure = true;
+        config.cookie.same_site = Some(SameSite::Strict);
+        config.cookie.check().unwrap();
+
+        let mut state = SantaState::new();
+        state.gift_config = Arc::new(config);
+        let app = router(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/wrap")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from("[1,2,3]"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+        let (name, token, attributes) = parse_set_cookie(set_cookie);
+        assert_eq!(name, "__Host-gift");
+        assert_eq!(attributes["samesite"], "Strict");
+        assert!(attributes.contains_key("secure"));
+
+        let cookie = format!("{name}={token}");
+        assert_eq!(
+            unwrap_cookie(&app, &cookie).await,
+            (StatusCode::OK, "[1,2,3]".to_string())
+        );
+
+        // The unprefixed name is no longer read
+        let (status, _) = unwrap_cookie(&app, &format!("gift={token}")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/logout")
+                    .header(header::COOKIE, &cookie)
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
 
-        // assert_eq!(response.status(), StatusCode::OK);
-        let body = String::from_utf8(
-            response
-                .into_body()
-                .collect()
-                .await
+        assert_eq!(response.status(), StatusCode::OK);
+        let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+        let (name, value, attributes) = parse_set_cookie(set_cookie);
+        assert_eq!(name, "__Host-gift");
+        assert_eq!(value, "");
+        assert_eq!(attributes["max-age"], "0");
+        assert_eq!(attributes["path"], "/");
+
+        // Logging out also revokes the gift
+        assert_eq!(
+            unwrap_cookie(&app, &cookie).await,
+            (StatusCode::BAD_REQUEST, "Gift has been revoked".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn inspect() {
+        let mut state = SantaState::new();
+        state.admin_token = AdminToken(Some("elf".into()));
+        let keys = state.gift_keys.clone();
+        let config = state.gift_config.clone();
+        let app = router(state);
+
+        // Expired tokens can still be inspected
+        let claims = Claims {
+            exp: get_current_timestamp() - config.leeway.as_secs() - 1,
+            ..Claims::new(json!("present"), &config)
+        };
+        let token = keys.read().unwrap().encode(&claims).unwrap();
+
+        let inspect = |token: String| {
+            Request::post("/16/inspect")
+                .header(header::AUTHORIZATION, "Bearer elf")
+                .body(Body::from(token))
                 .unwrap()
-                .to_bytes()
-                .to_vec(),
+        };
+
+        let response = app.clone().oneshot(inspect(token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let inspection: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(inspection["encrypted"], false);
+        assert_eq!(inspection["alg"], "HS256");
+        assert_eq!(inspection["kid"], "default");
+        assert_eq!(inspection["header"]["typ"], "JWT");
+        assert_eq!(inspection["claims"]["data"], "present");
+        assert_eq!(inspection["expiry"], "expired");
+
+        let signatures = inspection["signatures"].as_array().unwrap();
+        assert_eq!(signatures.len(), 2);
+        assert_eq!(signatures[0]["key"], "default");
+        assert_eq!(signatures[0]["verified"], true);
+        assert_eq!(signatures[1]["key"], "santa:day16_santa_public_key.pem");
+        assert_eq!(signatures[1]["verified"], false);
+
+        let response = app
+            .clone()
+            .oneshot(inspect("not a token".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Send the given token to `/16/decode`, returning the status and body
+    async fn decode_token(app: &Router, token: String) -> (StatusCode, String) {
+        let response = app
+            .clone()
+            .oneshot(Request::post("/16/decode").body(Body::from(token)).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn decode_with_loaded_keys() {
+        let dir = std::env::temp_dir().join(format!("santa_decode_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("santa.pem"),
+            include_str!("../test_keys/gift_rs256.pub.pem"),
         )
         .unwrap();
-        assert_eq!(body, data);
+
+        let mut state = SantaState::new();
+        state.santa_keys = Arc::new(RwLock::new(SantaKeys::load(&dir).unwrap()));
+        let app = router(state);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let claims = json!({"naughty": false});
+
+        let key = EncodingKey::from_rsa_pem(include_bytes!("../test_keys/gift_rs256.pem")).unwrap();
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key).unwrap();
+        assert_eq!(
+            decode_token(&app, token).await,
+            (StatusCode::OK, claims.to_string())
+        );
+
+        // Signed by somebody else entirely
+        let key = EncodingKey::from_ec_pem(include_bytes!("../test_keys/gift_es256.pem")).unwrap();
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::ES256), &claims, &key).unwrap();
+        assert_eq!(decode_token(&app, token).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn decode_rejects_algorithm_confusion() {
+        let app = app();
+        let claims = json!({"naughty": false});
+
+        // HS256 using Santa's public key as the shared secret
+        let key = EncodingKey::from_secret(include_bytes!("../day16_santa_public_key.pem"));
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &key).unwrap();
+        assert_eq!(
+            decode_token(&app, token).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                "Algorithm HS256 is not allowed".to_string()
+            )
+        );
+
+        // Unsigned
+        let b64 = |val: serde_json::Value| URL_SAFE_NO_PAD.encode(val.to_string());
+        let token = format!(
+            "{}.{}.",
+            b64(json!({"alg": "none", "typ": "JWT"})),
+            b64(claims)
+        );
+        assert_eq!(
+            decode_token(&app, token).await,
+            (
+                StatusCode::UNAUTHORIZED,
+                "Algorithm none is not allowed".to_string()
+            )
+        );
+
+        // Allowed algorithm, forged signature
+        let token = format!(
+            "{}.{}.{}",
+            b64(json!({"alg": "RS256", "typ": "JWT"})),
+            b64(json!({"naughty": false})),
+            URL_SAFE_NO_PAD.encode([0; 256])
+        );
+        assert_eq!(decode_token(&app, token).await.0, StatusCode::UNAUTHORIZED);
+
+        // Allowed algorithm, but the rest of the header is invalid
+        let token = format!(
+            "{}.{}.{}",
+            b64(json!({"alg": "RS256", "kid": 5})),
+            b64(json!({"naughty": false})),
+            URL_SAFE_NO_PAD.encode([0; 256])
+        );
+        assert_eq!(
+            decode_token(&app, token).await,
+            (StatusCode::BAD_REQUEST, "Invalid header".to_string())
+        );
+
+        assert_eq!(
+            decode_token(&app, "garbage".to_string()).await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh() {
+        let state = SantaState::new();
+        let config = state.gift_config.clone();
+        let app = router(state);
+
+        // Returns the gift and refresh cookies set by the response
+        let cookies = |response: http::Response<Body>| {
+            let mut cookies = response
+                .headers()
+                .get_all("set-cookie")
+                .iter()
+                .map(|cookie| parse_set_cookie(cookie.to_str().unwrap()))
+                .map(|(name, value, _)| format!("{name}={value}"));
+
+            (cookies.next().unwrap(), cookies.next().unwrap())
+        };
+
+        let refresh = |cookie: &str| {
+            Request::post("/16/refresh")
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/wrap")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"toy":"train"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let set_cookie = response.headers().get_all("set-cookie").iter().nth(1);
+        let (name, _, attributes) = parse_set_cookie(set_cookie.unwrap().to_str().unwrap());
+        assert_eq!(name, config.cookie.refresh_name());
+        assert_eq!(
+            attributes["max-age"],
+            config.refresh_ttl.as_secs().to_string()
+        );
+
+        let (_, first) = cookies(response);
+
+        let response = app.clone().oneshot(refresh(&first)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let (gift, second) = cookies(response);
+        assert_ne!(first, second);
+
+        // The new gift holds the same data
+        assert_eq!(
+            unwrap_cookie(&app, &gift).await,
+            (StatusCode::OK, r#"{"toy":"train"}"#.to_string())
+        );
+
+        // Using a rotated out token again revokes the whole family
+        let response = app.clone().oneshot(refresh(&first)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(refresh(&second)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Along with the gifts issued in it
+        assert_eq!(
+            unwrap_cookie(&app, &gift).await,
+            (StatusCode::BAD_REQUEST, "Gift has been revoked".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn wrap_without_refresh_store() {
+        // Nothing listens on port 1, so storing refresh tokens fails
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_secs(1))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+            .unwrap();
+
+        let state = SantaState {
+            refresh_tokens: RefreshStore::Postgres(Arc::new(pool)),
+            ..SantaState::new()
+        };
+        let config = state.gift_config.clone();
+        let app = router(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/wrap")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"toy":"train"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The gift is issued without a refresh token
+        let cookies = response
+            .headers()
+            .get_all("set-cookie")
+            .iter()
+            .map(|cookie| parse_set_cookie(cookie.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 1);
+
+        let (name, value, _) = &cookies[0];
+        assert_eq!(*name, config.cookie.name);
+        assert_eq!(
+            unwrap_cookie(&app, &format!("{name}={value}")).await,
+            (StatusCode::OK, r#"{"toy":"train"}"#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_and_jwks() {
+        let mut state = SantaState::new();
+        state.admin_token = AdminToken(Some("elf".into()));
+        let app = router(state);
+
+        // Rotating requires the admin token
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/keys/rotate")
+                    .body(Body::from(r#"{"alg":"EdDSA","kid":"ed"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/16/keys/rotate")
+                    .header(header::AUTHORIZATION, "Bearer elf")
+                    .body(Body::from(r#"{"alg":"EdDSA","kid":"ed"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/.well-known/jwks.json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let jwks: JwkSet = serde_json::from_slice(&body).unwrap();
+
+        // Only the new public key is published, never the old shared secret
+        assert_eq!(jwks.keys.len(), 1);
+        assert!(jwks.find("ed").is_some());
     }
 }
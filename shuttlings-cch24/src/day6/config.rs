@@ -0,0 +1,354 @@
+use super::{SECS_PER_HOUR, SECS_PER_MINUTE};
+use jsonwebtoken::Validation;
+use shuttle_runtime::SecretStore;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How long a gift is valid for by default
+const DEFAULT_TTL: Duration = Duration::from_secs(SECS_PER_HOUR);
+
+/// How long a refresh token is valid for by default
+const DEFAULT_REFRESH_TTL: Duration = Duration::from_secs(30 * 24 * SECS_PER_HOUR);
+
+/// Default clock skew tolerated when checking `exp` and `nbf`
+const DEFAULT_LEEWAY: Duration = Duration::from_secs(SECS_PER_MINUTE);
+
+const DEFAULT_ISSUER: &str = "santa";
+const DEFAULT_AUDIENCE: &str = "gift";
+const DEFAULT_COOKIE_NAME: &str = "gift";
+
+/// The `SameSite` attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl std::str::FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(format!("Unknown SameSite value: {s}")),
+        }
+    }
+}
+
+/// The attributes of the cookie gifts are stored in
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    /// Name of the cookie, possibly with a `__Host-` or `__Secure-` prefix
+    pub name: String,
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_COOKIE_NAME.to_string(),
+            http_only: true,
+            secure: false,
+            same_site: Some(SameSite::Lax),
+            path: Some("/".to_string()),
+            domain: None,
+        }
+    }
+}
+
+/// Whether `name` is a token, as RFC 6265 requires of cookie names
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+/// Whether `value` can be used as a `Path` or `Domain` without ending the attribute early
+/// or breaking the header
+fn is_attribute_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b.is_ascii_graphic() && b != b';' && b != b',')
+}
+
+impl CookieConfig {
+    /// Load the cookie attributes from the `GIFT_COOKIE_*` secrets
+    fn from_secrets(secrets: &SecretStore) -> Result<Self, String> {
+        let default = Self::default();
+
+        let flag = |name: &str, default: bool| {
+            secrets.get(name).map_or(Ok(default), |val| {
+                val.parse().map_err(|e| format!("Invalid {name}: {e}"))
+            })
+        };
+
+        let same_site = match secrets.get("GIFT_COOKIE_SAME_SITE") {
+            Some(val) if val.is_empty() => None,
+            Some(val) => Some(val.parse()?),
+            None => default.same_site,
+        };
+
+        let config = Self {
+            name: secrets.get("GIFT_COOKIE_NAME").unwrap_or(default.name),
+            http_only: flag("GIFT_COOKIE_HTTP_ONLY", default.http_only)?,
+            secure: flag("GIFT_COOKIE_SECURE", default.secure)?,
+            same_site,
+            path: secrets.get("GIFT_COOKIE_PATH").or(default.path),
+            domain: secrets.get("GIFT_COOKIE_DOMAIN").or(default.domain),
+        };
+
+        config.check()?;
+
+        Ok(config)
+    }
+
+    /// Check the name and attributes are valid, and satisfy what browsers require of the
+    /// cookie's name prefix
+    pub fn check(&self) -> Result<(), String> {
+        let name = &self.name;
+
+        if !is_token(name) {
+            return Err(format!("Invalid cookie name {name:?}"));
+        }
+
+        for (attribute, value) in [("Path", &self.path), ("Domain", &self.domain)] {
+            if let Some(value) = value.as_deref().filter(|value| !is_attribute_value(value)) {
+                return Err(format!("Invalid {attribute} {value:?} for cookie {name}"));
+            }
+        }
+
+        let prefixed = name.starts_with("__Secure-") || name.starts_with("__Host-");
+        if prefixed && !self.secure {
+            return Err(format!("Cookie {name} must be Secure"));
+        }
+
+        if name.starts_with("__Host-") {
+            if self.domain.is_some() {
+                return Err(format!("Cookie {name} can't have a Domain"));
+            }
+
+            if self.path.as_deref() != Some("/") {
+                return Err(format!("Cookie {name} must have Path=/"));
+            }
+        }
+
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            return Err(format!("Cookie {name} must be Secure to use SameSite=None"));
+        }
+
+        Ok(())
+    }
+
+    /// Name of the cookie holding the refresh token
+    pub fn refresh_name(&self) -> String {
+        format!("{}_refresh", self.name)
+    }
+
+    /// The `Set-Cookie` value storing `value` for `max_age` seconds
+    pub fn set_cookie(&self, value: &str, max_age: u64) -> String {
+        self.build(&self.name, value, max_age)
+    }
+
+    /// The `Set-Cookie` value storing the refresh token for `max_age` seconds
+    pub fn set_refresh_cookie(&self, value: &str, max_age: u64) -> String {
+        self.build(&self.refresh_name(), value, max_age)
+    }
+
+    /// The `Set-Cookie` value telling the browser to drop the refresh cookie
+    pub fn clear_refresh_cookie(&self) -> String {
+        self.set_refresh_cookie("", 0)
+    }
+
+    fn build(&self, name: &str, value: &str, max_age: u64) -> String {
+        let mut cookie = format!("{name}={value}; Max-Age={max_age}");
+
+        if let Some(path) = &self.path {
+            write!(cookie, "; Path={path}").unwrap();
+        }
+
+        if let Some(domain) = &self.domain {
+            write!(cookie, "; Domain={domain}").unwrap();
+        }
+
+        if let Some(same_site) = self.same_site {
+            write!(cookie, "; SameSite={}", same_site.as_str()).unwrap();
+        }
+
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+
+        cookie
+    }
+
+    /// The `Set-Cookie` value telling the browser to drop the cookie
+    pub fn clear_cookie(&self) -> String {
+        self.set_cookie("", 0)
+    }
+}
+
+/// How gift tokens are issued and validated
+#[derive(Debug, Clone)]
+pub struct GiftConfig {
+    /// How long a token is valid for after being issued
+    pub ttl: Duration,
+
+    /// How long a refresh token is valid for after being issued
+    pub refresh_ttl: Duration,
+
+    /// Clock skew tolerated when checking `exp` and `nbf`
+    pub leeway: Duration,
+
+    /// Value of the `iss` claim
+    pub issuer: String,
+
+    /// Value of the `aud` claim
+    pub audience: String,
+
+    /// The cookie the token is stored in
+    pub cookie: CookieConfig,
+}
+
+impl Default for GiftConfig {
+    fn default() -> Self {
+        Self {
+            ttl: DEFAULT_TTL,
+            refresh_ttl: DEFAULT_REFRESH_TTL,
+            leeway: DEFAULT_LEEWAY,
+            issuer: DEFAULT_ISSUER.to_string(),
+            audience: DEFAULT_AUDIENCE.to_string(),
+            cookie: CookieConfig::default(),
+        }
+    }
+}
+
+/// Parse the given secret as a number of seconds
+fn secs_secret(secrets: &SecretStore, name: &str) -> Result<Option<Duration>, String> {
+    secrets
+        .get(name)
+        .map(|secs| {
+            secs.parse()
+                .map(Duration::from_secs)
+                .map_err(|e| format!("Invalid {name}: {e}"))
+        })
+        .transpose()
+}
+
+impl GiftConfig {
+    /// Load the config from the `GIFT_TTL_SECS`, `GIFT_REFRESH_TTL_SECS`, `GIFT_LEEWAY_SECS`,
+    /// `GIFT_ISSUER`, `GIFT_AUDIENCE` and `GIFT_COOKIE_*` secrets, using the defaults for
+    /// anything not set
+    pub fn from_secrets(secrets: &SecretStore) -> Result<Self, String> {
+        let default = Self::default();
+
+        Ok(Self {
+            ttl: secs_secret(secrets, "GIFT_TTL_SECS")?.unwrap_or(default.ttl),
+            refresh_ttl: secs_secret(secrets, "GIFT_REFRESH_TTL_SECS")?
+                .unwrap_or(default.refresh_ttl),
+            leeway: secs_secret(secrets, "GIFT_LEEWAY_SECS")?.unwrap_or(default.leeway),
+            issuer: secrets.get("GIFT_ISSUER").unwrap_or(default.issuer),
+            audience: secrets.get("GIFT_AUDIENCE").unwrap_or(default.audience),
+            cookie: CookieConfig::from_secrets(secrets)?,
+        })
+    }
+
+    /// The longest a token can still be accepted for after being issued
+    pub fn max_age(&self) -> Duration {
+        self.ttl + self.leeway
+    }
+
+    /// Validation checking every time based and registered claim of a gift
+    pub fn validation(&self) -> Validation {
+        let mut validation = Validation::default();
+        validation.validate_exp = true;
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud"]);
+
+        validation
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::{CookieConfig, SameSite};
+
+    #[test]
+    fn host_prefix_requirements() {
+        let mut cookie = CookieConfig {
+            name: "__Host-gift".to_string(),
+            ..CookieConfig::default()
+        };
+        assert!(cookie.check().is_err());
+
+        cookie.secure = true;
+        assert!(cookie.check().is_ok());
+
+        cookie.domain = Some("example.com".to_string());
+        assert!(cookie.check().is_err());
+
+        cookie.domain = None;
+        cookie.path = Some("/16".to_string());
+        assert!(cookie.check().is_err());
+    }
+
+    #[test]
+    fn invalid_names_and_attributes() {
+        let cookie = |name: &str, path: &str, domain: &str| CookieConfig {
+            name: name.to_string(),
+            path: Some(path.to_string()),
+            domain: Some(domain.to_string()),
+            ..CookieConfig::default()
+        };
+
+        assert!(cookie("gift", "/16", "example.com").check().is_ok());
+
+        for name in ["", "gift box", "gift;", "gift=", "gift\n", "gïft"] {
+            assert!(
+                cookie(name, "/", "example.com").check().is_err(),
+                "{name:?}"
+            );
+        }
+
+        for attribute in ["/; Secure", "/a,b", "/a b", "/\t", "/\u{7f}"] {
+            assert!(cookie("gift", attribute, "example.com").check().is_err());
+            assert!(cookie("gift", "/", attribute).check().is_err());
+        }
+    }
+
+    #[test]
+    fn same_site_none_requires_secure() {
+        let mut cookie = CookieConfig {
+            same_site: Some(SameSite::None),
+            ..CookieConfig::default()
+        };
+        assert!(cookie.check().is_err());
+
+        cookie.secure = true;
+        assert!(cookie.check().is_ok());
+    }
+}
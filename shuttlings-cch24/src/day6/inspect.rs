@@ -0,0 +1,117 @@
+use super::jwe::is_jwe;
+use super::keys::{check_signature, GiftKeys, SignatureCheck};
+use super::santa::SantaKeys;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode_header, get_current_timestamp, Algorithm, Header};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Where the token stands relative to its `exp` and `nbf` claims
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryStatus {
+    Valid,
+    Expired,
+    NotYetValid,
+
+    /// The token has no `exp` claim
+    NoExpiry,
+}
+
+/// Everything that can be learned about a token, whether it's valid or not
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    /// The token was a JWE that had to be decrypted first
+    pub encrypted: bool,
+    pub header: Header,
+    pub alg: Algorithm,
+    pub kid: Option<String>,
+    pub claims: serde_json::Value,
+    pub expiry: ExpiryStatus,
+
+    /// Seconds until the token expires, negative once it has
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>,
+
+    pub signatures: Vec<SignatureCheck>,
+}
+
+/// Read a numeric date claim
+fn timestamp(claims: &serde_json::Value, name: &str) -> Option<i64> {
+    claims.get(name).and_then(serde_json::Value::as_i64)
+}
+
+/// Work out the expiry status of the given claims, allowing for `leeway` of clock skew
+fn expiry(claims: &serde_json::Value, now: i64, leeway: i64) -> ExpiryStatus {
+    if timestamp(claims, "nbf").is_some_and(|nbf| nbf > now + leeway) {
+        return ExpiryStatus::NotYetValid;
+    }
+
+    match timestamp(claims, "exp") {
+        None => ExpiryStatus::NoExpiry,
+        Some(exp) if exp < now - leeway => ExpiryStatus::Expired,
+        Some(_) => ExpiryStatus::Valid,
+    }
+}
+
+/// Inspect the given token, checking its signature against the gift keys and Santa's key
+pub fn inspect(
+    token: &str,
+    keys: &GiftKeys,
+    santa: &SantaKeys,
+    leeway: Duration,
+) -> Result<Inspection, String> {
+    let signed = keys.decrypt(token).map_err(|e| e.to_string())?;
+
+    let header = decode_header(&signed).map_err(|e| format!("Invalid header: {e}"))?;
+
+    let payload = signed
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| "Token has no claims".to_string())?;
+    let claims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|e| format!("Invalid claims encoding: {e}"))?;
+    let claims: serde_json::Value =
+        serde_json::from_slice(&claims).map_err(|e| format!("Invalid claims: {e}"))?;
+
+    let now = i64::try_from(get_current_timestamp()).unwrap_or(i64::MAX);
+    let leeway = i64::try_from(leeway.as_secs()).unwrap_or(i64::MAX);
+
+    let mut signatures = keys.check_signatures(&signed);
+    signatures.extend(santa.keys.iter().map(|key| {
+        let result = check_signature(&signed, &key.key, header.alg);
+        SignatureCheck::new(&format!("santa:{}", key.name), header.alg, false, result)
+    }));
+
+    Ok(Inspection {
+        encrypted: is_jwe(token),
+        alg: header.alg,
+        kid: header.kid.clone(),
+        expiry: expiry(&claims, now, leeway),
+        expires_in: timestamp(&claims, "exp").map(|exp| exp - now),
+        header,
+        claims,
+        signatures,
+    })
+}
+
+#[cfg(test)]
+mod inspect_tests {
+    use super::{expiry, ExpiryStatus};
+    use serde_json::json;
+
+    #[test]
+    fn expiry_status() {
+        let now = 1000;
+
+        assert_eq!(expiry(&json!({}), now, 0), ExpiryStatus::NoExpiry);
+        assert_eq!(expiry(&json!({"exp": 1001}), now, 0), ExpiryStatus::Valid);
+        assert_eq!(expiry(&json!({"exp": 999}), now, 0), ExpiryStatus::Expired);
+        assert_eq!(expiry(&json!({"exp": 999}), now, 5), ExpiryStatus::Valid);
+        assert_eq!(
+            expiry(&json!({"exp": 2000, "nbf": 1010}), now, 5),
+            ExpiryStatus::NotYetValid
+        );
+    }
+}
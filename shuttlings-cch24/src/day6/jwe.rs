@@ -0,0 +1,317 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, sha2::Sha256, Oaep, RsaPrivateKey,
+    RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+
+/// Length of the AES-256-GCM content encryption key
+const CEK_LEN: usize = 32;
+
+/// The only content encryption supported
+const ENC: &str = "A256GCM";
+
+/// Protected header of a compact JWE
+#[derive(Debug, Serialize, Deserialize)]
+struct JweHeader {
+    alg: String,
+    enc: String,
+
+    /// The content is a signed JWT
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cty: Option<String>,
+
+    /// The key the content was encrypted for. Missing from gifts encrypted before keys had
+    /// ids.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+/// How the content encryption key of a gift JWE is established
+enum EncryptionKey {
+    /// `dir`: the shared key is used directly as the content encryption key
+    Direct([u8; CEK_LEN]),
+
+    /// `RSA-OAEP-256`: a random content encryption key is wrapped with the public key.
+    ///
+    /// The `rsa` crate's decryption isn't constant time (RUSTSEC-2023-0071, the Marvin
+    /// attack), so an attacker timing many decryptions may be able to recover the key.
+    /// It's only used when explicitly allowed.
+    RsaOaep(Box<RsaPrivateKey>),
+}
+
+/// An encryption key for gift tokens, identified by the `kid` in the JWE header so the
+/// key can be rotated
+pub struct GiftEncryption {
+    kid: String,
+    key: EncryptionKey,
+}
+
+/// Whether the given token is a compact JWE rather than a JWS
+pub fn is_jwe(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+fn b64_decode(part: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|e| format!("Invalid base64: {e}"))
+}
+
+fn aes_key(cek: &[u8]) -> Result<LessSafeKey, String> {
+    UnboundKey::new(&AES_256_GCM, cek)
+        .map(LessSafeKey::new)
+        .map_err(|_| "Invalid content encryption key".to_string())
+}
+
+/// The error for any JWE that fails to decrypt. Failing to unwrap the key isn't told
+/// apart from failing to decrypt the content, so the errors can't be used as an oracle.
+fn decrypt_failed() -> String {
+    "Failed to decrypt".to_string()
+}
+
+/// The `kid` in the header of the given compact JWE, if it has one
+pub fn kid(jwe: &str) -> Option<String> {
+    let header = b64_decode(jwe.split('.').next()?).ok()?;
+    let header: JweHeader = serde_json::from_slice(&header).ok()?;
+
+    header.kid
+}
+
+impl GiftEncryption {
+    /// Direct encryption with a freshly generated key
+    #[cfg(test)]
+    pub fn random_direct(kid: &str) -> Self {
+        let mut key = [0; CEK_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .expect("Failed to generate encryption key");
+
+        GiftEncryption {
+            kid: kid.to_string(),
+            key: EncryptionKey::Direct(key),
+        }
+    }
+
+    /// Create the encryption from the `alg` and key given in config. Direct keys are
+    /// base64url encoded, RSA keys are PKCS#1 or PKCS#8 PEM private keys. `RSA-OAEP-256` is
+    /// rejected unless `allow_rsa` is set, see [`EncryptionKey::RsaOaep`].
+    pub fn new(kid: &str, alg: &str, key: &str, allow_rsa: bool) -> Result<Self, String> {
+        let key =
+            match alg {
+                "dir" => {
+                    let key = b64_decode(key.trim())?
+                        .try_into()
+                        .map_err(|_| format!("Direct keys must be {CEK_LEN} bytes"))?;
+
+                    EncryptionKey::Direct(key)
+                }
+                "RSA-OAEP-256" if !allow_rsa => return Err(
+                    "RSA-OAEP-256 is affected by RUSTSEC-2023-0071 and must be explicitly allowed"
+                        .to_string(),
+                ),
+                "RSA-OAEP-256" => {
+                    let key = RsaPrivateKey::from_pkcs8_pem(key)
+                        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(key))
+                        .map_err(|e| format!("Invalid RSA key: {e}"))?;
+
+                    EncryptionKey::RsaOaep(Box::new(key))
+                }
+                _ => return Err(format!("Unsupported encryption alg: {alg}")),
+            };
+
+        Ok(GiftEncryption {
+            kid: kid.to_string(),
+            key,
+        })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    fn alg(&self) -> &'static str {
+        match self.key {
+            EncryptionKey::Direct(_) => "dir",
+            EncryptionKey::RsaOaep(_) => "RSA-OAEP-256",
+        }
+    }
+
+    /// Encrypt the given signed token into a compact JWE
+    pub fn encrypt(&self, token: &str) -> Result<String, String> {
+        let rng = SystemRandom::new();
+        let failed = |_| "Failed to generate randomness".to_string();
+
+        let (cek, encrypted_key) = match &self.key {
+            EncryptionKey::Direct(key) => (*key, Vec::new()),
+            EncryptionKey::RsaOaep(key) => {
+                let mut cek = [0; CEK_LEN];
+                rng.fill(&mut cek).map_err(failed)?;
+
+                let encrypted_key = RsaPublicKey::from(key.as_ref())
+                    .encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), &cek)
+                    .map_err(|e| format!("Failed to wrap key: {e}"))?;
+
+                (cek, encrypted_key)
+            }
+        };
+
+        let header = JweHeader {
+            alg: self.alg().to_string(),
+            enc: ENC.to_string(),
+            cty: Some("JWT".to_string()),
+            kid: Some(self.kid.clone()),
+        };
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap());
+
+        let mut iv = [0; NONCE_LEN];
+        rng.fill(&mut iv).map_err(failed)?;
+
+        let mut ciphertext = token.as_bytes().to_vec();
+        let tag = aes_key(&cek)?
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(iv),
+                Aad::from(header.as_bytes()),
+                &mut ciphertext,
+            )
+            .map_err(|_| "Failed to encrypt".to_string())?;
+
+        Ok([
+            header,
+            URL_SAFE_NO_PAD.encode(encrypted_key),
+            URL_SAFE_NO_PAD.encode(iv),
+            URL_SAFE_NO_PAD.encode(ciphertext),
+            URL_SAFE_NO_PAD.encode(tag),
+        ]
+        .join("."))
+    }
+
+    /// Decrypt the given compact JWE, returning the signed token inside
+    pub fn decrypt(&self, jwe: &str) -> Result<String, String> {
+        let [header, encrypted_key, iv, ciphertext, tag] = jwe
+            .split('.')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| "JWE must have 5 parts".to_string())?;
+
+        let parsed: JweHeader = serde_json::from_slice(&b64_decode(header)?)
+            .map_err(|e| format!("Invalid JWE header: {e}"))?;
+
+        if parsed.alg != self.alg() || parsed.enc != ENC {
+            return Err(format!(
+                "Unsupported JWE algorithm: {}/{}",
+                parsed.alg, parsed.enc
+            ));
+        }
+
+        let encrypted_key = b64_decode(encrypted_key)?;
+        let cek = match &self.key {
+            EncryptionKey::Direct(key) if encrypted_key.is_empty() => key.to_vec(),
+            EncryptionKey::Direct(_) => {
+                return Err("Direct encryption must not carry a key".to_string())
+            }
+            EncryptionKey::RsaOaep(key) => {
+                // A key that fails to unwrap is replaced with a random one, so decryption
+                // fails the same way as it would for a wrong key (RFC 7516, section 11.5)
+                let mut random = [0; CEK_LEN];
+                SystemRandom::new()
+                    .fill(&mut random)
+                    .map_err(|_| "Failed to generate randomness".to_string())?;
+
+                key.decrypt(Oaep::new::<Sha256>(), &encrypted_key)
+                    .ok()
+                    .filter(|cek| cek.len() == CEK_LEN)
+                    .unwrap_or_else(|| random.to_vec())
+            }
+        };
+
+        let iv: [u8; NONCE_LEN] = b64_decode(iv)?
+            .try_into()
+            .map_err(|_| "Invalid JWE iv".to_string())?;
+
+        let mut in_out = b64_decode(ciphertext)?;
+        in_out.extend(b64_decode(tag)?);
+
+        let plaintext = aes_key(&cek)
+            .map_err(|_| decrypt_failed())?
+            .open_in_place(
+                Nonce::assume_unique_for_key(iv),
+                Aad::from(header.as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| decrypt_failed())?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| "JWE content is not a JWT".to_string())
+    }
+}
+
+#[cfg(test)]
+mod jwe_tests {
+    use super::{is_jwe, kid, GiftEncryption};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    const TOKEN: &str = "header.claims.signature";
+
+    #[test]
+    fn direct_round_trip() {
+        let encryption = GiftEncryption::random_direct("enc");
+
+        let jwe = encryption.encrypt(TOKEN).unwrap();
+        assert!(is_jwe(&jwe));
+        assert!(!jwe.contains("claims"));
+        assert_eq!(kid(&jwe).as_deref(), Some("enc"));
+        assert_eq!(encryption.decrypt(&jwe).unwrap(), TOKEN);
+
+        // Another key can't open it
+        assert!(GiftEncryption::random_direct("enc").decrypt(&jwe).is_err());
+    }
+
+    #[test]
+    fn rsa_oaep_round_trip() {
+        let pem = include_str!("../../test_keys/gift_rs256.pem");
+
+        // Only used when explicitly allowed
+        assert!(GiftEncryption::new("enc", "RSA-OAEP-256", pem, false).is_err());
+        let encryption = GiftEncryption::new("enc", "RSA-OAEP-256", pem, true).unwrap();
+
+        let jwe = encryption.encrypt(TOKEN).unwrap();
+        assert!(is_jwe(&jwe));
+        assert_eq!(encryption.decrypt(&jwe).unwrap(), TOKEN);
+
+        // A key that fails to unwrap fails just like content that fails to decrypt
+        let mut parts = jwe.split('.').map(str::to_string).collect::<Vec<_>>();
+        let mut encrypted_key = URL_SAFE_NO_PAD.decode(&parts[1]).unwrap();
+        encrypted_key[0] ^= 1;
+        let bad_key = {
+            let mut parts = parts.clone();
+            parts[1] = URL_SAFE_NO_PAD.encode(encrypted_key);
+            parts.join(".")
+        };
+
+        let mut tag = URL_SAFE_NO_PAD.decode(&parts[4]).unwrap();
+        tag[0] ^= 1;
+        parts[4] = URL_SAFE_NO_PAD.encode(tag);
+        let bad_tag = parts.join(".");
+
+        assert_eq!(
+            encryption.decrypt(&bad_key).unwrap_err(),
+            encryption.decrypt(&bad_tag).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn tampered_header_rejected() {
+        let encryption = GiftEncryption::random_direct("enc");
+        let jwe = encryption.encrypt(TOKEN).unwrap();
+
+        // The header is authenticated as additional data
+        let (_, rest) = jwe.split_once('.').unwrap();
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"dir","enc":"A256GCM"}"#);
+
+        assert!(encryption.decrypt(&format!("{header}.{rest}")).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use super::keys::{GiftKeys, StoredKey};
+use super::SECS_PER_MINUTE;
+use jsonwebtoken::{get_current_timestamp, Algorithm};
+use sqlx::{PgPool, Row};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// How often the store is checked for keys rotated by other replicas by default
+pub const DEFAULT_GIFT_KEYS_RELOAD_INTERVAL: Duration = Duration::from_secs(SECS_PER_MINUTE);
+
+/// Where signing keys generated by rotation are kept. Retired keys are purged once they
+/// expire.
+#[derive(Clone)]
+pub enum GiftKeyStore {
+    /// Keys kept in memory, lost on restart
+    Memory(Arc<Mutex<Vec<StoredKey>>>),
+
+    /// Keys kept in the `gift_keys` table, shared by every replica
+    Postgres(Arc<PgPool>),
+}
+
+impl Default for GiftKeyStore {
+    fn default() -> Self {
+        GiftKeyStore::Memory(Arc::default())
+    }
+}
+
+/// Convert a unix timestamp for binding to a postgres query
+fn timestamp(secs: u64) -> i64 {
+    i64::try_from(secs).unwrap_or(i64::MAX)
+}
+
+impl GiftKeyStore {
+    /// Store a newly generated key, retiring every other key `retention` after it was
+    /// generated. Returns false, storing nothing, if a key with the same id already exists.
+    pub async fn save(&self, key: &StoredKey, retention: Duration) -> Result<bool, sqlx::Error> {
+        let now = get_current_timestamp();
+        let retired_at = key.created_at + retention.as_secs();
+
+        match self {
+            GiftKeyStore::Memory(keys) => {
+                let mut keys = keys.lock().unwrap();
+                if keys.iter().any(|stored| stored.kid == key.kid) {
+                    return Ok(false);
+                }
+
+                for stored in keys.iter_mut() {
+                    stored.expires_at.get_or_insert(retired_at);
+                }
+
+                keys.retain(|stored| stored.expires_at.is_none_or(|expires_at| expires_at > now));
+                keys.push(key.clone());
+            }
+            GiftKeyStore::Postgres(pool) => {
+                let mut tx = pool.begin().await?;
+
+                let query = "
+                    INSERT INTO
+                        gift_keys (kid, alg, private_key, created_at)
+                    VALUES
+                        ($1, $2, $3, to_timestamp($4))
+                    ON CONFLICT (kid) DO NOTHING
+                    ";
+
+                let inserted = sqlx::query(query)
+                    .bind(&key.kid)
+                    .bind(format!("{:?}", key.alg))
+                    .bind(&key.private_key)
+                    .bind(timestamp(key.created_at))
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+
+                if inserted == 0 {
+                    return Ok(false);
+                }
+
+                sqlx::query(
+                    "UPDATE gift_keys SET expires_at = to_timestamp($2) WHERE kid <> $1 AND expires_at IS NULL",
+                )
+                .bind(&key.kid)
+                .bind(timestamp(retired_at))
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query("DELETE FROM gift_keys WHERE expires_at <= to_timestamp($1)")
+                    .bind(timestamp(now))
+                    .execute(&mut *tx)
+                    .await?;
+
+                tx.commit().await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Every key that hasn't expired. Keys with an unknown algorithm are skipped.
+    pub async fn load(&self) -> Result<Vec<StoredKey>, sqlx::Error> {
+        let now = get_current_timestamp();
+
+        match self {
+            GiftKeyStore::Memory(keys) => Ok(keys
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|key| key.expires_at.is_none_or(|expires_at| expires_at > now))
+                .cloned()
+                .collect()),
+            GiftKeyStore::Postgres(pool) => {
+                let query = "
+                    SELECT
+                        kid,
+                        alg,
+                        private_key,
+                        EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at,
+                        EXTRACT(EPOCH FROM expires_at)::BIGINT AS expires_at
+                    FROM
+                        gift_keys
+                    WHERE
+                        expires_at IS NULL OR expires_at > to_timestamp($1)
+                    ORDER BY
+                        created_at
+                    ";
+
+                let rows = sqlx::query(query)
+                    .bind(timestamp(now))
+                    .fetch_all(pool.as_ref())
+                    .await?;
+
+                let mut keys = Vec::with_capacity(rows.len());
+
+                for row in rows {
+                    let kid: String = row.try_get("kid")?;
+                    let alg: String = row.try_get("alg")?;
+                    let Ok(alg) = Algorithm::from_str(&alg) else {
+                        tracing::warn!("Skipping stored gift key {kid} with unknown alg {alg}");
+                        continue;
+                    };
+
+                    let created_at: i64 = row.try_get("created_at")?;
+                    let expires_at: Option<i64> = row.try_get("expires_at")?;
+
+                    keys.push(StoredKey {
+                        kid,
+                        alg,
+                        private_key: row.try_get("private_key")?,
+                        created_at: u64::try_from(created_at).unwrap_or_default(),
+                        expires_at: expires_at.map(|secs| u64::try_from(secs).unwrap_or_default()),
+                    });
+                }
+
+                Ok(keys)
+            }
+        }
+    }
+}
+
+/// Adopt keys rotated by other replicas, checking the store every `interval`
+pub fn spawn_gift_key_reloader(
+    keys: Arc<RwLock<GiftKeys>>,
+    store: GiftKeyStore,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            match store.load().await {
+                Ok(stored) => keys.write().unwrap().sync(&stored),
+                Err(e) => tracing::warn!("Failed to reload gift keys: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod key_store_tests {
+    use super::GiftKeyStore;
+    use crate::day6::GiftKeys;
+    use jsonwebtoken::Algorithm;
+    use std::sync::Arc;
+
+    /// A rotation on one replica is picked up by another sharing the same store
+    async fn shared_rotation(store: GiftKeyStore) {
+        let mut first = GiftKeys::random();
+        let mut second = GiftKeys::random();
+
+        let key = first.generate("rotated", Algorithm::ES256).unwrap();
+        assert!(store.save(&key, first.retention()).await.unwrap());
+        assert!(!store.save(&key, first.retention()).await.unwrap());
+        first.install(&key).unwrap();
+
+        second.sync(&store.load().await.unwrap());
+        assert_eq!(second.kid(), "rotated");
+        assert_eq!(second.alg(), Algorithm::ES256);
+
+        let kids = |keys: &GiftKeys| {
+            let mut kids = keys
+                .jwks()
+                .keys
+                .into_iter()
+                .filter_map(|jwk| jwk.common.key_id)
+                .collect::<Vec<_>>();
+            kids.sort();
+            kids
+        };
+        assert_eq!(kids(&first), kids(&second));
+        assert_eq!(kids(&second), vec!["rotated".to_string()]);
+
+        // Rotating back to an HMAC key, which isn't published
+        let key = second.generate("secret", Algorithm::HS256).unwrap();
+        assert!(store.save(&key, second.retention()).await.unwrap());
+        second.install(&key).unwrap();
+
+        first.sync(&store.load().await.unwrap());
+        assert_eq!(first.kid(), "secret");
+        assert_eq!(kids(&first), vec!["rotated".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn memory_rotation() {
+        shared_rotation(GiftKeyStore::default()).await;
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn postgres_rotation(pool: sqlx::PgPool) {
+        shared_rotation(GiftKeyStore::Postgres(Arc::new(pool))).await;
+    }
+}
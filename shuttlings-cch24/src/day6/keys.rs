@@ -0,0 +1,927 @@
+use super::jwe::{self, GiftEncryption};
+use super::SECS_PER_HOUR;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode_header, get_current_timestamp,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+        PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+};
+use rand::distributions::DistString;
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents,
+        ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED_SIGNING,
+    },
+};
+use serde::{de::DeserializeOwned, Serialize};
+use shuttle_runtime::SecretStore;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The key id used when none is configured
+const DEFAULT_KID: &str = "default";
+
+/// Key id of the encryption key when `GIFT_ENCRYPTION_KEY_ID` isn't set
+const DEFAULT_ENCRYPTION_KID: &str = "default";
+
+/// Length of the secret generated for HMAC keys
+const RANDOM_SECRET_LEN: usize = 64;
+
+/// How long a rotated out key is still accepted for verification by default
+const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * SECS_PER_HOUR);
+
+/// Errors from verifying a gift token
+#[derive(Debug)]
+pub enum TokenError {
+    /// The token's `kid` doesn't match any active key
+    UnknownKey(String),
+
+    /// The token failed to decode or validate
+    Jwt(jsonwebtoken::errors::Error),
+
+    /// The token couldn't be encrypted or decrypted
+    Encryption(String),
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::UnknownKey(kid) => write!(f, "Unknown key id: {kid}"),
+            TokenError::Jwt(e) => write!(f, "{e}"),
+            TokenError::Encryption(e) => write!(f, "Encryption failed: {e}"),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(val: jsonwebtoken::errors::Error) -> Self {
+        TokenError::Jwt(val)
+    }
+}
+
+/// A key used to verify gift tokens
+struct VerifyingKey {
+    alg: Algorithm,
+    key: DecodingKey,
+
+    /// The public form of the key, if it can be published
+    jwk: Option<Jwk>,
+
+    /// Unix timestamp after which the key is no longer accepted
+    expires_at: Option<u64>,
+}
+
+impl VerifyingKey {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A signing key generated by rotation, in the form it's persisted in so it survives
+/// restarts and is shared by every replica
+#[derive(Debug, Clone)]
+pub struct StoredKey {
+    pub kid: String,
+    pub alg: Algorithm,
+
+    /// The PEM encoded private key, or the secret itself for HMAC keys
+    pub private_key: String,
+
+    /// Unix timestamp the key was generated at
+    pub created_at: u64,
+
+    /// Unix timestamp after which the key is no longer accepted, once it's been rotated out
+    pub expires_at: Option<u64>,
+}
+
+/// The key currently used to sign new gift tokens
+struct SigningKey {
+    kid: String,
+    alg: Algorithm,
+    key: EncodingKey,
+}
+
+/// The keys used to sign and verify gift tokens. Tokens are signed with a single key and
+/// carry its `kid` in the header so verification can pick the matching key. Several keys
+/// can be active for verification at once, so tokens signed before a rotation remain valid
+/// until the old key is retired.
+pub struct GiftKeys {
+    signing: SigningKey,
+    verifying: HashMap<String, VerifyingKey>,
+
+    /// How long a key is still accepted for verification after being rotated out
+    retention: Duration,
+
+    /// Encryption applied to signed tokens, if they should be kept confidential
+    encryption: Option<GiftEncryption>,
+
+    /// Encryption keys rotated out, by `kid`, that gifts are still decrypted with
+    retired_encryption: HashMap<String, GiftEncryption>,
+}
+
+/// The outcome of checking a token's signature against a single key
+#[derive(Debug, Serialize)]
+pub struct SignatureCheck {
+    /// The key id, or another name for keys without one
+    pub key: String,
+    pub alg: Algorithm,
+
+    /// The key is no longer accepted for verification
+    pub retired: bool,
+
+    pub verified: bool,
+
+    /// Why the signature didn't verify, if it didn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SignatureCheck {
+    pub fn new(
+        key: &str,
+        alg: Algorithm,
+        retired: bool,
+        result: Result<(), jsonwebtoken::errors::Error>,
+    ) -> Self {
+        let error = result.err().map(|e| e.to_string());
+
+        Self {
+            key: key.to_string(),
+            alg,
+            retired,
+            verified: error.is_none(),
+            error,
+        }
+    }
+}
+
+/// Check only the signature of the given token, ignoring every claim
+pub fn check_signature(
+    token: &str,
+    key: &DecodingKey,
+    alg: Algorithm,
+) -> Result<(), jsonwebtoken::errors::Error> {
+    let mut validation = Validation::new(alg);
+    validation.validate_exp = false;
+    validation.validate_nbf = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+
+    jsonwebtoken::decode::<serde_json::Value>(token, key, &validation).map(|_| ())
+}
+
+/// Encode the given bytes as unpadded base64url, as used by JWKs
+fn b64(bytes: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Build the public JWK for the given key parameters
+fn public_jwk(kid: &str, alg: Algorithm, algorithm: AlgorithmParameters) -> Result<Jwk, String> {
+    let key_algorithm = KeyAlgorithm::from_str(&format!("{alg:?}"))
+        .map_err(|e| format!("Unsupported JWK algorithm {alg:?}: {e}"))?;
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm,
+    })
+}
+
+/// Parse the signing key and its matching verifying key for the given algorithm. HMAC
+/// algorithms use the private key as the shared secret, everything else expects a PEM
+/// encoded private key from which the public key is derived.
+fn parse_key_pair(
+    kid: &str,
+    alg: Algorithm,
+    private_key: &[u8],
+) -> Result<(EncodingKey, VerifyingKey), String> {
+    if matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        let verifying = VerifyingKey {
+            alg,
+            key: DecodingKey::from_secret(private_key),
+            jwk: None,
+            expires_at: None,
+        };
+
+        return Ok((EncodingKey::from_secret(private_key), verifying));
+    }
+
+    let pem = pem::parse(private_key).map_err(|e| format!("Invalid private key PEM: {e}"))?;
+    let der = pem.contents();
+    let invalid = |e| format!("Invalid private key for {alg:?}: {e}");
+
+    let (signing, key, params) = match alg {
+        Algorithm::ES256 | Algorithm::ES384 => {
+            let (signing_alg, curve) = if alg == Algorithm::ES256 {
+                (&ECDSA_P256_SHA256_FIXED_SIGNING, EllipticCurve::P256)
+            } else {
+                (&ECDSA_P384_SHA384_FIXED_SIGNING, EllipticCurve::P384)
+            };
+
+            let pair = EcdsaKeyPair::from_pkcs8(signing_alg, der, &SystemRandom::new())
+                .map_err(|e| invalid(e.to_string()))?;
+
+            // The public key is an uncompressed point: 0x04 || x || y
+            let point = &pair.public_key().as_ref()[1..];
+            let (x, y) = point.split_at(point.len() / 2);
+            let (x, y) = (b64(x), b64(y));
+
+            let params = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve,
+                x: x.clone(),
+                y: y.clone(),
+            });
+
+            let key =
+                DecodingKey::from_ec_components(&x, &y).map_err(|e| invalid(e.to_string()))?;
+
+            (EncodingKey::from_ec_der(der), key, params)
+        }
+        Algorithm::EdDSA => {
+            let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+                .map_err(|e| invalid(e.to_string()))?;
+            let x = b64(pair.public_key());
+
+            let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: x.clone(),
+            });
+
+            let key = DecodingKey::from_ed_components(&x).map_err(|e| invalid(e.to_string()))?;
+
+            (EncodingKey::from_ed_der(der), key, params)
+        }
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => {
+            let pair = if pem.tag() == "RSA PRIVATE KEY" {
+                RsaKeyPair::from_der(der)
+            } else {
+                RsaKeyPair::from_pkcs8(der)
+            }
+            .map_err(|e| invalid(e.to_string()))?;
+
+            let public = RsaPublicKeyComponents::<Vec<u8>>::from(pair.public());
+
+            let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: b64(&public.n),
+                e: b64(&public.e),
+            });
+
+            let signing =
+                EncodingKey::from_rsa_pem(private_key).map_err(|e| invalid(e.to_string()))?;
+            let key = DecodingKey::from_rsa_raw_components(&public.n, &public.e);
+
+            (signing, key, params)
+        }
+        _ => return Err(format!("Unsupported key algorithm {alg:?}")),
+    };
+
+    let verifying = VerifyingKey {
+        alg,
+        key,
+        jwk: Some(public_jwk(kid, alg, params)?),
+        expires_at: None,
+    };
+
+    Ok((signing, verifying))
+}
+
+/// Generate a new private key for the given algorithm, in the form [`parse_key_pair`] expects
+fn generate_private_key(alg: Algorithm) -> Result<Vec<u8>, String> {
+    let rng = SystemRandom::new();
+    let failed = |e| format!("Failed to generate {alg:?} key: {e}");
+
+    let pkcs8 = match alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = rand::distributions::Alphanumeric
+                .sample_string(&mut rand::thread_rng(), RANDOM_SECRET_LEN);
+
+            return Ok(secret.into_bytes());
+        }
+        Algorithm::ES256 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|e| failed(e.to_string()))?,
+        Algorithm::ES384 => EcdsaKeyPair::generate_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, &rng)
+            .map_err(|e| failed(e.to_string()))?,
+        Algorithm::EdDSA => {
+            Ed25519KeyPair::generate_pkcs8(&rng).map_err(|e| failed(e.to_string()))?
+        }
+        _ => {
+            return Err(format!(
+                "{alg:?} keys can't be generated, configure them through GIFT_KEY instead"
+            ))
+        }
+    };
+
+    let pem = pem::Pem::new("PRIVATE KEY", pkcs8.as_ref());
+
+    Ok(pem::encode(&pem).into_bytes())
+}
+
+impl GiftKeys {
+    /// Create the keys from the given private key
+    pub fn new(kid: &str, alg: Algorithm, private_key: &[u8]) -> Result<Self, String> {
+        let (key, verifying) = parse_key_pair(kid, alg, private_key)?;
+
+        Ok(Self {
+            signing: SigningKey {
+                kid: kid.to_string(),
+                alg,
+                key,
+            },
+            verifying: HashMap::from([(kid.to_string(), verifying)]),
+            retention: DEFAULT_RETENTION,
+            encryption: None,
+            retired_encryption: HashMap::new(),
+        })
+    }
+
+    /// Create HS256 keys from a freshly generated secret
+    pub fn random() -> Self {
+        let secret = generate_private_key(Algorithm::HS256).expect("HS256 keys are always valid");
+
+        Self::new(DEFAULT_KID, Algorithm::HS256, &secret).expect("HS256 keys are always valid")
+    }
+
+    /// Load the keys from the `GIFT_KEY*` secrets. Additional verification keys can be given
+    /// as a JWK set in `GIFT_JWKS`. Tokens are encrypted when `GIFT_ENCRYPTION` is set to
+    /// `dir` or `RSA-OAEP-256`, using the key in `GIFT_ENCRYPTION_KEY` identified by
+    /// `GIFT_ENCRYPTION_KEY_ID`. Keys rotated out can be given as a JSON object of key ids
+    /// to keys in `GIFT_ENCRYPTION_RETIRED_KEYS`. `RSA-OAEP-256` also needs
+    /// `GIFT_ENCRYPTION_ALLOW_RSA` set to `true`, as it's affected by RUSTSEC-2023-0071.
+    ///
+    /// `GIFT_KEY` is required unless running `local`ly, where a random secret is used
+    /// instead. Tokens signed with a random secret stop verifying on restart and aren't
+    /// accepted by other replicas.
+    pub fn from_secrets(secrets: &SecretStore, local: bool) -> Result<Self, String> {
+        let kid = secrets
+            .get("GIFT_KEY_ID")
+            .unwrap_or_else(|| DEFAULT_KID.to_string());
+
+        let alg = match secrets.get("GIFT_KEY_ALG") {
+            Some(alg) => {
+                Algorithm::from_str(&alg).map_err(|e| format!("Invalid GIFT_KEY_ALG: {e}"))?
+            }
+            None => Algorithm::HS256,
+        };
+
+        let mut keys = match secrets.get("GIFT_KEY") {
+            Some(private_key) => Self::new(&kid, alg, private_key.as_bytes())?,
+            None if local => {
+                tracing::warn!("GIFT_KEY not set, signing gifts with a random secret");
+                Self::random()
+            }
+            None => return Err("GIFT_KEY is required outside of local development".to_string()),
+        };
+
+        if let Some(retention) = secrets.get("GIFT_KEY_RETENTION_SECS") {
+            let retention = retention
+                .parse()
+                .map_err(|e| format!("Invalid GIFT_KEY_RETENTION_SECS: {e}"))?;
+
+            keys.retention = Duration::from_secs(retention);
+        }
+
+        if let Some(alg) = secrets.get("GIFT_ENCRYPTION") {
+            let Some(key) = secrets.get("GIFT_ENCRYPTION_KEY") else {
+                return Err(format!("GIFT_ENCRYPTION_KEY is required for {alg}"));
+            };
+
+            let kid = secrets
+                .get("GIFT_ENCRYPTION_KEY_ID")
+                .unwrap_or_else(|| DEFAULT_ENCRYPTION_KID.to_string());
+
+            let allow_rsa = match secrets.get("GIFT_ENCRYPTION_ALLOW_RSA") {
+                Some(allow) => allow
+                    .parse()
+                    .map_err(|e| format!("Invalid GIFT_ENCRYPTION_ALLOW_RSA: {e}"))?,
+                None => false,
+            };
+
+            keys.encryption = Some(GiftEncryption::new(&kid, &alg, &key, allow_rsa)?);
+
+            if let Some(retired) = secrets.get("GIFT_ENCRYPTION_RETIRED_KEYS") {
+                let retired: HashMap<String, String> = serde_json::from_str(&retired)
+                    .map_err(|e| format!("Invalid GIFT_ENCRYPTION_RETIRED_KEYS: {e}"))?;
+
+                for (kid, key) in retired {
+                    let encryption = GiftEncryption::new(&kid, &alg, &key, allow_rsa)
+                        .map_err(|e| format!("Invalid retired encryption key {kid}: {e}"))?;
+
+                    keys.retired_encryption.insert(kid, encryption);
+                }
+            }
+        }
+
+        if let Some(jwks) = secrets.get("GIFT_JWKS") {
+            let jwks: JwkSet =
+                serde_json::from_str(&jwks).map_err(|e| format!("Invalid GIFT_JWKS: {e}"))?;
+
+            for jwk in jwks.keys {
+                keys.add_jwk(jwk)?;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Accept tokens signed by the given public key. The JWK must carry both a `kid` and
+    /// an `alg`.
+    pub fn add_jwk(&mut self, jwk: Jwk) -> Result<(), String> {
+        let Some(kid) = jwk.common.key_id.clone() else {
+            return Err("JWK is missing a kid".to_string());
+        };
+
+        let Some(alg) = jwk.common.key_algorithm else {
+            return Err(format!("JWK {kid} is missing an alg"));
+        };
+
+        // Every verifying key given as a JWK is published, which would leak a shared secret
+        if let AlgorithmParameters::OctetKey(_) = jwk.algorithm {
+            return Err(format!("JWK {kid} is not a public key"));
+        }
+
+        let alg = Algorithm::from_str(&alg.to_string())
+            .map_err(|e| format!("JWK {kid} has an unsupported alg: {e}"))?;
+
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| format!("Invalid JWK {kid}: {e}"))?;
+
+        let verifying = VerifyingKey {
+            alg,
+            key,
+            jwk: Some(jwk),
+            expires_at: None,
+        };
+
+        self.verifying.insert(kid, verifying);
+
+        Ok(())
+    }
+
+    /// The id of the key new tokens are signed with
+    pub fn kid(&self) -> &str {
+        &self.signing.kid
+    }
+
+    /// The algorithm new tokens are signed with
+    pub fn alg(&self) -> Algorithm {
+        self.signing.alg
+    }
+
+    /// Make sure rotated out keys are kept for at least `min`, so tokens they signed stay
+    /// verifiable for their whole lifetime
+    pub fn extend_retention(&mut self, min: Duration) {
+        self.retention = self.retention.max(min);
+    }
+
+    /// How long a key is still accepted for verification after being rotated out
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Generate a new key to rotate to with [`GiftKeys::install`]
+    pub fn generate(&self, kid: &str, alg: Algorithm) -> Result<StoredKey, String> {
+        if self.verifying.contains_key(kid) {
+            return Err(format!("Key {kid} already exists"));
+        }
+
+        let private_key = String::from_utf8(generate_private_key(alg)?)
+            .map_err(|e| format!("Generated {alg:?} key isn't text: {e}"))?;
+
+        Ok(StoredKey {
+            kid: kid.to_string(),
+            alg,
+            private_key,
+            created_at: get_current_timestamp(),
+            expires_at: None,
+        })
+    }
+
+    /// Replace the signing key with the given one. The old key stays valid for verification
+    /// for the configured retention period after the new key was generated.
+    pub fn install(&mut self, key: &StoredKey) -> Result<(), String> {
+        let (signing, verifying) = parse_key_pair(&key.kid, key.alg, key.private_key.as_bytes())?;
+
+        if let Some(old) = self.verifying.get_mut(&self.signing.kid) {
+            old.expires_at = Some(key.created_at + self.retention.as_secs());
+        }
+
+        self.verifying
+            .retain(|_, key| !key.is_expired(get_current_timestamp()));
+        self.verifying.insert(key.kid.clone(), verifying);
+        self.signing = SigningKey {
+            kid: key.kid.clone(),
+            alg: key.alg,
+            key: signing,
+        };
+
+        Ok(())
+    }
+
+    /// Replace the signing key with a freshly generated one, only kept in memory
+    pub fn rotate(&mut self, kid: &str, alg: Algorithm) -> Result<(), String> {
+        let key = self.generate(kid, alg)?;
+        self.install(&key)
+    }
+
+    /// Bring the keys in line with those persisted by rotations, possibly made by another
+    /// replica. The newest key that hasn't been rotated out signs new tokens, and every
+    /// other key is retired. Keys that can't be parsed are skipped.
+    pub fn sync(&mut self, stored: &[StoredKey]) {
+        let now = get_current_timestamp();
+
+        let newest = stored
+            .iter()
+            .filter(|key| key.expires_at.is_none())
+            .max_by_key(|key| (key.created_at, &key.kid));
+
+        if let Some(newest) = newest {
+            if newest.kid != self.signing.kid {
+                if let Err(e) = self.install(newest) {
+                    tracing::warn!("Skipping stored gift key {}: {e}", newest.kid);
+                }
+            }
+        }
+
+        for key in stored {
+            // Stored keys can only have been retired by a newer key, or by having been
+            // rotated out
+            let expires_at = match (key.expires_at, newest) {
+                (Some(expires_at), _) => Some(expires_at),
+                (None, Some(newest)) if newest.kid != key.kid => {
+                    Some(newest.created_at + self.retention.as_secs())
+                }
+                (None, _) => None,
+            };
+
+            if expires_at.is_some_and(|expires_at| expires_at <= now) {
+                continue;
+            }
+
+            if let Some(existing) = self.verifying.get_mut(&key.kid) {
+                existing.expires_at = expires_at;
+                continue;
+            }
+
+            match parse_key_pair(&key.kid, key.alg, key.private_key.as_bytes()) {
+                Ok((_, verifying)) => {
+                    self.verifying.insert(
+                        key.kid.clone(),
+                        VerifyingKey {
+                            expires_at,
+                            ..verifying
+                        },
+                    );
+                }
+                Err(e) => tracing::warn!("Skipping stored gift key {}: {e}", key.kid),
+            }
+        }
+
+        self.verifying.retain(|_, key| !key.is_expired(now));
+    }
+
+    /// The public keys currently accepted for verification. HMAC keys are never published.
+    pub fn jwks(&self) -> JwkSet {
+        let now = get_current_timestamp();
+
+        let mut keys = self
+            .verifying
+            .values()
+            .filter(|key| !key.is_expired(now))
+            .filter_map(|key| key.jwk.clone())
+            .collect::<Vec<_>>();
+
+        // Keep the output stable regardless of map order
+        keys.sort_by(|x, y| x.common.key_id.cmp(&y.common.key_id));
+
+        JwkSet { keys }
+    }
+
+    /// Sign the given claims with the current signing key
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.signing.alg);
+        header.kid = Some(self.signing.kid.clone());
+
+        jsonwebtoken::encode(&header, claims, &self.signing.key)
+    }
+
+    /// Encrypt all tokens produced by [`GiftKeys::encode`] with the given encryption. The
+    /// previous key is still used to decrypt gifts encrypted with it.
+    pub fn set_encryption(&mut self, encryption: GiftEncryption) {
+        if let Some(old) = self.encryption.replace(encryption) {
+            self.retired_encryption.insert(old.kid().to_string(), old);
+        }
+    }
+
+    /// Sign the given claims, then encrypt the result if encryption is configured
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, TokenError> {
+        let token = self.sign(claims)?;
+
+        match &self.encryption {
+            Some(encryption) => encryption.encrypt(&token).map_err(TokenError::Encryption),
+            None => Ok(token),
+        }
+    }
+
+    /// Decrypt the given token if it's a JWE, returning the signed token inside. Plain
+    /// signed tokens are returned as is.
+    pub fn decrypt<'a>(&self, token: &'a str) -> Result<Cow<'a, str>, TokenError> {
+        if !jwe::is_jwe(token) {
+            return Ok(Cow::Borrowed(token));
+        }
+
+        let Some(current) = &self.encryption else {
+            return Err(TokenError::Encryption(
+                "Encrypted gifts aren't enabled".to_string(),
+            ));
+        };
+
+        let encryption = match jwe::kid(token) {
+            Some(kid) if kid == current.kid() => current,
+            Some(kid) => self
+                .retired_encryption
+                .get(&kid)
+                .ok_or_else(|| TokenError::Encryption(format!("Unknown encryption key {kid}")))?,
+            // Gifts encrypted before keys had ids
+            None => current,
+        };
+
+        let token = encryption.decrypt(token).map_err(TokenError::Encryption)?;
+
+        Ok(Cow::Owned(token))
+    }
+
+    /// Decrypt the given token if it's a JWE, then verify it. Plain signed tokens are
+    /// accepted either way.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: Validation,
+    ) -> Result<TokenData<T>, TokenError> {
+        self.verify(&self.decrypt(token)?, validation)
+    }
+
+    /// Check the signature of the given signed token against every known key, regardless
+    /// of its `kid` or claims
+    pub fn check_signatures(&self, token: &str) -> Vec<SignatureCheck> {
+        let now = get_current_timestamp();
+
+        let mut checks = self
+            .verifying
+            .iter()
+            .map(|(kid, key)| {
+                let result = check_signature(token, &key.key, key.alg);
+                SignatureCheck::new(kid, key.alg, key.is_expired(now), result)
+            })
+            .collect::<Vec<_>>();
+
+        // Keep the output stable regardless of map order
+        checks.sort_by(|x, y| x.key.cmp(&y.key));
+
+        checks
+    }
+
+    /// Verify the given token against the key named by its `kid`. Tokens without a `kid`
+    /// are verified against the current signing key. Only the algorithm of the chosen key
+    /// is accepted, regardless of what `validation` allows.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        mut validation: Validation,
+    ) -> Result<TokenData<T>, TokenError> {
+        let header = decode_header(token)?;
+        let kid = header.kid.as_deref().unwrap_or(&self.signing.kid);
+
+        let key = self
+            .verifying
+            .get(kid)
+            .filter(|key| !key.is_expired(get_current_timestamp()))
+            .ok_or_else(|| TokenError::UnknownKey(kid.to_string()))?;
+
+        validation.algorithms = vec![key.alg];
+
+        Ok(jsonwebtoken::decode(token, &key.key, &validation)?)
+    }
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use super::{GiftKeys, TokenError};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+    use serde_json::json;
+    use shuttle_runtime::SecretStore;
+    use std::collections::BTreeMap;
+
+    /// Sign a token with the given keys and verify it round trips
+    fn round_trip(keys: &GiftKeys) {
+        let claims = json!({"exp": 0xdead_beef_u64, "data": "present"});
+        let token = keys.sign(&claims).unwrap();
+
+        let header = decode_header(&token).unwrap();
+        assert_eq!(header.kid.as_deref(), Some(keys.kid()));
+
+        let data = keys
+            .verify::<serde_json::Value>(&token, Validation::default())
+            .unwrap();
+        assert_eq!(data.claims, claims);
+
+        // Tokens must also verify against the published public key
+        if let Some(jwk) = keys.jwks().find(keys.kid()) {
+            let key = DecodingKey::from_jwk(jwk).unwrap();
+            let validation = Validation::new(keys.alg());
+            jsonwebtoken::decode::<serde_json::Value>(&token, &key, &validation).unwrap();
+        }
+    }
+
+    #[test]
+    fn hs256_round_trip() {
+        let keys = GiftKeys::random();
+        round_trip(&keys);
+
+        // Shared secrets are never published
+        assert!(keys.jwks().keys.is_empty());
+    }
+
+    #[test]
+    fn rs256_round_trip() {
+        let keys = GiftKeys::new(
+            "rsa",
+            Algorithm::RS256,
+            include_bytes!("../../test_keys/gift_rs256.pem"),
+        )
+        .unwrap();
+
+        round_trip(&keys);
+    }
+
+    #[test]
+    fn es256_round_trip() {
+        let keys = GiftKeys::new(
+            "ec",
+            Algorithm::ES256,
+            include_bytes!("../../test_keys/gift_es256.pem"),
+        )
+        .unwrap();
+
+        round_trip(&keys);
+    }
+
+    #[test]
+    fn eddsa_round_trip() {
+        let keys = GiftKeys::new(
+            "ed",
+            Algorithm::EdDSA,
+            include_bytes!("../../test_keys/gift_eddsa.pem"),
+        )
+        .unwrap();
+
+        round_trip(&keys);
+    }
+
+    #[test]
+    fn mismatched_algorithm_rejected() {
+        let keys = GiftKeys::new(
+            "ec",
+            Algorithm::EdDSA,
+            include_bytes!("../../test_keys/gift_es256.pem"),
+        );
+
+        assert!(keys.is_err());
+    }
+
+    #[test]
+    fn unknown_kid_rejected() {
+        let keys = GiftKeys::random();
+        let other = GiftKeys::new("other", Algorithm::HS256, b"othersecret").unwrap();
+
+        let token = other.sign(&json!({"exp": 0xdead_beef_u64})).unwrap();
+        let err = keys
+            .verify::<serde_json::Value>(&token, Validation::default())
+            .unwrap_err();
+
+        assert!(matches!(err, TokenError::UnknownKey(kid) if kid == "other"));
+    }
+
+    #[test]
+    fn rotation_keeps_old_tokens_valid() {
+        let mut keys = GiftKeys::random();
+        let claims = json!({"exp": 0xdead_beef_u64});
+        let old_token = keys.sign(&claims).unwrap();
+
+        keys.rotate("ed-2024", Algorithm::EdDSA).unwrap();
+        assert_eq!(keys.kid(), "ed-2024");
+        round_trip(&keys);
+
+        keys.verify::<serde_json::Value>(&old_token, Validation::default())
+            .unwrap();
+
+        // Retired keys are no longer accepted once their retention has passed
+        keys.verifying.get_mut("default").unwrap().expires_at = Some(0);
+        keys.retention = std::time::Duration::ZERO;
+        keys.rotate("ec-2024", Algorithm::ES256).unwrap();
+        keys.rotate("ec-2025", Algorithm::ES256).unwrap();
+
+        let err = keys
+            .verify::<serde_json::Value>(&old_token, Validation::default())
+            .unwrap_err();
+        assert!(matches!(err, TokenError::UnknownKey(_)));
+
+        let jwks = serde_json::to_string(&keys.jwks()).unwrap();
+        let jwks: JwkSet = serde_json::from_str(&jwks).unwrap();
+        assert!(jwks.find("ec-2025").is_some());
+        assert!(jwks.find("ed-2024").is_none());
+    }
+
+    #[test]
+    fn rsa_keys_cannot_be_generated() {
+        let mut keys = GiftKeys::random();
+        assert!(keys.rotate("rsa", Algorithm::RS256).is_err());
+        assert_eq!(keys.kid(), "default");
+    }
+
+    #[test]
+    fn gift_key_required_outside_local() {
+        let secrets = SecretStore::new(BTreeMap::new());
+        assert!(GiftKeys::from_secrets(&secrets, false).is_err());
+        assert!(GiftKeys::from_secrets(&secrets, true).is_ok());
+
+        let secrets = SecretStore::new(BTreeMap::from([(
+            "GIFT_KEY".to_string(),
+            "configured".to_string().into(),
+        )]));
+        assert!(GiftKeys::from_secrets(&secrets, false).is_ok());
+    }
+
+    #[test]
+    fn encryption_secrets() {
+        let keys = |secrets: &[(&str, &str)]| {
+            let secrets = secrets
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string().into()))
+                .collect();
+
+            GiftKeys::from_secrets(&SecretStore::new(secrets), true)
+        };
+
+        // Gifts are never encrypted with a random key
+        assert!(keys(&[("GIFT_ENCRYPTION", "dir")]).is_err());
+
+        let key = URL_SAFE_NO_PAD.encode([7; 32]);
+        assert!(keys(&[("GIFT_ENCRYPTION", "dir"), ("GIFT_ENCRYPTION_KEY", &key)]).is_ok());
+
+        let pem = include_str!("../../test_keys/gift_rs256.pem");
+        let rsa = [
+            ("GIFT_ENCRYPTION", "RSA-OAEP-256"),
+            ("GIFT_ENCRYPTION_KEY", pem),
+        ];
+        assert!(keys(&rsa).is_err());
+        assert!(keys(&[rsa[0], rsa[1], ("GIFT_ENCRYPTION_ALLOW_RSA", "true")]).is_ok());
+    }
+
+    #[test]
+    fn symmetric_jwks_rejected() {
+        let keys = |jwks: serde_json::Value| {
+            let secrets = BTreeMap::from([("GIFT_JWKS".to_string(), jwks.to_string().into())]);
+            GiftKeys::from_secrets(&SecretStore::new(secrets), true)
+        };
+
+        let secret = json!({
+            "kty": "oct",
+            "kid": "shared",
+            "alg": "HS256",
+            "k": URL_SAFE_NO_PAD.encode(b"supersecret"),
+        });
+        assert!(keys(json!({"keys": [secret]})).is_err());
+
+        let ec = GiftKeys::new(
+            "ec",
+            Algorithm::ES256,
+            include_bytes!("../../test_keys/gift_es256.pem"),
+        )
+        .unwrap();
+        let public = serde_json::to_value(ec.jwks().find("ec").unwrap()).unwrap();
+
+        let jwks = keys(json!({"keys": [public]})).unwrap().jwks();
+        assert!(jwks.find("ec").is_some());
+        assert!(jwks.find("shared").is_none());
+    }
+}
@@ -0,0 +1,405 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::get_current_timestamp;
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Number of random bytes in a refresh token
+const TOKEN_LEN: usize = 32;
+
+/// Errors from redeeming a refresh token
+#[derive(Debug)]
+pub enum RefreshError {
+    /// The token was never issued, or its family has been revoked
+    Invalid,
+
+    /// The token is past its expiry
+    Expired,
+
+    /// The token was already used, so its whole family has been revoked. The gifts issued
+    /// in the family still need revoking.
+    Reused(Vec<IssuedGift>),
+
+    Database(sqlx::Error),
+}
+
+impl Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::Invalid => write!(f, "Invalid refresh token"),
+            RefreshError::Expired => write!(f, "Refresh token has expired"),
+            RefreshError::Reused(_) => write!(f, "Refresh token reuse detected"),
+            RefreshError::Database(e) => write!(f, "Failed to refresh: {e:?}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(val: sqlx::Error) -> Self {
+        RefreshError::Database(val)
+    }
+}
+
+/// A gift token issued alongside a refresh token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IssuedGift {
+    pub jti: Uuid,
+
+    /// When the gift stops being accepted, and so no longer needs revoking
+    pub expires_at: u64,
+}
+
+/// A refresh token that was successfully redeemed
+pub struct Redeemed {
+    /// The family the replacement token must join
+    pub family: Uuid,
+
+    /// The gift data the token was issued for
+    pub data: serde_json::Value,
+}
+
+/// A refresh token as kept in memory
+pub struct MemoryEntry {
+    family: Uuid,
+    gift: IssuedGift,
+    data: serde_json::Value,
+    expires_at: u64,
+    used: bool,
+}
+
+/// Where refresh tokens are kept. Only a hash of each token is stored. Every token issued
+/// by refreshing another joins its family, so reuse of a rotated out token can revoke
+/// every token descended from the same `wrap`.
+#[derive(Clone)]
+pub enum RefreshStore {
+    /// Refresh tokens kept in memory, lost on restart
+    Memory(Arc<Mutex<HashMap<Vec<u8>, MemoryEntry>>>),
+
+    /// Refresh tokens kept in the `gift_refresh_tokens` table
+    Postgres(Arc<PgPool>),
+}
+
+impl Default for RefreshStore {
+    fn default() -> Self {
+        RefreshStore::Memory(Arc::default())
+    }
+}
+
+/// The hash a refresh token is stored under
+fn hash(token: &str) -> Vec<u8> {
+    digest(&SHA256, token.as_bytes()).as_ref().to_vec()
+}
+
+/// Convert a unix timestamp for binding to a postgres query
+fn timestamp(secs: u64) -> i64 {
+    i64::try_from(secs).unwrap_or(i64::MAX)
+}
+
+/// Take every token in `family` out of the in memory store, returning the gifts issued
+/// with them
+fn remove_family(tokens: &mut HashMap<Vec<u8>, MemoryEntry>, family: Uuid) -> Vec<IssuedGift> {
+    let mut gifts = Vec::new();
+
+    tokens.retain(|_, entry| {
+        if entry.family != family {
+            return true;
+        }
+
+        gifts.push(entry.gift);
+        false
+    });
+
+    gifts
+}
+
+/// Delete every token in the family matching `condition`, returning the gifts issued with
+/// them. Tokens issued before gifts were recorded are deleted without one.
+fn delete_family(condition: &str) -> String {
+    format!(
+        "
+        WITH deleted AS (
+            DELETE FROM
+                gift_refresh_tokens
+            WHERE
+                family = {condition}
+            RETURNING
+                jti, jti_expires_at
+        )
+        SELECT
+            jti, EXTRACT(EPOCH FROM jti_expires_at)::BIGINT
+        FROM
+            deleted
+        WHERE
+            jti IS NOT NULL
+        "
+    )
+}
+
+/// Convert the rows returned by a [`delete_family`] query
+fn issued_gifts(rows: Vec<(Uuid, i64)>) -> Vec<IssuedGift> {
+    rows.into_iter()
+        .map(|(jti, expires_at)| IssuedGift {
+            jti,
+            expires_at: u64::try_from(expires_at).unwrap_or_default(),
+        })
+        .collect()
+}
+
+impl RefreshStore {
+    /// Issue a new refresh token for `data` in the given family, valid until `expires_at`,
+    /// recording the gift issued along with it
+    pub async fn issue(
+        &self,
+        family: Uuid,
+        gift: IssuedGift,
+        data: &serde_json::Value,
+        expires_at: u64,
+    ) -> Result<String, sqlx::Error> {
+        let mut token = [0; TOKEN_LEN];
+        SystemRandom::new()
+            .fill(&mut token)
+            .expect("Failed to generate refresh token");
+        let token = URL_SAFE_NO_PAD.encode(token);
+
+        let now = get_current_timestamp();
+
+        match self {
+            RefreshStore::Memory(tokens) => {
+                let mut tokens = tokens.lock().unwrap();
+                tokens.retain(|_, entry| entry.expires_at > now);
+                tokens.insert(
+                    hash(&token),
+                    MemoryEntry {
+                        family,
+                        gift,
+                        data: data.clone(),
+                        expires_at,
+                        used: false,
+                    },
+                );
+            }
+            RefreshStore::Postgres(pool) => {
+                sqlx::query("DELETE FROM gift_refresh_tokens WHERE expires_at <= to_timestamp($1)")
+                    .bind(timestamp(now))
+                    .execute(pool.as_ref())
+                    .await?;
+
+                let query = "
+                    INSERT INTO
+                        gift_refresh_tokens (hash, family, jti, jti_expires_at, data, expires_at)
+                    VALUES
+                        ($1, $2, $3, to_timestamp($4), $5, to_timestamp($6))
+                    ";
+
+                sqlx::query(query)
+                    .bind(hash(&token))
+                    .bind(family)
+                    .bind(gift.jti)
+                    .bind(timestamp(gift.expires_at))
+                    .bind(data.to_string())
+                    .bind(timestamp(expires_at))
+                    .execute(pool.as_ref())
+                    .await?;
+            }
+        }
+
+        Ok(token)
+    }
+
+    /// Redeem the given refresh token, marking it used. Redeeming a token twice revokes
+    /// its whole family, returning the gifts issued in it.
+    pub async fn redeem(&self, token: &str) -> Result<Redeemed, RefreshError> {
+        let hash = hash(token);
+        let now = get_current_timestamp();
+
+        match self {
+            RefreshStore::Memory(tokens) => {
+                let mut tokens = tokens.lock().unwrap();
+
+                let entry = tokens.get_mut(&hash).ok_or(RefreshError::Invalid)?;
+
+                if entry.used {
+                    let family = entry.family;
+                    return Err(RefreshError::Reused(remove_family(&mut tokens, family)));
+                }
+
+                if entry.expires_at <= now {
+                    return Err(RefreshError::Expired);
+                }
+
+                entry.used = true;
+
+                Ok(Redeemed {
+                    family: entry.family,
+                    data: entry.data.clone(),
+                })
+            }
+            RefreshStore::Postgres(pool) => {
+                let mut tx = pool.begin().await?;
+
+                let query = "
+                    SELECT
+                        family, data, used, expires_at <= to_timestamp($2)
+                    FROM
+                        gift_refresh_tokens
+                    WHERE
+                        hash = $1
+                    FOR UPDATE
+                    ";
+
+                let (family, data, used, expired): (Uuid, String, bool, bool) =
+                    sqlx::query_as(query)
+                        .bind(&hash)
+                        .bind(timestamp(now))
+                        .fetch_optional(&mut *tx)
+                        .await?
+                        .ok_or(RefreshError::Invalid)?;
+
+                if used {
+                    let gifts = sqlx::query_as(&delete_family("$1"))
+                        .bind(family)
+                        .fetch_all(&mut *tx)
+                        .await?;
+                    tx.commit().await?;
+
+                    return Err(RefreshError::Reused(issued_gifts(gifts)));
+                }
+
+                if expired {
+                    return Err(RefreshError::Expired);
+                }
+
+                sqlx::query("UPDATE gift_refresh_tokens SET used = TRUE WHERE hash = $1")
+                    .bind(&hash)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+
+                let data = serde_json::from_str(&data)
+                    .map_err(|e| RefreshError::Database(sqlx::Error::Decode(e.into())))?;
+
+                Ok(Redeemed { family, data })
+            }
+        }
+    }
+
+    /// Revoke every token in the family of the given refresh token, returning the gifts
+    /// issued in it
+    pub async fn revoke(&self, token: &str) -> Result<Vec<IssuedGift>, sqlx::Error> {
+        let hash = hash(token);
+
+        match self {
+            RefreshStore::Memory(tokens) => {
+                let mut tokens = tokens.lock().unwrap();
+
+                match tokens.get(&hash).map(|entry| entry.family) {
+                    Some(family) => Ok(remove_family(&mut tokens, family)),
+                    None => Ok(Vec::new()),
+                }
+            }
+            RefreshStore::Postgres(pool) => {
+                let query =
+                    delete_family("(SELECT family FROM gift_refresh_tokens WHERE hash = $1)");
+
+                let gifts = sqlx::query_as(&query)
+                    .bind(&hash)
+                    .fetch_all(pool.as_ref())
+                    .await?;
+
+                Ok(issued_gifts(gifts))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod refresh_tests {
+    use super::{IssuedGift, RefreshError, RefreshStore};
+    use jsonwebtoken::get_current_timestamp;
+    use serde_json::json;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn gift() -> IssuedGift {
+        IssuedGift {
+            jti: Uuid::new_v4(),
+            expires_at: get_current_timestamp() + 60,
+        }
+    }
+
+    async fn reuse_revokes_family(store: RefreshStore) {
+        let expires_at = get_current_timestamp() + 60;
+        let family = Uuid::new_v4();
+
+        let (first_gift, second_gift) = (gift(), gift());
+
+        let first = store
+            .issue(family, first_gift, &json!(1), expires_at)
+            .await
+            .unwrap();
+        let redeemed = store.redeem(&first).await.unwrap();
+        assert_eq!(redeemed.family, family);
+        assert_eq!(redeemed.data, json!(1));
+
+        let second = store
+            .issue(family, second_gift, &json!(1), expires_at)
+            .await
+            .unwrap();
+
+        // An unrelated family is unaffected by the reuse below
+        let other = store
+            .issue(Uuid::new_v4(), gift(), &json!(2), expires_at)
+            .await
+            .unwrap();
+
+        // Every gift in the family is returned for revoking
+        let Err(RefreshError::Reused(mut gifts)) = store.redeem(&first).await else {
+            panic!("reuse wasn't detected");
+        };
+        gifts.sort_by_key(|gift| gift.jti);
+        let mut expected = vec![first_gift, second_gift];
+        expected.sort_by_key(|gift| gift.jti);
+        assert_eq!(gifts, expected);
+
+        assert!(matches!(
+            store.redeem(&second).await,
+            Err(RefreshError::Invalid)
+        ));
+        assert!(store.redeem(&other).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn memory_reuse_revokes_family() {
+        reuse_revokes_family(RefreshStore::default()).await;
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn postgres_reuse_revokes_family(pool: sqlx::PgPool) {
+        reuse_revokes_family(RefreshStore::Postgres(Arc::new(pool))).await;
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_rejected() {
+        let store = RefreshStore::default();
+        let token = store
+            .issue(Uuid::new_v4(), gift(), &json!(1), get_current_timestamp())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            store.redeem(&token).await,
+            Err(RefreshError::Expired)
+        ));
+        assert!(matches!(
+            store.redeem("made up").await,
+            Err(RefreshError::Invalid)
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+use jsonwebtoken::get_current_timestamp;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Where revoked gift token ids are kept. Entries are only needed until the token they
+/// revoke would have expired anyway, after which they are purged.
+#[derive(Clone)]
+pub enum RevocationStore {
+    /// Revocations kept in memory, lost on restart
+    Memory(Arc<Mutex<HashMap<Uuid, u64>>>),
+
+    /// Revocations kept in the `gift_revocations` table
+    Postgres(Arc<PgPool>),
+}
+
+impl Default for RevocationStore {
+    fn default() -> Self {
+        RevocationStore::Memory(Arc::default())
+    }
+}
+
+/// Convert a unix timestamp for binding to a postgres query
+fn timestamp(secs: u64) -> i64 {
+    i64::try_from(secs).unwrap_or(i64::MAX)
+}
+
+impl RevocationStore {
+    /// Revoke the token with the given `jti` until `expires_at`, purging any revocations
+    /// that are no longer needed
+    pub async fn revoke(&self, jti: Uuid, expires_at: u64) -> Result<(), sqlx::Error> {
+        let now = get_current_timestamp();
+
+        match self {
+            RevocationStore::Memory(revoked) => {
+                let mut revoked = revoked.lock().unwrap();
+                revoked.retain(|_, expires_at| *expires_at > now);
+                revoked.insert(jti, expires_at);
+            }
+            RevocationStore::Postgres(pool) => {
+                sqlx::query("DELETE FROM gift_revocations WHERE expires_at <= to_timestamp($1)")
+                    .bind(timestamp(now))
+                    .execute(pool.as_ref())
+                    .await?;
+
+                let query = "
+                    INSERT INTO
+                        gift_revocations (jti, expires_at)
+                    VALUES
+                        ($1, to_timestamp($2))
+                    ON CONFLICT (jti) DO NOTHING
+                    ";
+
+                sqlx::query(query)
+                    .bind(jti)
+                    .bind(timestamp(expires_at))
+                    .execute(pool.as_ref())
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if the token with the given `jti` has been revoked
+    pub async fn is_revoked(&self, jti: Uuid) -> Result<bool, sqlx::Error> {
+        match self {
+            RevocationStore::Memory(revoked) => Ok(revoked.lock().unwrap().contains_key(&jti)),
+            RevocationStore::Postgres(pool) => {
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM gift_revocations WHERE jti = $1)")
+                    .bind(jti)
+                    .fetch_one(pool.as_ref())
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod revocation_tests {
+    use super::RevocationStore;
+    use jsonwebtoken::get_current_timestamp;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn expired_revocations_are_purged() {
+        let store = RevocationStore::default();
+        let now = get_current_timestamp();
+
+        let stale = Uuid::new_v4();
+        let fresh = Uuid::new_v4();
+
+        store.revoke(stale, now - 1).await.unwrap();
+        assert!(store.is_revoked(stale).await.unwrap());
+
+        store.revoke(fresh, now + 60).await.unwrap();
+        assert!(store.is_revoked(fresh).await.unwrap());
+        assert!(!store.is_revoked(stale).await.unwrap());
+    }
+}
@@ -0,0 +1,458 @@
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey,
+};
+use rsa::pkcs8::spki::{ObjectIdentifier, SubjectPublicKeyInfoRef};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the key files are checked for changes by default
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Name of the key compiled into the binary
+const EMBEDDED_KEY: &str = "day16_santa_public_key.pem";
+
+/// OID of the P-256 curve, as named in EC public keys
+const P256_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+
+/// OID of the P-384 curve
+const P384_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.34");
+
+/// The curve of an EC key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcCurve {
+    P256,
+    P384,
+}
+
+/// The type of a verification key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    Ec(EcCurve),
+    Ed,
+    Rsa,
+}
+
+impl KeyKind {
+    /// The signature algorithms that can be verified with keys of this kind
+    pub fn algorithms(self) -> &'static [Algorithm] {
+        match self {
+            KeyKind::Ec(EcCurve::P256) => &[Algorithm::ES256],
+            KeyKind::Ec(EcCurve::P384) => &[Algorithm::ES384],
+            KeyKind::Ed => &[Algorithm::EdDSA],
+            KeyKind::Rsa => &[
+                Algorithm::RS256,
+                Algorithm::RS384,
+                Algorithm::RS512,
+                Algorithm::PS256,
+                Algorithm::PS384,
+                Algorithm::PS512,
+            ],
+        }
+    }
+}
+
+/// One of Santa's public keys
+pub struct SantaKey {
+    /// The `kid` of JWKs, or the file name of PEM keys
+    pub name: String,
+    pub kind: KeyKind,
+    pub key: DecodingKey,
+
+    /// The algorithms tokens verified with this key may use
+    pub algorithms: Vec<Algorithm>,
+}
+
+impl SantaKey {
+    /// Whether tokens signed with `alg` may be verified with this key
+    pub fn allows(&self, alg: Algorithm) -> bool {
+        self.algorithms.contains(&alg)
+    }
+}
+
+/// The public keys used to verify tokens sent to `/16/decode`, either compiled in or
+/// loaded from a directory of PEM files or a JWKS file
+pub struct SantaKeys {
+    pub keys: Vec<SantaKey>,
+
+    /// Where the keys were loaded from, if not compiled in
+    source: Option<PathBuf>,
+
+    /// Modification times of the loaded files, to detect changes
+    fingerprint: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+/// The curve named in an EC public key PEM
+fn ec_curve(pem: &[u8]) -> Option<EcCurve> {
+    let pem = pem::parse(pem).ok()?;
+    let spki = SubjectPublicKeyInfoRef::try_from(pem.contents()).ok()?;
+
+    match spki.algorithm.parameters_oid().ok()? {
+        P256_OID => Some(EcCurve::P256),
+        P384_OID => Some(EcCurve::P384),
+        _ => None,
+    }
+}
+
+/// Parse a public key PEM of any supported type
+fn parse_pem(name: &str, pem: &[u8]) -> Result<SantaKey, String> {
+    let (kind, key) = if let Ok(key) = DecodingKey::from_ec_pem(pem) {
+        let curve = ec_curve(pem).ok_or_else(|| format!("{name} uses an unsupported curve"))?;
+        (KeyKind::Ec(curve), key)
+    } else if let Ok(key) = DecodingKey::from_ed_pem(pem) {
+        (KeyKind::Ed, key)
+    } else if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
+        (KeyKind::Rsa, key)
+    } else {
+        return Err(format!("{name} is not an EC, Ed or RSA public key"));
+    };
+
+    Ok(SantaKey {
+        name: name.to_string(),
+        kind,
+        key,
+        algorithms: kind.algorithms().to_vec(),
+    })
+}
+
+/// Parse a public JWK. Shared secrets aren't accepted as Santa's keys.
+fn parse_jwk(index: usize, jwk: &Jwk) -> Result<SantaKey, String> {
+    let name = jwk
+        .common
+        .key_id
+        .clone()
+        .unwrap_or_else(|| format!("jwk{index}"));
+
+    let kind = match &jwk.algorithm {
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => KeyKind::Ec(EcCurve::P256),
+            EllipticCurve::P384 => KeyKind::Ec(EcCurve::P384),
+            _ => return Err(format!("{name} uses an unsupported curve")),
+        },
+        AlgorithmParameters::OctetKeyPair(_) => KeyKind::Ed,
+        AlgorithmParameters::RSA(_) => KeyKind::Rsa,
+        AlgorithmParameters::OctetKey(_) => return Err(format!("{name} is not a public key")),
+    };
+
+    // A JWK naming its algorithm is only accepted for that one
+    let algorithms = match jwk.common.key_algorithm {
+        Some(alg) => {
+            let alg = Algorithm::from_str(&alg.to_string())
+                .ok()
+                .filter(|alg| kind.algorithms().contains(alg))
+                .ok_or_else(|| format!("{name} can't be used to verify {alg} signatures"))?;
+
+            vec![alg]
+        }
+        None => kind.algorithms().to_vec(),
+    };
+
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid key {name}: {e}"))?;
+
+    Ok(SantaKey {
+        name,
+        kind,
+        key,
+        algorithms,
+    })
+}
+
+/// The files keys are loaded from: every `.pem` and `.json` file of a directory, or the
+/// file itself
+fn key_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let entries =
+        fs::read_dir(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    let mut files = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "pem" || ext == "json")
+        })
+        .collect::<Vec<_>>();
+
+    files.sort();
+
+    Ok(files)
+}
+
+/// The current modification times of the given files
+fn fingerprint(files: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    files
+        .iter()
+        .map(|file| {
+            let modified = fs::metadata(file).and_then(|meta| meta.modified()).ok();
+            (file.clone(), modified)
+        })
+        .collect()
+}
+
+impl SantaKeys {
+    /// The key compiled into the binary
+    pub fn embedded() -> Self {
+        let pem = include_bytes!("../../day16_santa_public_key.pem");
+        let key = parse_pem(EMBEDDED_KEY, pem).expect("Invalid public key from santa");
+
+        Self {
+            keys: vec![key],
+            source: None,
+            fingerprint: Vec::new(),
+        }
+    }
+
+    /// Load the keys from the given path, which is either a directory of PEM and JWKS files
+    /// or a single PEM or JWKS (`.json`) file. Invalid keys are logged and skipped, but
+    /// finding no valid key at all is an error.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let files = key_files(path)?;
+        let fingerprint = fingerprint(&files);
+
+        let mut keys = Vec::new();
+
+        for file in &files {
+            let name = file
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+
+            let contents = match fs::read(file) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    tracing::warn!("Skipping Santa key {}: {e}", file.display());
+                    continue;
+                }
+            };
+
+            if file.extension().is_some_and(|ext| ext == "json") {
+                let jwks: JwkSet = match serde_json::from_slice(&contents) {
+                    Ok(jwks) => jwks,
+                    Err(e) => {
+                        tracing::warn!("Skipping Santa JWKS {}: {e}", file.display());
+                        continue;
+                    }
+                };
+
+                for (index, jwk) in jwks.keys.iter().enumerate() {
+                    match parse_jwk(index, jwk) {
+                        Ok(key) => keys.push(key),
+                        Err(e) => tracing::warn!("Skipping Santa key in {}: {e}", file.display()),
+                    }
+                }
+            } else {
+                match parse_pem(&name, &contents) {
+                    Ok(key) => keys.push(key),
+                    Err(e) => tracing::warn!("Skipping Santa key {}: {e}", file.display()),
+                }
+            }
+        }
+
+        if keys.is_empty() {
+            return Err(format!("No valid Santa keys found in {}", path.display()));
+        }
+
+        Ok(Self {
+            keys,
+            source: Some(path.to_path_buf()),
+            fingerprint,
+        })
+    }
+
+    /// Load the keys from the given path, falling back to the key compiled into the binary
+    /// if none can be loaded. The path is still watched, so keys added later are picked up.
+    pub fn load_or_embedded(path: &Path) -> Self {
+        Self::load(path).unwrap_or_else(|e| {
+            tracing::warn!("Failed to load Santa keys, using the built in key: {e}");
+
+            Self {
+                source: Some(path.to_path_buf()),
+                fingerprint: key_files(path)
+                    .map_or_else(|_| Vec::new(), |files| fingerprint(&files)),
+                ..Self::embedded()
+            }
+        })
+    }
+
+    /// Whether the key files changed since they were loaded
+    fn changed(&self) -> bool {
+        let Some(source) = &self.source else {
+            return false;
+        };
+
+        key_files(source).is_ok_and(|files| fingerprint(&files) != self.fingerprint)
+    }
+}
+
+/// Spawn the background task reloading the keys whenever their files change. Keys compiled
+/// into the binary are never reloaded. If the files no longer hold a valid key, the keys
+/// loaded before are kept.
+pub fn spawn_santa_reloader(keys: Arc<RwLock<SantaKeys>>, interval: Duration) {
+    let Some(source) = keys.read().unwrap().source.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            // Checking and reading the files blocks
+            let reloaded = tokio::task::spawn_blocking({
+                let keys = keys.clone();
+                let source = source.clone();
+
+                move || {
+                    keys.read()
+                        .unwrap()
+                        .changed()
+                        .then(|| SantaKeys::load(&source))
+                }
+            })
+            .await;
+
+            match reloaded {
+                Ok(Some(Ok(reloaded))) => {
+                    tracing::info!(
+                        "Reloaded {} Santa keys from {}",
+                        reloaded.keys.len(),
+                        source.display()
+                    );
+                    *keys.write().unwrap() = reloaded;
+                }
+                Ok(Some(Err(e))) => {
+                    tracing::warn!("Failed to reload Santa keys, keeping the current ones: {e}");
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to reload Santa keys: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod santa_tests {
+    use super::{EcCurve, KeyKind, SantaKeys};
+    use jsonwebtoken::Algorithm;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A fresh directory for a test to write keys to
+    fn key_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("santa_keys_{test}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn invalid_keys_are_skipped() {
+        let dir = key_dir("skip");
+        fs::write(
+            dir.join("santa.pem"),
+            include_str!("../../day16_santa_public_key.pem"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("elf.pem"),
+            include_str!("../../test_keys/gift_es256.pub.pem"),
+        )
+        .unwrap();
+        fs::write(dir.join("grinch.pem"), "not a key").unwrap();
+        fs::write(dir.join("notes.txt"), "not a pem file").unwrap();
+        fs::write(
+            dir.join("reindeer.json"),
+            r#"{"keys": [{"kty": "OKP", "crv": "Ed25519", "kid": "rudolph",
+                "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"}]}"#,
+        )
+        .unwrap();
+
+        let keys = SantaKeys::load(&dir).unwrap();
+        let loaded = keys
+            .keys
+            .iter()
+            .map(|key| (key.name.as_str(), key.kind))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            loaded,
+            vec![
+                ("elf.pem", KeyKind::Ec(EcCurve::P256)),
+                ("rudolph", KeyKind::Ed),
+                ("santa.pem", KeyKind::Rsa)
+            ]
+        );
+
+        // EC keys are only used with the algorithm for their curve
+        assert_eq!(keys.keys[0].algorithms, vec![Algorithm::ES256]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn jwks_file() {
+        let dir = key_dir("jwks");
+        let path = dir.join("jwks.json");
+        let jwks = r#"{"keys": [
+            {"kty": "OKP", "crv": "Ed25519", "kid": "ed", "alg": "EdDSA",
+             "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"},
+            {"kty": "OKP", "crv": "Ed25519", "kid": "confused", "alg": "HS256",
+             "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"},
+            {"kty": "EC", "crv": "P-256", "kid": "wrong curve", "alg": "ES384",
+             "x": "AA", "y": "AA"},
+            {"kty": "oct", "kid": "secret", "k": "c2VjcmV0"}
+        ]}"#;
+        fs::write(&path, jwks).unwrap();
+
+        let keys = SantaKeys::load(&path).unwrap();
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(keys.keys[0].name, "ed");
+        assert_eq!(keys.keys[0].kind, KeyKind::Ed);
+        assert_eq!(keys.keys[0].algorithms, vec![Algorithm::EdDSA]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn no_keys_is_an_error() {
+        let dir = key_dir("none");
+        fs::write(dir.join("grinch.pem"), "not a key").unwrap();
+
+        assert!(SantaKeys::load(&dir).is_err());
+        assert!(SantaKeys::load(&dir.join("missing")).is_err());
+
+        // Falling back to the built in key
+        let keys = SantaKeys::load_or_embedded(&dir.join("missing"));
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(keys.keys[0].name, "day16_santa_public_key.pem");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn detects_changes() {
+        let dir = key_dir("changes");
+        let keys = SantaKeys::load_or_embedded(&dir);
+        assert_eq!(keys.keys.len(), 1);
+        assert!(!keys.changed());
+
+        fs::write(
+            dir.join("santa.pem"),
+            include_str!("../../day16_santa_public_key.pem"),
+        )
+        .unwrap();
+        assert!(keys.changed());
+
+        let keys = SantaKeys::load(&dir).unwrap();
+        assert_eq!(keys.keys.len(), 1);
+        assert!(!keys.changed());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,21 +1,135 @@
+use crate::admin::Admin;
 use axum::{
     body::Bytes,
     extract::{Extension, Path, Query},
-    http::StatusCode,
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
 };
 use chrono::{offset::Utc, DateTime};
+use futures_util::stream::{self, Stream};
 use rand::{distributions::DistString, rngs::SmallRng, SeedableRng};
-use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use sqlx::{postgres::PgListener, FromRow, PgExecutor, PgPool};
+use std::collections::VecDeque;
+use std::fmt::Write;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
 use uuid::Uuid;
+use v_htmlescape::escape;
 
 const PAGE_SIZE: i32 = 3;
 
+/// How often scheduled drafts are checked for publishing
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The channel notified by the `quote_changes` trigger whenever a quote changes
+const CHANGES_CHANNEL: &str = "quote_changes";
+
+/// How long to wait before reconnecting the change listener after it fails
+const LISTENER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many wake ups can be queued for a `/19/changes` subscriber. Any more are merged, as
+/// each one only prompts the subscriber to read the latest changes.
+const WAKE_CAPACITY: usize = 16;
+
+const SECS_PER_HOUR: u64 = 60 * 60;
+
+/// How long changes are kept for subscribers to resume from
+const CHANGES_RETENTION: Duration = Duration::from_secs(7 * 24 * SECS_PER_HOUR);
+
+/// How often changes older than [`CHANGES_RETENTION`] are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(SECS_PER_HOUR);
+
+/// The collection used by the routes that don't name one explicitly
+const DEFAULT_COLLECTION: &str = "default";
+
+/// The actions routed directly under `/19/`. Collections can't be named after them, as
+/// their routes would shadow the collection's.
+const RESERVED_COLLECTIONS: [&str; 8] = [
+    "reset", "draft", "cite", "remove", "undo", "list", "batch", "changes",
+];
+
+fn default_collection() -> String {
+    DEFAULT_COLLECTION.to_string()
+}
+
+/// Deserialize a collection name, rejecting [`RESERVED_COLLECTIONS`]
+fn collection_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let collection = String::deserialize(deserializer)?;
+
+    if RESERVED_COLLECTIONS.contains(&collection.as_str()) {
+        return Err(D::Error::custom(format!(
+            "{collection:?} is reserved and can't be used as a collection"
+        )));
+    }
+
+    Ok(collection)
+}
+
+/// Path params for routes scoped to a collection. Falls back to [`DEFAULT_COLLECTION`] for
+/// the unscoped `/19/...` routes.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CollectionParams {
+    #[serde(default = "default_collection", deserialize_with = "collection_name")]
+    collection: String,
+}
+
+/// Path params for routes addressing a single quote in a collection
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuoteParams {
+    #[serde(default = "default_collection", deserialize_with = "collection_name")]
+    collection: String,
+    id: Uuid,
+}
+
+/// The lifecycle of a quote. Only published quotes are visible to anonymous callers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+enum QuoteStatus {
+    Draft,
+    Published,
+    Archived,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct DraftParams {
     author: String,
     quote: String,
+
+    #[serde(default)]
+    status: Option<QuoteStatus>,
+
+    /// `Some(None)` when given as null, which clears the publish time of an existing quote
+    #[serde(default, deserialize_with = "present")]
+    #[allow(clippy::option_option)]
+    publish_at: Option<Option<DateTime<Utc>>>,
+}
+
+/// Deserialize a field that's present as `Some`, even if it's null, so that a null can be
+/// told apart from a missing field
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl DraftParams {
+    /// The status of a newly drafted quote. Quotes are published immediately unless they
+    /// are scheduled for later.
+    fn initial_status(&self) -> QuoteStatus {
+        match (self.status, self.publish_at.flatten()) {
+            (Some(status), _) => status,
+            (None, Some(_)) => QuoteStatus::Draft,
+            (None, None) => QuoteStatus::Published,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -30,11 +144,39 @@ struct Pagination {
     next_token: Option<String>,
 }
 
-#[derive(Serialize, Debug, Clone, FromRow)]
-struct TokenRow {
-    id: String,
-    page: i32,
-    prev_id: String,
+/// A single operation submitted to [`batch`]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Create {
+        #[serde(flatten)]
+        params: DraftParams,
+    },
+    Update {
+        id: Uuid,
+
+        #[serde(flatten)]
+        params: DraftParams,
+    },
+    Delete {
+        id: Uuid,
+    },
+}
+
+impl BatchOp {
+    fn name(&self) -> &'static str {
+        match self {
+            BatchOp::Create { .. } => "create",
+            BatchOp::Update { .. } => "update",
+            BatchOp::Delete { .. } => "delete",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct BatchResult {
+    op: &'static str,
+    quote: Quote,
 }
 
 #[derive(Serialize, Debug, Clone, FromRow)]
@@ -45,11 +187,141 @@ struct Quote {
     quote: String,
     created_at: DateTime<Utc>,
     version: i32,
+    status: QuoteStatus,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    publish_at: Option<DateTime<Utc>>,
+}
+
+/// Path params for `cite`, where the id may carry a `.html`, `.svg` or `.json` extension
+#[derive(Deserialize, Debug, Clone)]
+pub struct CiteParams {
+    #[serde(default = "default_collection", deserialize_with = "collection_name")]
+    collection: String,
+    id: String,
+}
+
+/// The formats a quote can be rendered as by `cite`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CiteFormat {
+    Json,
+    Html,
+    Svg,
+}
+
+/// Width of the rendered SVG card in pixels
+const SVG_WIDTH: usize = 600;
+
+/// Padding around the text of the rendered SVG card in pixels
+const SVG_PADDING: usize = 40;
+
+/// Height of each line of text in the rendered SVG card in pixels
+const SVG_LINE_HEIGHT: usize = 28;
+
+/// Maximum number of characters on a single line of the rendered SVG card
+const SVG_LINE_CHARS: usize = 48;
+
+/// A row from the `quote_changes` table, populated by trigger on every change to `quotes`.
+/// Changes are sent in the order they were committed, with their position as the event id.
+#[derive(Debug, Clone, FromRow)]
+struct QuoteChange {
+    position: i64,
+    op: String,
+    payload: String,
+}
+
+impl From<QuoteChange> for Event {
+    fn from(val: QuoteChange) -> Self {
+        Event::default()
+            .id(val.position.to_string())
+            .event(val.op)
+            .data(val.payload)
+    }
+}
+
+/// Wakes every `/19/changes` subscriber whenever a quote changes. A single listener
+/// connection is shared by all of them, rather than each holding one from the pool.
+#[derive(Clone)]
+pub struct ChangeNotifier(broadcast::Sender<()>);
+
+impl ChangeNotifier {
+    /// Spawn the task listening for changes, reconnecting whenever the listener fails
+    pub fn spawn(pool: Arc<PgPool>) -> Self {
+        let (sender, _) = broadcast::channel(WAKE_CAPACITY);
+        let notifier = Self(sender.clone());
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = listen_for_changes(&pool, &sender).await {
+                    tracing::warn!("Quote change listener failed, reconnecting: {e}");
+                }
+
+                tokio::time::sleep(LISTENER_RETRY_INTERVAL).await;
+            }
+        });
+
+        notifier
+    }
+}
+
+/// Wake every subscriber on each notification until the listener fails
+async fn listen_for_changes(
+    pool: &PgPool,
+    sender: &broadcast::Sender<()>,
+) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANGES_CHANNEL).await?;
+
+    loop {
+        // Subscribers are woken whenever the listener (re)connects as well, to catch up on any
+        // changes made while it wasn't listening. It's fine if there are none.
+        let _ = sender.send(());
+
+        // `None` when the connection was lost and has been re-established
+        listener.try_recv().await?;
+    }
+}
+
+/// The state of a single `/19/changes` subscriber
+struct ChangeFeed {
+    wake: broadcast::Receiver<()>,
+    pool: Arc<PgPool>,
+    collection: String,
+    all: bool,
+    last_position: i64,
+    pending: VecDeque<QuoteChange>,
+}
+
+impl ChangeFeed {
+    /// Wait for the next change in the collection after the last one sent, or `None` once
+    /// changes are no longer being listened for
+    async fn next(&mut self) -> Result<Option<Event>, sqlx::Error> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                self.last_position = change.position;
+                return Ok(Some(change.into()));
+            }
+
+            // Wake ups carry nothing, the changes themselves are always read from the table.
+            // Missed wake ups are merged into one.
+            match self.wake.recv().await {
+                Ok(()) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(None),
+            }
+
+            let changes =
+                get_changes_after(&self.pool, &self.collection, self.last_position, self.all)
+                    .await?;
+            self.pending.extend(changes);
+        }
+    }
 }
 
 impl From<DraftParams> for Quote {
     fn from(val: DraftParams) -> Self {
         Self {
+            status: val.initial_status(),
+            publish_at: val.publish_at.flatten(),
             author: val.author,
             quote: val.quote,
             id: Uuid::new_v4(),
@@ -59,8 +331,134 @@ impl From<DraftParams> for Quote {
     }
 }
 
-pub async fn reset(Extension(pool): Extension<Arc<PgPool>>) -> Result<(), (StatusCode, String)> {
-    sqlx::query("DELETE FROM quotes")
+/// Insert a new quote from the given params
+async fn insert_quote<'e>(
+    executor: impl PgExecutor<'e>,
+    collection: &str,
+    params: DraftParams,
+) -> Result<Quote, (StatusCode, String)> {
+    let status = params.initial_status();
+    let DraftParams {
+        author,
+        quote,
+        publish_at,
+        ..
+    } = params;
+    let id = Uuid::new_v4();
+    let version = 1;
+
+    let query = "
+        INSERT INTO quotes (id, author, quote, version, collection_id, status, publish_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, author, quote, created_at, version, status, publish_at
+    ";
+
+    sqlx::query_as::<_, Quote>(query)
+        .bind(id)
+        .bind(author)
+        .bind(quote)
+        .bind(version)
+        .bind(collection)
+        .bind(status)
+        .bind(publish_at.flatten())
+        .fetch_one(executor)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to insert quote: {e:?}"),
+            )
+        })
+}
+
+/// Replace the author and quote of the given quote, bumping its version. The status and
+/// publish time are only changed when given, with a null publish time clearing it. Only
+/// published quotes can be updated unless `all` is set.
+async fn update_quote<'e>(
+    executor: impl PgExecutor<'e>,
+    collection: &str,
+    id: Uuid,
+    params: DraftParams,
+    all: bool,
+) -> Result<Quote, (StatusCode, String)> {
+    let query = "
+        UPDATE
+            quotes
+        SET
+            author = $1,
+            quote = $2,
+            version = version + 1,
+            status = COALESCE($5, status),
+            publish_at = CASE WHEN $6 THEN $7 ELSE publish_at END
+        WHERE
+            id = $3 AND collection_id = $4 AND ($8 OR status = 'published')
+        RETURNING 
+            id, author, quote, created_at, version, status, publish_at
+        ";
+
+    let DraftParams {
+        author,
+        quote,
+        status,
+        publish_at,
+    } = params;
+
+    sqlx::query_as::<_, Quote>(query)
+        .bind(author)
+        .bind(quote)
+        .bind(id)
+        .bind(collection)
+        .bind(status)
+        .bind(publish_at.is_some())
+        .bind(publish_at.flatten())
+        .bind(all)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("Failed to update {id:?}: {e:?}"),
+            )
+        })
+}
+
+/// Delete the given quote, returning the removed row. Only published quotes can be deleted
+/// unless `all` is set.
+async fn delete_quote<'e>(
+    executor: impl PgExecutor<'e>,
+    collection: &str,
+    id: Uuid,
+    all: bool,
+) -> Result<Quote, (StatusCode, String)> {
+    let query = "
+        DELETE FROM 
+            quotes 
+        WHERE
+            id = $1 AND collection_id = $2 AND ($3 OR status = 'published')
+        RETURNING 
+            id, author, quote, created_at, version, status, publish_at
+        ";
+
+    sqlx::query_as::<_, Quote>(query)
+        .bind(id)
+        .bind(collection)
+        .bind(all)
+        .fetch_one(executor)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("Failed to delete {id:?}: {e:?}"),
+            )
+        })
+}
+
+pub async fn reset(
+    Extension(pool): Extension<Arc<PgPool>>,
+    Path(CollectionParams { collection }): Path<CollectionParams>,
+) -> Result<(), (StatusCode, String)> {
+    sqlx::query("DELETE FROM quotes WHERE collection_id = $1")
+        .bind(collection)
         .execute(pool.as_ref())
         .await
         .map_err(|e| {
@@ -75,6 +473,7 @@ pub async fn reset(Extension(pool): Extension<Arc<PgPool>>) -> Result<(), (Statu
 
 pub async fn draft(
     Extension(pool): Extension<Arc<PgPool>>,
+    Path(CollectionParams { collection }): Path<CollectionParams>,
     body: Bytes,
 ) -> Result<(StatusCode, String), (StatusCode, String)> {
     let params: DraftParams = serde_json::from_slice(&body).map_err(|e| {
@@ -84,30 +483,7 @@ pub async fn draft(
         )
     })?;
 
-    let DraftParams { author, quote } = params;
-    let id = Uuid::new_v4();
-    let version = 1;
-
-    let query = "
-        INSERT INTO quotes (id, author, quote, version)
-        VALUES ($1, $2, $3, $4)
-        RETURNING id, author, quote, created_at, version
-    ";
-
-    // Insert the new row
-    let quote = sqlx::query_as::<_, Quote>(query)
-        .bind(id)
-        .bind(author)
-        .bind(quote)
-        .bind(version)
-        .fetch_one(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                format!("Failed to insert quote: {e:?}"),
-            )
-        })?;
+    let quote = insert_quote(pool.as_ref(), &collection, params).await?;
 
     Ok((
         StatusCode::CREATED,
@@ -115,17 +491,146 @@ pub async fn draft(
     ))
 }
 
+/// Split the requested id into the quote id and the format to render it as
+fn parse_cite_id(id: &str) -> Result<(Uuid, CiteFormat), (StatusCode, String)> {
+    let (id, format) = match id.rsplit_once('.') {
+        None => (id, CiteFormat::Json),
+        Some((id, "json")) => (id, CiteFormat::Json),
+        Some((id, "html")) => (id, CiteFormat::Html),
+        Some((id, "svg")) => (id, CiteFormat::Svg),
+        Some((_, ext)) => {
+            return Err((StatusCode::NOT_FOUND, format!("Unknown format: {ext}")));
+        }
+    };
+
+    let id = Uuid::parse_str(id)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid id {id:?}: {e:?}")))?;
+
+    Ok((id, format))
+}
+
+/// Greedily wrap the given text into lines of at most `width` characters. Words longer
+/// than a line are split across lines.
+fn wrap_words(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut line_len = 0;
+
+    for word in text.split_whitespace() {
+        let mut word = word.chars().collect::<Vec<_>>();
+
+        // Start a new line if this word doesn't fit on the current one
+        if line_len > 0 && line_len + 1 + word.len() > width {
+            lines.push(std::mem::take(&mut line));
+            line_len = 0;
+        }
+
+        // Split words that can't fit on a line by themselves
+        while word.len() > width {
+            let rest = word.split_off(width);
+            lines.push(word.into_iter().collect());
+            word = rest;
+        }
+
+        if line_len > 0 {
+            line.push(' ');
+            line_len += 1;
+        }
+
+        line.extend(word.iter());
+        line_len += word.len();
+    }
+
+    if line_len > 0 {
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Render the quote as a shareable HTML card
+fn render_html(quote: &Quote) -> String {
+    let author = escape(&quote.author);
+    let text = escape(&quote.quote);
+    let date = quote.created_at.format("%B %-d, %Y");
+
+    format!(
+        r#"<html>
+    <head>
+        <meta charset="utf-8">
+        <title>{author}</title>
+        <style>
+body {{
+    background-color: #0d0d0d;
+    color: #eee;
+}}
+main {{
+    max-width: 600px;
+    margin: auto;
+    margin-top: 100px;
+}}
+blockquote {{
+    border-left: 4px solid #a00;
+    padding-left: 20px;
+    font-size: 1.5em;
+}}
+        </style>
+    </head>
+    <body>
+        <main>
+            <figure>
+                <blockquote>{text}</blockquote>
+                <figcaption>&mdash; {author}, <time datetime="{created_at}">{date}</time></figcaption>
+            </figure>
+        </main>
+    </body>
+</html>"#,
+        created_at = quote.created_at.to_rfc3339(),
+    )
+}
+
+/// Render the quote as an SVG card with the text wrapped to fit
+fn render_svg(quote: &Quote) -> String {
+    let lines = wrap_words(&quote.quote, SVG_LINE_CHARS);
+
+    // Leave an extra two lines of space for the attribution
+    let height = SVG_PADDING * 2 + (lines.len() + 2) * SVG_LINE_HEIGHT;
+    let author_x = SVG_WIDTH - SVG_PADDING;
+    let author_y = height - SVG_PADDING;
+
+    let mut tspans = String::new();
+    for line in &lines {
+        let _ = write!(
+            tspans,
+            r#"<tspan x="{SVG_PADDING}" dy="{SVG_LINE_HEIGHT}">{}</tspan>"#,
+            escape(line)
+        );
+    }
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{SVG_WIDTH}" height="{height}" viewBox="0 0 {SVG_WIDTH} {height}">
+    <rect width="100%" height="100%" rx="16" fill="#0d0d0d"/>
+    <text y="{SVG_PADDING}" font-family="Georgia, serif" font-size="20" fill="#eee">{tspans}</text>
+    <text x="{author_x}" y="{author_y}" text-anchor="end" font-family="Georgia, serif" font-size="16" fill="#a00">&#8212; {author}</text>
+</svg>"##,
+        author = escape(&quote.author),
+    )
+}
+
 pub async fn cite(
     Extension(pool): Extension<Arc<PgPool>>,
-    Path(id): Path<Uuid>,
-) -> Result<String, (StatusCode, String)> {
+    Path(CiteParams { collection, id }): Path<CiteParams>,
+    admin: Option<Admin>,
+) -> Result<Response, (StatusCode, String)> {
+    let (id, format) = parse_cite_id(&id)?;
+
     let query = "
         SELECT 
             * 
         FROM 
             quotes 
         WHERE
-            id = $1
+            id = $1 AND collection_id = $2 AND ($3 OR status = 'published')
         LIMIT
             1
         ";
@@ -133,50 +638,53 @@ pub async fn cite(
     // Insert the new row
     let quote = sqlx::query_as::<_, Quote>(query)
         .bind(id)
+        .bind(collection)
+        .bind(admin.is_some())
         .fetch_one(pool.as_ref())
         .await
         .map_err(|e| (StatusCode::NOT_FOUND, format!("ID not found {id:?}: {e:?}")))?;
 
-    Ok(serde_json::to_string_pretty(&quote).unwrap())
+    let resp = match format {
+        CiteFormat::Json => serde_json::to_string_pretty(&quote)
+            .unwrap()
+            .into_response(),
+        CiteFormat::Html => Html(render_html(&quote)).into_response(),
+        CiteFormat::Svg => ([(CONTENT_TYPE, "image/svg+xml")], render_svg(&quote)).into_response(),
+    };
+
+    Ok(resp)
 }
 
 pub async fn remove(
     Extension(pool): Extension<Arc<PgPool>>,
-    Path(id): Path<Uuid>,
+    Path(QuoteParams { collection, id }): Path<QuoteParams>,
+    admin: Option<Admin>,
 ) -> Result<String, (StatusCode, String)> {
-    let query = "
-        DELETE FROM 
-            quotes 
-        WHERE
-            id = $1
-        RETURNING 
-            id, author, quote, created_at, version
-        ";
-
-    // Insert the new row
-    let quote = sqlx::query_as::<_, Quote>(query)
-        .bind(id)
-        .fetch_one(pool.as_ref())
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::NOT_FOUND,
-                format!("Failed to delete {id:?}: {e:?}"),
-            )
-        })?;
+    // Only admins can remove drafts and archived quotes
+    let quote = delete_quote(pool.as_ref(), &collection, id, admin.is_some()).await?;
 
     Ok(serde_json::to_string_pretty(&quote).unwrap())
 }
 
-async fn get_num_quotes(pool: &PgPool) -> Result<i32, (StatusCode, String)> {
+/// Get the number of quotes in the collection. Only published quotes are counted unless
+/// `all` is set.
+async fn get_num_quotes(
+    pool: &PgPool,
+    collection: &str,
+    all: bool,
+) -> Result<i32, (StatusCode, String)> {
     let query = "
         SELECT 
             COUNT(*)
         FROM
             quotes 
+        WHERE
+            collection_id = $1 AND ($2 OR status = 'published')
         ";
 
     let rows: (i64,) = sqlx::query_as(query)
+        .bind(collection)
+        .bind(all)
         .fetch_one(pool)
         .await
         .map_err(|e| (StatusCode::NOT_FOUND, format!("Failed to list: {e:?}")))?;
@@ -187,6 +695,7 @@ async fn get_num_quotes(pool: &PgPool) -> Result<i32, (StatusCode, String)> {
 /// Update the next page for the given token
 async fn update_token_page(
     pool: &PgPool,
+    collection: &str,
     token: Option<String>,
 ) -> Result<String, (StatusCode, String)> {
     let mut rng = SmallRng::from_entropy();
@@ -194,9 +703,9 @@ async fn update_token_page(
 
     let query = "
         INSERT INTO 
-            pages (id, page)
+            pages (id, page, collection_id)
         VALUES 
-            ($1, 1)
+            ($1, 1, $2)
         ON
             CONFLICT (id)
         DO UPDATE SET
@@ -205,6 +714,7 @@ async fn update_token_page(
 
     sqlx::query(query)
         .bind(&token)
+        .bind(collection)
         .execute(pool)
         .await
         .map_err(|e| {
@@ -218,18 +728,23 @@ async fn update_token_page(
 }
 
 /// Get the next page of quotes for the given token
-async fn get_page_from_token(pool: &PgPool, token: &str) -> Result<i32, (StatusCode, String)> {
+async fn get_page_from_token(
+    pool: &PgPool,
+    collection: &str,
+    token: &str,
+) -> Result<i32, (StatusCode, String)> {
     let query = "
         SELECT
             page
         FROM
             pages
         where
-            id = $1
+            id = $1 AND collection_id = $2
         ";
 
     let page: (i32,) = sqlx::query_as(query)
         .bind(token)
+        .bind(collection)
         .fetch_one(pool)
         .await
         .map_err(|e| (StatusCode::BAD_REQUEST, format!("Token not found: {e:?}")))?;
@@ -237,10 +752,13 @@ async fn get_page_from_token(pool: &PgPool, token: &str) -> Result<i32, (StatusC
     Ok(page.0)
 }
 
-/// Get the next page of quotes for the given offset
+/// Get the next page of quotes for the given offset. Only published quotes are returned
+/// unless `all` is set.
 async fn get_quotes_by_offset(
     pool: &PgPool,
+    collection: &str,
     offset: i32,
+    all: bool,
 ) -> Result<Vec<Quote>, (StatusCode, String)> {
     let query = format!(
         "
@@ -248,6 +766,8 @@ async fn get_quotes_by_offset(
             *
         FROM
             quotes 
+        WHERE
+            collection_id = $2 AND ($3 OR status = 'published')
         ORDER BY 
             created_at ASC
         LIMIT
@@ -259,6 +779,8 @@ async fn get_quotes_by_offset(
 
     let quotes = sqlx::query_as(&query)
         .bind(offset)
+        .bind(collection)
+        .bind(all)
         .fetch_all(pool)
         .await
         .map_err(|e| (StatusCode::NOT_FOUND, format!("Failed to list: {e:?}")))?;
@@ -268,12 +790,17 @@ async fn get_quotes_by_offset(
 
 pub async fn list(
     Extension(pool): Extension<Arc<PgPool>>,
+    Path(CollectionParams { collection }): Path<CollectionParams>,
     Query(ListParams { token }): Query<ListParams>,
+    admin: Option<Admin>,
 ) -> Result<String, (StatusCode, String)> {
+    // Admins can see drafts and archived quotes as well
+    let all = admin.is_some();
+
     // Get the current page for the the given token
     let (page, token) = if let Some(token) = token {
         (
-            get_page_from_token(pool.as_ref(), &token).await?,
+            get_page_from_token(pool.as_ref(), &collection, &token).await?,
             Some(token),
         )
     } else {
@@ -282,17 +809,17 @@ pub async fn list(
 
     let offset = page * PAGE_SIZE;
 
-    let rows = get_num_quotes(pool.as_ref()).await?;
+    let rows = get_num_quotes(pool.as_ref(), &collection, all).await?;
 
     let next_page = page + 1;
     let next_token = if rows > next_page * PAGE_SIZE {
-        Some(update_token_page(&pool, token).await?)
+        Some(update_token_page(&pool, &collection, token).await?)
     } else {
         None
     };
 
     let resp = Pagination {
-        quotes: get_quotes_by_offset(&pool, offset).await?,
+        quotes: get_quotes_by_offset(&pool, &collection, offset, all).await?,
         page: next_page,
         next_token,
     };
@@ -300,44 +827,615 @@ pub async fn list(
     Ok(serde_json::to_string_pretty(&resp).unwrap())
 }
 
-pub async fn undo(
-    Extension(pool): Extension<Arc<PgPool>>,
-    Path(id): Path<Uuid>,
-    body: Bytes,
-) -> Result<String, (StatusCode, String)> {
-    let params = serde_json::from_slice(&body).map_err(|e| {
+/// Publish every draft whose scheduled publish time has arrived
+async fn publish_scheduled(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let query = "
+        UPDATE
+            quotes
+        SET
+            status = 'published'
+        WHERE
+            status = 'draft' AND publish_at <= CURRENT_TIMESTAMP
+        ";
+
+    let result = sqlx::query(query).execute(pool).await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Spawn the background task that publishes scheduled drafts once their time arrives
+pub fn spawn_publisher(pool: Arc<PgPool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = publish_scheduled(&pool).await {
+                tracing::warn!("Failed to publish scheduled quotes: {e:?}");
+            }
+        }
+    });
+}
+
+/// Get every change in the collection after the given position. Only changes to published
+/// quotes are returned unless `all` is set.
+async fn get_changes_after(
+    pool: &PgPool,
+    collection: &str,
+    last_position: i64,
+    all: bool,
+) -> Result<Vec<QuoteChange>, sqlx::Error> {
+    // Changes recorded before quotes had a status were all to published quotes
+    let query = "
+        SELECT
+            position, op, payload::TEXT AS payload
+        FROM
+            quote_changes
+        WHERE
+            collection_id = $1
+            AND position > $2
+            AND ($3 OR COALESCE(payload->>'status', 'published') = 'published')
+        ORDER BY
+            position ASC
+        ";
+
+    sqlx::query_as(query)
+        .bind(collection)
+        .bind(last_position)
+        .bind(all)
+        .fetch_all(pool)
+        .await
+}
+
+/// Get the position of the latest change across all collections
+async fn get_last_change_position(pool: &PgPool) -> Result<i64, (StatusCode, String)> {
+    let query = "
+        SELECT
+            COALESCE(MAX(position), 0)
+        FROM
+            quote_changes
+        ";
+
+    let id: (i64,) = sqlx::query_as(query).fetch_one(pool).await.map_err(|e| {
         (
-            StatusCode::BAD_REQUEST,
-            format!("Failed to deserialize payload: {e:?}"),
+            StatusCode::NOT_FOUND,
+            format!("Failed to get changes: {e:?}"),
         )
     })?;
 
+    Ok(id.0)
+}
+
+/// Delete changes older than [`CHANGES_RETENTION`]
+async fn prune_changes(pool: &PgPool) -> Result<u64, sqlx::Error> {
     let query = "
-        UPDATE
-            quotes
-        SET
-            author = $1, quote = $2, version = version + 1
+        DELETE FROM
+            quote_changes
         WHERE
-            id = $3
-        RETURNING 
-            id, author, quote, created_at, version
+            created_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
         ";
 
-    let DraftParams { author, quote } = params;
+    let result = sqlx::query(query)
+        .bind(CHANGES_RETENTION.as_secs_f64())
+        .execute(pool)
+        .await?;
 
-    // Insert the new row
-    let quote = sqlx::query_as::<_, Quote>(query)
-        .bind(author)
-        .bind(quote)
-        .bind(id)
-        .fetch_one(pool.as_ref())
+    Ok(result.rows_affected())
+}
+
+/// Spawn the background task that prunes changes once they're past their retention
+pub fn spawn_change_pruner(pool: Arc<PgPool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = prune_changes(&pool).await {
+                tracing::warn!("Failed to prune quote changes: {e:?}");
+            }
+        }
+    });
+}
+
+pub async fn changes(
+    Extension(pool): Extension<Arc<PgPool>>,
+    Extension(notifier): Extension<ChangeNotifier>,
+    Path(CollectionParams { collection }): Path<CollectionParams>,
+    headers: HeaderMap,
+    admin: Option<Admin>,
+) -> Result<Sse<impl Stream<Item = Result<Event, sqlx::Error>>>, (StatusCode, String)> {
+    // Admins see changes to drafts and archived quotes as well
+    let all = admin.is_some();
+
+    // Subscribe before looking up where to start so no change committed in between is missed
+    let wake = notifier.0.subscribe();
+
+    // Resume after the last event the client saw, otherwise only send new changes
+    let last_position = match headers.get("last-event-id") {
+        Some(id) => id
+            .to_str()
+            .ok()
+            .and_then(|id| id.parse::<i64>().ok())
+            .ok_or((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid Last-Event-ID: {id:?}"),
+            ))?,
+        None => get_last_change_position(&pool).await?,
+    };
+
+    let pending = get_changes_after(&pool, &collection, last_position, all)
         .await
         .map_err(|e| {
             (
                 StatusCode::NOT_FOUND,
-                format!("Failed to delete {id:?}: {e:?}"),
+                format!("Failed to get changes: {e:?}"),
             )
         })?;
 
+    let feed = ChangeFeed {
+        wake,
+        pool,
+        collection,
+        all,
+        last_position,
+        pending: pending.into(),
+    };
+
+    let stream = stream::try_unfold(feed, |mut feed| async move {
+        let event = feed.next().await?;
+        Ok(event.map(|event| (event, feed)))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+pub async fn undo(
+    Extension(pool): Extension<Arc<PgPool>>,
+    Path(QuoteParams { collection, id }): Path<QuoteParams>,
+    admin: Option<Admin>,
+    body: Bytes,
+) -> Result<String, (StatusCode, String)> {
+    let params = serde_json::from_slice(&body).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Failed to deserialize payload: {e:?}"),
+        )
+    })?;
+
+    // Only admins can update drafts and archived quotes
+    let quote = update_quote(pool.as_ref(), &collection, id, params, admin.is_some()).await?;
+
     Ok(serde_json::to_string_pretty(&quote).unwrap())
 }
+
+pub async fn batch(
+    Extension(pool): Extension<Arc<PgPool>>,
+    Path(CollectionParams { collection }): Path<CollectionParams>,
+    admin: Option<Admin>,
+    body: Bytes,
+) -> Result<String, (StatusCode, String)> {
+    // Only admins can update or delete drafts and archived quotes
+    let all = admin.is_some();
+
+    let ops: Vec<BatchOp> = serde_json::from_slice(&body).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Failed to deserialize payload: {e:?}"),
+        )
+    })?;
+
+    let mut tx = pool.begin().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to begin transaction: {e:?}"),
+        )
+    })?;
+
+    let mut results = Vec::with_capacity(ops.len());
+
+    // Apply each operation in order. Any failure drops the transaction, which rolls back
+    // every operation applied so far.
+    for (i, op) in ops.into_iter().enumerate() {
+        let name = op.name();
+
+        let quote = match op {
+            BatchOp::Create { params } => insert_quote(&mut *tx, &collection, params).await,
+            BatchOp::Update { id, params } => {
+                update_quote(&mut *tx, &collection, id, params, all).await
+            }
+            BatchOp::Delete { id } => delete_quote(&mut *tx, &collection, id, all).await,
+        }
+        .map_err(|(code, e)| (code, format!("Operation {i} ({name}) failed: {e}")))?;
+
+        results.push(BatchResult { op: name, quote });
+    }
+
+    tx.commit().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to commit transaction: {e:?}"),
+        )
+    })?;
+
+    Ok(serde_json::to_string_pretty(&results).unwrap())
+}
+
+#[cfg(test)]
+mod day7_tests {
+    use super::{render_svg, wrap_words, Quote, QuoteStatus};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    #[test]
+    fn wrap_words_fits_width() {
+        let lines = wrap_words("The best way to spread Christmas cheer", 12);
+        assert_eq!(lines, ["The best way", "to spread", "Christmas", "cheer"]);
+    }
+
+    #[test]
+    fn wrap_words_splits_long_words() {
+        let lines = wrap_words("ho hohohohohohoho", 6);
+        assert_eq!(lines, ["ho", "hohoho", "hohoho", "ho"]);
+    }
+
+    #[test]
+    fn svg_is_escaped() {
+        let quote = Quote {
+            id: Uuid::new_v4(),
+            author: "<Grinch>".to_string(),
+            quote: "Stealing & hiding".to_string(),
+            created_at: Utc::now(),
+            version: 1,
+            status: QuoteStatus::Published,
+            publish_at: None,
+        };
+
+        let svg = render_svg(&quote);
+        assert!(svg.contains("&lt;Grinch&gt;"));
+        assert!(svg.contains("Stealing &amp; hiding"));
+        assert!(!svg.contains("<Grinch>"));
+    }
+}
+
+/// Tests against a real database. `#[sqlx::test]` creates a fresh, migrated database for
+/// each test on the server given by `DATABASE_URL`, so these are ignored unless one is set
+/// up and they're run with `--include-ignored`.
+#[cfg(test)]
+mod day7_db_tests {
+    use super::ChangeNotifier;
+    use crate::{admin::AdminToken, router, SantaState};
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        Extension, Router,
+    };
+    use http_body_util::BodyExt;
+    use sqlx::PgPool;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::util::ServiceExt; // for `call`, `oneshot`, and `ready`
+
+    const ADMIN_TOKEN: &str = "s3cret";
+
+    fn app(pool: PgPool) -> Router {
+        let pool = Arc::new(pool);
+        let notifier = ChangeNotifier::spawn(pool.clone());
+
+        let mut state = SantaState::new();
+        state.admin_token = AdminToken(Some(ADMIN_TOKEN.into()));
+
+        router(state)
+            .layer(Extension(pool))
+            .layer(Extension(notifier))
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        send_as(app, false, method, uri, body).await
+    }
+
+    async fn send_as(
+        app: &Router,
+        admin: bool,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, String) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if admin {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"));
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn draft(app: &Router, uri: &str, body: &str) -> serde_json::Value {
+        let (status, body) = send(app, "POST", uri, body).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        serde_json::from_str(&body).unwrap()
+    }
+
+    fn quotes(body: &str) -> Vec<String> {
+        let list: serde_json::Value = serde_json::from_str(body).unwrap();
+        list["quotes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|quote| quote["quote"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn batch_rolls_back_on_failure(pool: PgPool) {
+        let app = app(pool);
+
+        let quote = draft(
+            &app,
+            "/19/draft",
+            r#"{"author":"Santa","quote":"Ho ho ho"}"#,
+        )
+        .await;
+        let id = quote["id"].as_str().unwrap();
+
+        let ops = format!(
+            r#"[
+                {{"op": "create", "author": "Elf", "quote": "Back to work"}},
+                {{"op": "update", "id": "{id}", "author": "Santa", "quote": "Ho ho no"}},
+                {{"op": "delete", "id": "{}"}}
+            ]"#,
+            uuid::Uuid::new_v4()
+        );
+
+        let (status, body) = send(&app, "POST", "/19/batch", &ops).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.starts_with("Operation 2 (delete) failed"), "{body}");
+
+        // Neither the create nor the update before the failing delete were kept
+        let (_, body) = send(&app, "GET", "/19/list", "").await;
+        assert_eq!(quotes(&body), ["Ho ho ho"]);
+
+        let (_, body) = send(&app, "GET", &format!("/19/cite/{id}"), "").await;
+        let cited: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(cited["version"], 1);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn collections_are_scoped(pool: PgPool) {
+        let app = app(pool);
+
+        let north = draft(
+            &app,
+            "/19/north/draft",
+            r#"{"author":"Santa","quote":"North"}"#,
+        )
+        .await;
+        draft(
+            &app,
+            "/19/south/draft",
+            r#"{"author":"Santa","quote":"South"}"#,
+        )
+        .await;
+        draft(&app, "/19/draft", r#"{"author":"Santa","quote":"Default"}"#).await;
+
+        for (uri, expected) in [
+            ("/19/north/list", "North"),
+            ("/19/south/list", "South"),
+            ("/19/list", "Default"),
+            ("/19/default/list", "Default"),
+        ] {
+            let (_, body) = send(&app, "GET", uri, "").await;
+            assert_eq!(quotes(&body), [expected], "{uri}");
+        }
+
+        // Quotes can't be reached through another collection
+        let id = north["id"].as_str().unwrap();
+        let update = r#"{"author":"Grinch","quote":"Stolen"}"#;
+
+        for (method, uri, body) in [
+            ("GET", format!("/19/south/cite/{id}"), ""),
+            ("PUT", format!("/19/south/undo/{id}"), update),
+            ("DELETE", format!("/19/south/remove/{id}"), ""),
+        ] {
+            let (status, _) = send(&app, method, &uri, body).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        }
+
+        send(&app, "POST", "/19/south/reset", "").await;
+
+        let (_, body) = send(&app, "GET", "/19/south/list", "").await;
+        assert!(quotes(&body).is_empty());
+
+        let (_, body) = send(&app, "GET", "/19/north/list", "").await;
+        assert_eq!(quotes(&body), ["North"]);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn reserved_collections(pool: PgPool) {
+        let app = app(pool);
+        let id = uuid::Uuid::new_v4();
+
+        for (method, uri) in [
+            ("POST", "/19/list/draft".to_string()),
+            ("GET", "/19/changes/list".to_string()),
+            ("GET", format!("/19/cite/cite/{id}")),
+            ("DELETE", format!("/19/undo/remove/{id}")),
+        ] {
+            let (status, body) = send(&app, method, &uri, "").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+            assert!(body.contains("is reserved"), "{uri}: {body}");
+        }
+    }
+
+    /// Subscribe to the changes in the collection, resuming after `last_event_id` if given
+    async fn subscribe(app: &Router, uri: &str, admin: bool, last_event_id: Option<&str>) -> Body {
+        let mut request = Request::get(uri);
+        if admin {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"));
+        }
+        if let Some(id) = last_event_id {
+            request = request.header("last-event-id", id);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        response.into_body()
+    }
+
+    /// Read the next `count` events from the stream as their types and quotes
+    async fn events(body: &mut Body, count: usize) -> Vec<(String, String)> {
+        let mut text = String::new();
+        let mut events = Vec::new();
+
+        while events.len() < count {
+            let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+                .await
+                .expect("Timed out waiting for a change")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(frame.data_ref().unwrap()).unwrap());
+
+            while let Some((event, rest)) = text.split_once("\n\n") {
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(str::to_string)
+                };
+
+                if let (Some(op), Some(data)) = (field("event: "), field("data: ")) {
+                    let quote: serde_json::Value = serde_json::from_str(&data).unwrap();
+                    events.push((op, quote["quote"].as_str().unwrap().to_string()));
+                }
+
+                text = rest.to_string();
+            }
+        }
+
+        events
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn changes_hide_drafts(pool: PgPool) {
+        let app = app(pool);
+
+        let mut anonymous = subscribe(&app, "/19/feed/changes", false, None).await;
+        let mut admin = subscribe(&app, "/19/feed/changes", true, None).await;
+
+        let secret = r#"{"author":"Santa","quote":"Secret","status":"draft"}"#;
+        draft(&app, "/19/feed/draft", secret).await;
+        draft(
+            &app,
+            "/19/feed/draft",
+            r#"{"author":"Santa","quote":"Public"}"#,
+        )
+        .await;
+
+        let change = |quote: &str| ("draft".to_string(), quote.to_string());
+
+        assert_eq!(events(&mut anonymous, 1).await, [change("Public")]);
+        assert_eq!(
+            events(&mut admin, 2).await,
+            [change("Secret"), change("Public")]
+        );
+
+        // Resuming replays the changes since, still without the draft
+        let mut resumed = subscribe(&app, "/19/feed/changes", false, Some("0")).await;
+        assert_eq!(events(&mut resumed, 1).await, [change("Public")]);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn drafts_are_hidden(pool: PgPool) {
+        let app = app(pool);
+
+        let secret = r#"{"author":"Santa","quote":"Secret","status":"draft"}"#;
+        let quote = draft(&app, "/19/draft", secret).await;
+        let id = quote["id"].as_str().unwrap();
+        let update = r#"{"author":"Grinch","quote":"Stolen"}"#;
+
+        let (_, body) = send(&app, "GET", "/19/list", "").await;
+        assert!(quotes(&body).is_empty());
+
+        let (_, body) = send_as(&app, true, "GET", "/19/list", "").await;
+        assert_eq!(quotes(&body), ["Secret"]);
+
+        // Anonymous callers can't see, change or remove the draft, even knowing its id
+        let batch_update =
+            format!(r#"[{{"op": "update", "id": "{id}", "author": "Grinch", "quote": "Stolen"}}]"#);
+        let batch_delete = format!(r#"[{{"op": "delete", "id": "{id}"}}]"#);
+
+        for (method, uri, body) in [
+            ("GET", format!("/19/cite/{id}"), ""),
+            ("PUT", format!("/19/undo/{id}"), update),
+            ("DELETE", format!("/19/remove/{id}"), ""),
+            ("POST", "/19/batch".to_string(), &batch_update),
+            ("POST", "/19/batch".to_string(), &batch_delete),
+        ] {
+            let (status, body) = send(&app, method, &uri, body).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{method} {uri}");
+            assert!(!body.contains("Secret"), "{body}");
+        }
+
+        let (status, body) = send_as(&app, true, "GET", &format!("/19/cite/{id}"), "").await;
+        assert_eq!(status, StatusCode::OK);
+        let cited: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(cited["quote"], "Secret");
+        assert_eq!(cited["version"], 1);
+
+        let (status, _) = send_as(&app, true, "PUT", &format!("/19/undo/{id}"), update).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = send_as(&app, true, "DELETE", &format!("/19/remove/{id}"), "").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn publish_at_can_be_cleared(pool: PgPool) {
+        let app = app(pool);
+
+        let scheduled = r#"{"author":"Santa","quote":"Soon","publish_at":"2099-12-25T00:00:00Z"}"#;
+        let quote = draft(&app, "/19/draft", scheduled).await;
+        assert_eq!(quote["status"], "draft");
+        let uri = format!("/19/undo/{}", quote["id"].as_str().unwrap());
+
+        // Leaving it out keeps the publish time
+        let (_, body) = send_as(
+            &app,
+            true,
+            "PUT",
+            &uri,
+            r#"{"author":"Santa","quote":"Later"}"#,
+        )
+        .await;
+        let updated: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(updated["publish_at"], "2099-12-25T00:00:00Z");
+
+        let unschedule = r#"{"author":"Santa","quote":"Never","publish_at":null}"#;
+        let (_, body) = send_as(&app, true, "PUT", &uri, unschedule).await;
+        let updated: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(updated["quote"], "Never");
+        assert!(updated.get("publish_at").is_none());
+    }
+}
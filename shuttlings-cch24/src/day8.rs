@@ -125,7 +125,7 @@ pub async fn present(Path(color): Path<String>) -> Result<Html<String>, StatusCo
     </div>"#
     );
 
-    Ok(Html(div.to_string()))
+    Ok(Html(div))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -154,7 +154,7 @@ pub async fn ornament(Path(params): Path<OrnamentParams>) -> Result<Html<String>
         "#
     );
 
-    Ok(Html(div.to_string()))
+    Ok(Html(div))
 }
 
 pub async fn lockfile(mut multipart: Multipart) -> Result<String, (StatusCode, String)> {
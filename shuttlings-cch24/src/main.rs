@@ -5,24 +5,38 @@ use axum::{
     routing::{delete, get, post, put},
     Extension, Router,
 };
-use jsonwebtoken::DecodingKey;
-use std::sync::{Arc, Mutex};
+use shuttle_runtime::{DeploymentMetadata, Environment, SecretStore};
+use std::num::NonZeroU64;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use tower_http::services::ServeDir;
 
+mod admin;
+use admin::AdminToken;
 mod day1;
 mod day2;
 mod day3;
+use day3::ManifestPolicy;
 mod day4;
 mod day5;
 use day5::Board;
 mod day6;
+use day6::{GiftConfig, GiftKeyStore, GiftKeys, RefreshStore, RevocationStore, SantaKeys};
 mod day7;
 mod day8;
 
 #[derive(Clone)]
 struct SantaState {
     board: Arc<Mutex<Board>>,
-    pubkey: Arc<DecodingKey>,
+    santa_keys: Arc<RwLock<SantaKeys>>,
+    admin_token: AdminToken,
+    gift_keys: Arc<RwLock<GiftKeys>>,
+    gift_key_store: GiftKeyStore,
+    gift_config: Arc<GiftConfig>,
+    revocations: RevocationStore,
+    refresh_tokens: RefreshStore,
+    manifest_policy: Arc<ManifestPolicy>,
 }
 
 impl FromRef<SantaState> for Arc<Mutex<Board>> {
@@ -31,33 +45,105 @@ impl FromRef<SantaState> for Arc<Mutex<Board>> {
     }
 }
 
-impl FromRef<SantaState> for Arc<DecodingKey> {
-    fn from_ref(state: &SantaState) -> Arc<DecodingKey> {
-        state.pubkey.clone()
+impl FromRef<SantaState> for Arc<RwLock<SantaKeys>> {
+    fn from_ref(state: &SantaState) -> Arc<RwLock<SantaKeys>> {
+        state.santa_keys.clone()
+    }
+}
+
+impl FromRef<SantaState> for Arc<RwLock<GiftKeys>> {
+    fn from_ref(state: &SantaState) -> Arc<RwLock<GiftKeys>> {
+        state.gift_keys.clone()
+    }
+}
+
+impl FromRef<SantaState> for GiftKeyStore {
+    fn from_ref(state: &SantaState) -> GiftKeyStore {
+        state.gift_key_store.clone()
+    }
+}
+
+impl FromRef<SantaState> for Arc<GiftConfig> {
+    fn from_ref(state: &SantaState) -> Arc<GiftConfig> {
+        state.gift_config.clone()
+    }
+}
+
+impl FromRef<SantaState> for RevocationStore {
+    fn from_ref(state: &SantaState) -> RevocationStore {
+        state.revocations.clone()
+    }
+}
+
+impl FromRef<SantaState> for RefreshStore {
+    fn from_ref(state: &SantaState) -> RefreshStore {
+        state.refresh_tokens.clone()
+    }
+}
+
+impl FromRef<SantaState> for Arc<ManifestPolicy> {
+    fn from_ref(state: &SantaState) -> Arc<ManifestPolicy> {
+        state.manifest_policy.clone()
+    }
+}
+
+impl FromRef<SantaState> for AdminToken {
+    fn from_ref(state: &SantaState) -> AdminToken {
+        state.admin_token.clone()
     }
 }
 
 impl SantaState {
     pub fn new() -> Self {
-        let pem = include_bytes!("../day16_santa_public_key.pem");
-        let key = if let Ok(key) = DecodingKey::from_ec_pem(pem) {
-            key
-        } else if let Ok(key) = DecodingKey::from_ed_pem(pem) {
-            key
-        } else if let Ok(key) = DecodingKey::from_rsa_pem(pem) {
-            key
-        } else {
-            panic!("Invalid public key from santa");
+        Self {
+            board: Arc::new(Mutex::new(Board::new())),
+            santa_keys: Arc::new(RwLock::new(SantaKeys::embedded())),
+            admin_token: AdminToken::default(),
+            gift_keys: Arc::new(RwLock::new(GiftKeys::random())),
+            gift_key_store: GiftKeyStore::default(),
+            gift_config: Arc::new(GiftConfig::default()),
+            revocations: RevocationStore::default(),
+            refresh_tokens: RefreshStore::default(),
+            manifest_policy: Arc::new(ManifestPolicy::default()),
+        }
+    }
+
+    /// Create the state, configured from the given secrets. Missing keys are only generated
+    /// when running `local`ly.
+    pub fn from_secrets(secrets: &SecretStore, local: bool) -> Self {
+        let gift_config = GiftConfig::from_secrets(secrets).expect("Invalid gift configuration");
+        let mut gift_keys =
+            GiftKeys::from_secrets(secrets, local).expect("Invalid gift key configuration");
+
+        // Retired keys must outlive every token they signed
+        gift_keys.extend_retention(gift_config.max_age());
+
+        // Santa's keys are compiled in unless a path to load them from is given
+        let santa_keys = match secrets.get("SANTA_KEYS_PATH") {
+            Some(path) => SantaKeys::load_or_embedded(Path::new(&path)),
+            None => SantaKeys::embedded(),
         };
 
+        let manifest_policy =
+            ManifestPolicy::from_secrets(secrets).expect("Invalid manifest policy");
+
         Self {
-            board: Arc::new(Mutex::new(Board::new())),
-            pubkey: Arc::new(key),
+            admin_token: AdminToken(secrets.get("ADMIN_TOKEN").map(Arc::from)),
+            santa_keys: Arc::new(RwLock::new(santa_keys)),
+            gift_keys: Arc::new(RwLock::new(gift_keys)),
+            gift_config: Arc::new(gift_config),
+            manifest_policy: Arc::new(manifest_policy),
+            ..Self::new()
         }
     }
 }
 
+#[cfg(test)]
 fn app() -> Router {
+    router(SantaState::new())
+}
+
+fn router(state: SantaState) -> Router {
     let limiter = day4::create_milk_limiter();
     let limiter = Arc::new(Mutex::new(limiter));
 
@@ -68,6 +154,8 @@ fn app() -> Router {
         .route("/2/v6/dest", get(day2::ipv6_dest))
         .route("/2/v6/key", get(day2::ipv6_key))
         .route("/5/manifest", post(day3::manifest))
+        .route("/5/dependencies", post(day3::dependencies))
+        .route("/5/lint", post(day3::lint_manifest))
         .route("/9/milk", post(day4::milk))
         .route("/9/refill", post(day4::refill))
         .route("/12/board", get(day5::board))
@@ -76,29 +164,102 @@ fn app() -> Router {
         .route("/12/random-board", get(day5::random_board))
         .route("/16/wrap", post(day6::wrap))
         .route("/16/unwrap", get(day6::unwrap))
+        .route("/16/revoke", post(day6::revoke))
+        .route("/16/logout", post(day6::logout))
+        .route("/16/refresh", post(day6::refresh))
         .route("/16/decode", post(day6::decode))
+        .route("/16/inspect", post(day6::inspect))
+        .route("/16/keys/rotate", post(day6::rotate_key))
+        .route("/.well-known/jwks.json", get(day6::jwks))
         .route("/19/reset", post(day7::reset))
         .route("/19/draft", post(day7::draft))
         .route("/19/cite/:id", get(day7::cite))
         .route("/19/remove/:id", delete(day7::remove))
         .route("/19/undo/:id", put(day7::undo))
         .route("/19/list", get(day7::list))
+        .route("/19/batch", post(day7::batch))
+        .route("/19/changes", get(day7::changes))
+        .route("/19/:collection/reset", post(day7::reset))
+        .route("/19/:collection/draft", post(day7::draft))
+        .route("/19/:collection/cite/:id", get(day7::cite))
+        .route("/19/:collection/remove/:id", delete(day7::remove))
+        .route("/19/:collection/undo/:id", put(day7::undo))
+        .route("/19/:collection/list", get(day7::list))
+        .route("/19/:collection/batch", post(day7::batch))
+        .route("/19/:collection/changes", get(day7::changes))
         .route("/23/star", get(day8::star))
         .route("/23/present/:color", get(day8::present))
         .route("/23/ornament/:state/:n", get(day8::ornament))
         .route("/23/lockfile", post(day8::lockfile))
         .layer(Extension(limiter))
-        .with_state(SantaState::new())
+        .with_state(state)
         .nest_service("/assets", ServeDir::new("assets"))
 }
 
+/// The reload interval in the secret `name`, in seconds. A reloader can't run without
+/// waiting between checks, so zero is rejected like any other invalid interval.
+fn reload_interval(secrets: &SecretStore, name: &str, default: Duration) -> Duration {
+    secrets.get(name).map_or(default, |secs| {
+        let secs: NonZeroU64 = secs
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid {name} {secs}: {e}"));
+
+        Duration::from_secs(secs.get())
+    })
+}
+
 #[shuttle_runtime::main]
 #[allow(clippy::unused_async)]
-async fn main(#[shuttle_shared_db::Postgres] pool: sqlx::PgPool) -> shuttle_axum::ShuttleAxum {
+async fn main(
+    #[shuttle_shared_db::Postgres] pool: sqlx::PgPool,
+    #[shuttle_runtime::Secrets] secrets: SecretStore,
+    #[shuttle_runtime::Metadata] metadata: DeploymentMetadata,
+) -> shuttle_axum::ShuttleAxum {
     sqlx::migrate!()
         .run(&pool)
         .await
         .expect("Failed to run migrations");
 
-    Ok(app().layer(Extension(Arc::new(pool))).into())
+    let pool = Arc::new(pool);
+    day7::spawn_publisher(pool.clone());
+    day7::spawn_change_pruner(pool.clone());
+    let change_notifier = day7::ChangeNotifier::spawn(pool.clone());
+
+    let state = SantaState {
+        gift_key_store: GiftKeyStore::Postgres(pool.clone()),
+        revocations: RevocationStore::Postgres(pool.clone()),
+        refresh_tokens: RefreshStore::Postgres(pool.clone()),
+        ..SantaState::from_secrets(&secrets, metadata.env == Environment::Local)
+    };
+
+    // Pick up keys rotated before a restart, or by other replicas
+    let stored_keys = state
+        .gift_key_store
+        .load()
+        .await
+        .expect("Failed to load gift keys");
+    state.gift_keys.write().unwrap().sync(&stored_keys);
+
+    let gift_keys_interval = reload_interval(
+        &secrets,
+        "GIFT_KEYS_RELOAD_SECS",
+        day6::DEFAULT_GIFT_KEYS_RELOAD_INTERVAL,
+    );
+    day6::spawn_gift_key_reloader(
+        state.gift_keys.clone(),
+        state.gift_key_store.clone(),
+        gift_keys_interval,
+    );
+
+    let santa_keys_interval = reload_interval(
+        &secrets,
+        "SANTA_KEYS_RELOAD_SECS",
+        day6::DEFAULT_RELOAD_INTERVAL,
+    );
+    day6::spawn_santa_reloader(state.santa_keys.clone(), santa_keys_interval);
+
+    Ok(router(state)
+        .layer(Extension(pool))
+        .layer(Extension(change_notifier))
+        .into())
 }